@@ -0,0 +1,56 @@
+#!/bin/bash
+set -eu -o pipefail
+
+# Runs append_bench against core built from each of two commits in turn, with the same settings,
+# and prints both results. Each run uses a server id of its own, so they don't share chains, but
+# they do share the database, so use one that's otherwise idle.
+#
+#   DATABASE_URL=postgres://... ./bench-compare.sh <before> <after> [threads] [appends per thread]
+
+usage="usage: bench-compare.sh <before commit> <after commit> [threads] [appends per thread]"
+before="${1:?$usage}"
+after="${2:?$usage}"
+threads="${3:-16}"
+appends="${4:-500}"
+: "${DATABASE_URL:?needs DATABASE_URL, for a database the benchmark can append to}"
+port="${BENCH_PORT:-8900}"
+
+repo=$(git rev-parse --show-toplevel)
+work=$(mktemp -d)
+core_pid=""
+
+cleanup() {
+  if [ -n "$core_pid" ]; then
+    kill "$core_pid" 2>/dev/null || true
+  fi
+  for tree in "$work"/tree-*; do
+    if [ -d "$tree" ]; then
+      git -C "$repo" worktree remove --force "$tree" || true
+    fi
+  done
+  rm -rf "$work"
+}
+trap cleanup EXIT
+
+# Builds and starts core from the given commit, and benchmarks it
+bench() {
+  local name="$1" commit="$2"
+  local tree="$work/tree-$name"
+  git -C "$repo" worktree add --detach "$tree" "$commit" >&2
+  (cd "$tree" && cargo build --release -p potboiler --bin core >&2)
+  (cd "$tree" && cargo build --release -p potboiler_client --example append_bench >&2)
+  # From core's directory, for its log.yaml
+  (cd "$tree/core" &&
+    LISTEN="127.0.0.1:$port" ADVERTISE_URL="http://127.0.0.1:$port" ID_PATH="$work/server-id-$name" \
+    exec "$tree/target/release/core" >"$work/core-$name.log" 2>&1) &
+  core_pid=$!
+  "$repo/wait-for-ready.sh" 127.0.0.1 "$port"
+  echo "== $name ($(git -C "$repo" rev-parse --short "$commit"))"
+  "$tree/target/release/examples/append_bench" "http://127.0.0.1:$port/log" "$threads" "$appends"
+  kill "$core_pid"
+  wait "$core_pid" 2>/dev/null || true
+  core_pid=""
+}
+
+bench before "$before"
+bench after "$after"
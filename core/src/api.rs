@@ -0,0 +1,507 @@
+use filters::Filter;
+use forks;
+use iron::{IronError, Request};
+use iron::typemap::Key;
+use membership::{self, Membership};
+use nodes::{self, NodeList};
+use notifications::{self, Notifier};
+use orphans;
+use persistent;
+use potboiler_common::{clock, config, health, server_id, trace};
+use potboiler_common::config::Config;
+use potboiler_common::clock::SyncClock;
+use potboiler_common::db::PostgresPool;
+use potboiler_common::error::ServiceError;
+use potboiler_common::metrics::{BoundedLabel, Counter};
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use replication;
+use retention;
+use runtime::{self, Stop};
+use schema;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use store::{self, Backend, NotifierRecord, SharedStore};
+use url::Url;
+use uuid::Uuid;
+
+const APPENDS: Counter = Counter {
+    name: "potboiler_log_appends_total",
+    help: "Entries appended to the log by this node, by stream",
+};
+
+// Streams are named by whoever's appending, so only this many get a series of their own
+const MAX_STREAM_LABELS: usize = 100;
+
+lazy_static! {
+    static ref STREAM_LABELS: BoundedLabel = BoundedLabel::new(MAX_STREAM_LABELS);
+}
+
+fn count_appends(stream: &Option<String>, count: usize) {
+    let stream = stream.as_ref().map(|x| x.as_str()).unwrap_or("");
+    APPENDS.add(&[("stream", STREAM_LABELS.value(stream))], count as f64);
+}
+
+// The log as a library. Everything the HTTP server does to the log goes through a Core, so
+// another program can embed one and append, read and subscribe without going over HTTP:
+//
+//   let core = try!(Core::new(Backend::Memory, Uuid::new_v4()));
+//   let updates = core.subscribe(Some(String::from("orders")));
+//   core.append(Some(String::from("orders")), data).unwrap();
+//
+// With a Postgres backend it's also a cluster node, syncing with the nodes it's told about,
+// which starts the gossip and archiving threads. stop() ends those, for when the program's done
+// with the core but carries on itself.
+
+#[derive(Debug)]
+pub enum CoreError {
+    BadRequest(String),
+    // Another entry got to that place in the chain first
+    Conflict(Uuid),
+    // Needs the Postgres backend, which is the only one that clusters
+    NotClustered,
+    Storage(StringError),
+}
+
+impl fmt::Display for CoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CoreError::BadRequest(ref msg) => write!(f, "{}", msg),
+            CoreError::Conflict(ref existing) => {
+                write!(f, "{} was appended at the same time, try again", existing)
+            }
+            CoreError::NotClustered => write!(f, "Clustering needs Postgres storage"),
+            CoreError::Storage(ref err) => write!(f, "Storage error: {}", err),
+        }
+    }
+}
+
+impl Error for CoreError {
+    fn description(&self) -> &str {
+        match *self {
+            CoreError::BadRequest(ref msg) => msg.as_str(),
+            CoreError::Conflict(_) => "Conflicting append",
+            CoreError::NotClustered => "Not clustered",
+            CoreError::Storage(ref err) => err.description(),
+        }
+    }
+}
+
+impl From<StringError> for CoreError {
+    fn from(err: StringError) -> CoreError {
+        CoreError::Storage(err)
+    }
+}
+
+impl From<CoreError> for ServiceError {
+    fn from(err: CoreError) -> ServiceError {
+        let message = format!("{}", err);
+        match err {
+            CoreError::BadRequest(_) | CoreError::NotClustered => ServiceError::BadRequest(message),
+            CoreError::Conflict(_) => ServiceError::Conflict(message),
+            CoreError::Storage(_) => ServiceError::Storage(message),
+        }
+    }
+}
+
+impl From<CoreError> for IronError {
+    fn from(err: CoreError) -> IronError {
+        IronError::from(ServiceError::from(err))
+    }
+}
+
+// Stream names end up in URLs (between peers, and in the client), unencoded, so they're kept to
+// letters, numbers, '_', '.' and '-'
+pub fn check_stream_name(name: &str) -> Result<(), CoreError> {
+    let valid = |c: char| {
+        match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '.' | '-' => true,
+            _ => false,
+        }
+    };
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(CoreError::BadRequest(format!("Bad stream name '{}': only letters, numbers, '_', '.' \
+                                                  and '-' are allowed",
+                                                 name)));
+    }
+    Ok(())
+}
+
+fn check_stream(stream: &Option<String>) -> Result<(), CoreError> {
+    match *stream {
+        Some(ref name) => check_stream_name(name),
+        None => Ok(()),
+    }
+}
+
+struct Subscriber {
+    stream: Option<String>,
+    sender: Sender<Log>,
+}
+
+// The parts that only exist with Postgres storage
+#[derive(Clone)]
+pub struct Cluster {
+    pub pool: PostgresPool,
+    pub nodelist: NodeList,
+    pub members: Membership,
+    // Peers we're fetching missing entries from right now
+    filling: Arc<Mutex<HashSet<String>>>,
+    // For the gossip and archiving threads
+    stop: Stop,
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+#[derive(Clone)]
+pub struct Core {
+    store: SharedStore,
+    server_id: Uuid,
+    clock: SyncClock,
+    notifiers: Arc<RwLock<Vec<Notifier>>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    cluster: Option<Cluster>,
+}
+
+impl Core {
+    pub fn new(backend: Backend, server_id: Uuid) -> Result<Core, CoreError> {
+        if let Backend::Postgres(ref pool) = backend {
+            let conn = try!(pool.get()
+                .map_err(|err| StringError::from(format!("Couldn't get a connection: {:?}", err))));
+            try!(schema::up(&conn)
+                .map_err(|err| StringError::from(format!("Couldn't migrate the database: {:?}", err))));
+            health::passed("migrations");
+        }
+        let store = try!(store::open(&backend));
+        let clock = clock::init_clock().clock_state;
+        let notifiers = Arc::new(RwLock::new(try!(notifications::init_notifiers(&store))));
+        let cluster = match backend {
+            Backend::Postgres(pool) => {
+                {
+                    let conn = try!(pool.get()
+                        .map_err(|err| StringError::from(format!("Couldn't get a connection: {:?}", err))));
+                    try!(forks::check_own_id(&*conn, &server_id));
+                }
+                let nodelist = nodes::init_nodelist(pool.clone(), clock.clone(), notifiers.clone());
+                let members = try!(Membership::new(pool.clone(), &store, nodelist.clone(), server_id));
+                let stop = Stop::new();
+                let archive_pool = pool.clone();
+                let archive_stop = stop.clone();
+                let gossip_members = members.clone();
+                let gossip_stop = stop.clone();
+                let threads = vec![
+                    thread::spawn(move || retention::archive_loop(archive_pool, archive_stop)),
+                    thread::spawn(move || membership::gossip_loop(gossip_members, gossip_stop)),
+                ];
+                Some(Cluster {
+                    pool: pool,
+                    nodelist: nodelist,
+                    members: members,
+                    filling: Arc::new(Mutex::new(HashSet::new())),
+                    stop: stop,
+                    threads: Arc::new(Mutex::new(threads)),
+                })
+            }
+            _ => {
+                info!("Running as a single node, as clustering needs Postgres storage");
+                None
+            }
+        };
+        Ok(Core {
+            store: store,
+            server_id: server_id,
+            clock: clock,
+            notifiers: notifiers,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            cluster: cluster,
+        })
+    }
+
+    // Storage and the server id from the given config, as the server uses
+    pub fn from_config(config: &Config) -> Result<Core, CoreError> {
+        let backend = try!(store::backend_from_config(config));
+        Core::new(backend, server_id::setup(&config.id_path))
+    }
+
+    // The same, from the environment (STORAGE, DATABASE_URL, ID_PATH and friends)
+    pub fn from_env() -> Result<Core, CoreError> {
+        Core::from_config(&config::current())
+    }
+
+    pub fn server_id(&self) -> Uuid {
+        self.server_id
+    }
+
+    pub fn clock(&self) -> SyncClock {
+        self.clock.clone()
+    }
+
+    pub fn store(&self) -> SharedStore {
+        self.store.clone()
+    }
+
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.cluster.as_ref()
+    }
+
+    fn new_log(&self, stream: &Option<String>, prev: Option<Uuid>, data: Value) -> Log {
+        Log {
+            id: Uuid::new_v4(),
+            owner: self.server_id,
+            prev: prev,
+            next: None,
+            when: clock::get_timestamp_from_state(&self.clock),
+            data: data,
+            stream: stream.clone(),
+            trace: trace::current().map(|context| context.traceparent()),
+        }
+    }
+
+    // Adds an entry to the end of our chain in the stream, and tells everyone about it
+    pub fn append(&self, stream: Option<String>, data: Value) -> Result<Log, CoreError> {
+        try!(check_stream(&stream));
+        let previous = try!(self.store.head(&self.server_id, &stream));
+        let log = self.new_log(&stream, previous, data);
+        if let Some(existing) = try!(self.store.insert(&log)) {
+            return Err(CoreError::Conflict(existing));
+        }
+        count_appends(&stream, 1);
+        self.publish(&log);
+        Ok(log)
+    }
+
+    // As append, but all of the items go in, in order, or none do
+    pub fn append_batch(&self, stream: Option<String>, items: Vec<Value>) -> Result<Vec<Log>, CoreError> {
+        try!(check_stream(&stream));
+        let mut previous = try!(self.store.head(&self.server_id, &stream));
+        let mut logs = Vec::with_capacity(items.len());
+        for data in items {
+            let log = self.new_log(&stream, previous, data);
+            previous = Some(log.id);
+            logs.push(log);
+        }
+        if let Some(existing) = try!(self.store.insert_batch(&logs)) {
+            return Err(CoreError::Conflict(existing));
+        }
+        count_appends(&stream, logs.len());
+        self.publish_batch(&logs);
+        Ok(logs)
+    }
+
+    pub fn get(&self, id: &Uuid) -> Result<Option<Log>, CoreError> {
+        Ok(try!(self.store.get(id)))
+    }
+
+    pub fn heads(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, CoreError> {
+        Ok(try!(self.store.heads(stream)))
+    }
+
+    pub fn firsts(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, CoreError> {
+        Ok(try!(self.store.firsts(stream)))
+    }
+
+    pub fn streams(&self) -> Result<Vec<String>, CoreError> {
+        Ok(try!(self.store.streams()))
+    }
+
+    pub fn range(&self, after: &Uuid, limit: usize) -> Result<Vec<Log>, CoreError> {
+        Ok(try!(self.store.range(after, limit)))
+    }
+
+    // New entries for the stream (or all of them, for None), as they arrive. Stops when the
+    // receiver is dropped.
+    pub fn subscribe(&self, stream: Option<String>) -> Receiver<Log> {
+        let (send, recv) = channel();
+        self.subscribers.lock().unwrap().push(Subscriber {
+            stream: stream,
+            sender: send,
+        });
+        recv
+    }
+
+    // Adds an HTTP subscriber. If the URL was already registered, its stream, filter and token are
+    // replaced, and it returns false.
+    pub fn register(&self,
+                    url: &str,
+                    stream: Option<String>,
+                    raw_filter: Option<Value>,
+                    token: Option<String>)
+                    -> Result<bool, CoreError> {
+        try!(Url::parse(url).map_err(|err| CoreError::BadRequest(format!("Bad URL {}: {:?}", url, err))));
+        let filter = match raw_filter {
+            Some(ref raw) => Some(try!(Filter::from_json(raw).map_err(|err| CoreError::BadRequest(err.0)))),
+            None => None,
+        };
+        debug!("Registering {:?} for {:?}", url, stream);
+        let record = NotifierRecord {
+            url: String::from(url),
+            stream: stream.clone(),
+            filter: raw_filter,
+            token: token.clone(),
+        };
+        let added = try!(self.store.add_notifier(&record));
+        let mut notifiers = self.notifiers.write().unwrap();
+        notifiers.retain(|notifier| notifier.url != url);
+        notifiers.push(Notifier {
+            url: String::from(url),
+            stream: stream,
+            filter: filter,
+            token: token,
+        });
+        Ok(added)
+    }
+
+    // Returns false if the URL wasn't registered
+    pub fn deregister(&self, url: &str) -> Result<bool, CoreError> {
+        let removed = try!(self.store.remove_notifier(url));
+        self.notifiers.write().unwrap().retain(|notifier| notifier.url != url);
+        Ok(removed)
+    }
+
+    pub fn notifiers(&self) -> Vec<Notifier> {
+        self.notifiers.read().unwrap().clone()
+    }
+
+    // Tells subscribers and other nodes about an entry that's now in the store
+    pub fn publish(&self, log: &Log) {
+        notifications::notify_everyone(&self.notifiers(), log);
+        self.send_to_subscribers(log);
+        if let Some(ref cluster) = self.cluster {
+            nodes::notify_nodes(&cluster.nodelist,
+                                Arc::new(vec![replication::Pending::from_log(log)]));
+        }
+    }
+
+    pub fn publish_batch(&self, logs: &[Log]) {
+        notifications::notify_everyone_batch(&self.notifiers(), logs);
+        for log in logs {
+            self.send_to_subscribers(log);
+        }
+        if let Some(ref cluster) = self.cluster {
+            nodes::notify_nodes(&cluster.nodelist, replication::pending_from_logs(logs));
+        }
+    }
+
+    fn send_to_subscribers(&self, log: &Log) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        // Sending fails once the receiver's gone, so drop those as we go
+        subscribers.retain(|subscriber| {
+            match subscriber.stream {
+                Some(ref stream) if log.stream.as_ref() != Some(stream) => true,
+                _ => subscriber.sender.send(log.clone()).is_ok(),
+            }
+        });
+    }
+
+    // Fetches whatever the entries a peer pushed us are waiting on, from that peer, in the
+    // background. Only for peers we know are members, as the request says who sent it.
+    pub fn fill_gaps(&self, from: &str) {
+        let cluster = match self.cluster {
+            Some(ref cluster) => cluster,
+            None => return,
+        };
+        if !cluster.members.alive_urls().iter().any(|url| url == from) {
+            warn!("Not filling gaps from {}, as it's not a member we know of", from);
+            return;
+        }
+        if !cluster.filling.lock().unwrap().insert(String::from(from)) {
+            // That'll pick up anything new too
+            return;
+        }
+        let core = self.clone();
+        let pool = cluster.pool.clone();
+        let filling = cluster.filling.clone();
+        let from = String::from(from);
+        runtime::spawn(move || {
+            let result = pool.get()
+                .map_err(|err| StringError::from(format!("Couldn't get a connection: {:?}", err)))
+                .and_then(|conn| orphans::fetch_missing(&*conn, &from));
+            filling.lock().unwrap().remove(&from);
+            match result {
+                Ok(added) => {
+                    if !added.is_empty() {
+                        core.publish_batch(&added);
+                    }
+                }
+                Err(err) => warn!("Error while filling gaps from {}: {}", from, err),
+            }
+        });
+    }
+
+    fn get_cluster(&self) -> Result<&Cluster, CoreError> {
+        self.cluster.as_ref().ok_or(CoreError::NotClustered)
+    }
+
+    // Adds a seed node, and starts finding out about the cluster from it
+    pub fn add_node(&self, url: &str) -> Result<(), CoreError> {
+        let cluster = try!(self.get_cluster());
+        try!(Url::parse(url).map_err(|err| CoreError::BadRequest(format!("Bad URL {}: {:?}", url, err))));
+        debug!("Registering node {}", url);
+        try!(self.store.add_node(url));
+        // Nodes are seeds now, so find out who they are, and let gossip do the rest
+        let members = cluster.members.clone();
+        let url = String::from(url);
+        runtime::spawn(move || members.join(&url));
+        Ok(())
+    }
+
+    // Returns false if we didn't know about the node
+    pub fn remove_node(&self, url: &str) -> Result<bool, CoreError> {
+        let cluster = try!(self.get_cluster());
+        let url = String::from(url);
+        let stored = try!(self.store.remove_node(&url));
+        let removed = cluster.members.remove_url(&url, clock::get_timestamp_from_state(&self.clock));
+        let stopped = nodes::stop_checking(&cluster.nodelist, &url);
+        if stored {
+            debug!("Removed {} from the stored nodes", url);
+        }
+        Ok(removed || stopped)
+    }
+
+    // For shutting down: stops syncing with peers and waits (until the deadline) for in-progress
+    // syncs, the last replication batches and notifications to finish
+    pub fn shutdown(&self, deadline: Instant) {
+        if let Some(ref cluster) = self.cluster {
+            cluster.stop.stop();
+            nodes::stop_all(&cluster.nodelist, deadline);
+        }
+        notifications::wait_for_deliveries(deadline);
+    }
+
+    // Ends the gossip and archiving threads, waiting for any round in progress. Applies to every
+    // clone of this Core, and it can't be started again, so only for when you're done with it.
+    pub fn stop(&self) {
+        if let Some(ref cluster) = self.cluster {
+            cluster.stop.stop();
+            let threads: Vec<JoinHandle<()>> = cluster.threads.lock().unwrap().drain(..).collect();
+            for handle in threads {
+                if handle.join().is_err() {
+                    warn!("A core thread panicked while stopping");
+                }
+            }
+        }
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        match self.cluster {
+            Some(ref cluster) => cluster.members.alive_urls(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct CoreKey;
+
+impl Key for CoreKey {
+    type Value = Core;
+}
+
+pub fn get_core(req: &Request) -> Core {
+    req.extensions.get::<persistent::Read<CoreKey>>().expect("core is set up").as_ref().clone()
+}
@@ -0,0 +1,220 @@
+use hyper;
+use hyper::client::{Client, RequestBuilder};
+use hyper::header::{Authorization, Bearer};
+use hyper::net::{Fresh, HttpsConnector, Openssl};
+use hyper::server::{Listening, Server};
+use iron::{BeforeMiddleware, Chain, Handler, IronError, IronResult, Protocol, Request, Timeouts};
+use iron::status;
+use openssl::ssl::{SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_NONE, SSL_VERIFY_PEER, SslContext, SslMethod};
+use openssl::x509::X509FileType;
+use potboiler_common::auth::constant_time_eq;
+use potboiler_common::config;
+use potboiler_common::string_error::StringError;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Node-to-node traffic goes to the /cluster routes. Peers prove they're part of the cluster with
+// the shared cluster_token (if one is set), and talk to each other over TLS if the URLs they
+// advertise are https ones. Outgoing connections check the other end's certificate against
+// tls_ca, and present tls_client_cert/tls_client_key if they're given. With tls_client_ca set,
+// core's own listener only takes connections with a client certificate signed by it.
+
+fn cluster_token() -> Option<String> {
+    config::current().cluster_token
+}
+
+// The certificate and key for core's own listener, if it should use TLS. The config's checked
+// they come as a pair.
+pub fn listener_tls() -> Option<(PathBuf, PathBuf)> {
+    let config = config::current();
+    match (config.tls_cert, config.tls_key) {
+        (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+        _ => None,
+    }
+}
+
+fn client_ssl_context() -> Result<SslContext, StringError> {
+    let config = config::current();
+    let mut context = try!(SslContext::new(SslMethod::Sslv23)
+        .map_err(|err| StringError::from(format!("Can't make SSL context: {:?}", err))));
+    match config.tls_ca {
+        Some(ca) => {
+            try!(context.set_CA_file(&ca)
+                .map_err(|err| StringError::from(format!("Can't load tls_ca from {}: {:?}", ca, err))));
+        }
+        None => {
+            try!(context.set_default_verify_paths()
+                .map_err(|err| StringError::from(format!("Can't load default CAs: {:?}", err))));
+        }
+    }
+    context.set_verify(SSL_VERIFY_PEER, None);
+    if let (Some(cert), Some(key)) = (config.tls_client_cert, config.tls_client_key) {
+        try!(context.set_certificate_file(&cert, X509FileType::PEM)
+            .map_err(|err| StringError::from(format!("Can't load {}: {:?}", cert, err))));
+        try!(context.set_private_key_file(&key, X509FileType::PEM)
+            .map_err(|err| StringError::from(format!("Can't load {}: {:?}", key, err))));
+    }
+    if config.tls_insecure {
+        warn!("Not checking peer certificates, as tls_insecure is set");
+        context.set_verify(SSL_VERIFY_NONE, None);
+    }
+    Ok(context)
+}
+
+lazy_static! {
+    // Made once, as loading the certificates is the slow part of making a client
+    static ref CLIENT_SSL: Option<Arc<SslContext>> = match client_ssl_context() {
+        Ok(context) => Some(Arc::new(context)),
+        Err(err) => {
+            warn!("Can't set up TLS for talking to other nodes, so only using plain HTTP: {}", err);
+            None
+        }
+    };
+}
+
+// A client for talking to other nodes, over either http or https
+pub fn client(timeout: Duration) -> Client {
+    let mut client = match *CLIENT_SSL {
+        Some(ref context) => {
+            Client::with_connector(HttpsConnector::new(Openssl { context: context.clone() }))
+        }
+        None => Client::new(),
+    };
+    client.set_read_timeout(Some(timeout));
+    client.set_write_timeout(Some(timeout));
+    client
+}
+
+lazy_static! {
+    // Shared, so connections to peers are kept alive and reused rather than made afresh for every
+    // check, push and fetch
+    static ref PEER_CLIENT: Client = client(config::current().timeout());
+}
+
+pub fn peer_client() -> &'static Client {
+    &*PEER_CLIENT
+}
+
+fn authorise<'a>(builder: RequestBuilder<'a>) -> RequestBuilder<'a> {
+    match cluster_token() {
+        Some(token) => builder.header(Authorization(Bearer { token: token })),
+        None => builder,
+    }
+}
+
+pub fn get<'a>(client: &'a Client, url: &str) -> RequestBuilder<'a> {
+    authorise(client.get(url))
+}
+
+pub fn post<'a>(client: &'a Client, url: &str) -> RequestBuilder<'a> {
+    authorise(client.post(url))
+}
+
+pub struct PeerAuth;
+
+impl BeforeMiddleware for PeerAuth {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let expected = match cluster_token() {
+            Some(val) => val,
+            None => return Ok(()),
+        };
+        let ok = match req.headers.get::<Authorization<Bearer>>() {
+            Some(&Authorization(Bearer { ref token })) => {
+                constant_time_eq(token.as_bytes(), expected.as_bytes())
+            }
+            None => false,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(IronError::new(StringError::from("Missing or bad cluster token"),
+                               (status::Unauthorized, "Node-to-node routes need the cluster token")))
+        }
+    }
+}
+
+// Wraps a handler so only other nodes can use it
+pub fn peer_only<H: Handler>(handler: H) -> Chain {
+    let mut chain = Chain::new(handler);
+    chain.link_before(PeerAuth);
+    chain
+}
+
+fn server_ssl_context(cert: &PathBuf, key: &PathBuf) -> Result<SslContext, StringError> {
+    let mut context = try!(SslContext::new(SslMethod::Sslv23)
+        .map_err(|err| StringError::from(format!("Can't make SSL context: {:?}", err))));
+    try!(context.set_certificate_file(cert, X509FileType::PEM)
+        .map_err(|err| StringError::from(format!("Can't load {:?}: {:?}", cert, err))));
+    try!(context.set_private_key_file(key, X509FileType::PEM)
+        .map_err(|err| StringError::from(format!("Can't load {:?}: {:?}", key, err))));
+    if let Some(ca) = config::current().tls_client_ca {
+        try!(context.set_CA_file(&ca)
+            .map_err(|err| StringError::from(format!("Can't load tls_client_ca from {}: {:?}", ca, err))));
+        context.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+    }
+    Ok(context)
+}
+
+// Hands requests from our own TLS server to iron, which is what iron's own listener does, as that
+// can't be given an SSL context to check client certificates with
+struct TlsHandler<H> {
+    handler: H,
+    addr: SocketAddr,
+    protocol: Protocol,
+}
+
+impl<H: Handler> hyper::server::Handler for TlsHandler<H> {
+    fn handle<'a, 'k>(&'a self,
+                      http_req: hyper::server::Request<'a, 'k>,
+                      mut http_res: hyper::server::Response<'a, Fresh>) {
+        *http_res.status_mut() = hyper::status::StatusCode::InternalServerError;
+        match Request::from_http(http_req, self.addr, &self.protocol) {
+            Ok(mut req) => {
+                let res = match self.handler.handle(&mut req) {
+                    Ok(res) => res,
+                    Err(err) => {
+                        debug!("Error handling {:?}: {:?}", req.url, err.error);
+                        err.response
+                    }
+                };
+                res.write_back(http_res);
+            }
+            Err(err) => {
+                warn!("Bad request: {}", err);
+                *http_res.status_mut() = hyper::status::StatusCode::BadRequest;
+                let _ = http_res.send(b"");
+            }
+        }
+    }
+}
+
+// Serves the handler over https with the certificate and key from listener_tls
+pub fn listen_tls<H: Handler>(handler: H,
+                              addr: &str,
+                              threads: usize,
+                              (cert, key): (PathBuf, PathBuf),
+                              timeouts: Timeouts)
+                              -> Result<Listening, StringError> {
+    let addr = try!(try!(addr.to_socket_addrs()
+            .map_err(|err| StringError::from(format!("Bad listen address {}: {:?}", addr, err))))
+        .next()
+        .ok_or(StringError::from(format!("No address for {}", addr))));
+    let context = try!(server_ssl_context(&cert, &key));
+    let mut server = try!(Server::https(addr, Openssl { context: Arc::new(context) })
+        .map_err(|err| StringError::from(format!("Can't listen on {}: {:?}", addr, err))));
+    server.keep_alive(timeouts.keep_alive);
+    server.set_read_timeout(timeouts.read);
+    server.set_write_timeout(timeouts.write);
+    let handler = TlsHandler {
+        handler: handler,
+        addr: addr,
+        protocol: Protocol::Https {
+            certificate: cert,
+            key: key,
+        },
+    };
+    server.handle_threads(handler, threads)
+        .map_err(|err| StringError::from(format!("Can't listen on {}: {:?}", addr, err)))
+}
@@ -0,0 +1,221 @@
+use api;
+use forks;
+use iron::prelude::{IronResult, Request, Response};
+use iron::response::{ResponseBody, WriteBody};
+use iron::status;
+use logs;
+use nodes;
+use persistent;
+use postgres::GenericConnection;
+use potboiler_common::db;
+use potboiler_common::error::ServiceError;
+use potboiler_common::iron_str_error;
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use r2d2;
+use r2d2_postgres;
+use serde_json;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use store::pg;
+use uuid::Uuid;
+
+pub type PostgresPool = r2d2::Pool<r2d2_postgres::PostgresConnectionManager>;
+
+fn parse_param<T, F>(req: &mut Request, name: &str, parse: F) -> IronResult<Option<T>>
+    where F: Fn(&str) -> Option<T>
+{
+    match logs::query_param(req, name) {
+        Some(raw) => {
+            match parse(&raw) {
+                Some(val) => Ok(Some(val)),
+                None => Err(iron_str_error(StringError::from(format!("Bad {} parameter: {}", name, raw)))),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+// Writes out whole chains, in chain order, so that an import can check the linkage as it goes
+struct LogExport {
+    pool: PostgresPool,
+    owner: Option<Uuid>,
+    stream: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl LogExport {
+    fn wanted(&self, log: &Log) -> bool {
+        let secs = log.when.time.as_timespec().sec;
+        self.since.map(|since| secs >= since).unwrap_or(true) &&
+        self.until.map(|until| secs < until).unwrap_or(true)
+    }
+
+    fn export(&self, conn: &GenericConnection, res: &mut ResponseBody) -> Result<(), StringError> {
+        let firsts = try!(conn.query("SELECT id from log WHERE prev is null and ($1::uuid is null or owner = \
+                                      $1) and ($2::varchar is null or stream = $2)",
+                                     &[&self.owner, &self.stream]));
+        for row in &firsts {
+            let mut current: Option<Uuid> = Some(row.get("id"));
+            while let Some(id) = current {
+                let log = try!(try!(pg::read_log(conn, &id))
+                    .ok_or(StringError::from(format!("Missing log {} in chain", id))));
+                if self.wanted(&log) {
+                    try!(res.write_all(try!(serde_json::to_string(&log)).as_bytes()));
+                    try!(res.write_all(b"\n"));
+                }
+                current = log.next;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WriteBody for LogExport {
+    fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
+        let conn = try!(self.pool
+            .get()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err))));
+        self.export(&*conn, res).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+}
+
+pub fn export_log(req: &mut Request) -> IronResult<Response> {
+    let pool = match req.extensions.get::<persistent::Read<db::PostgresDB>>() {
+        Some(pool) => (**pool).clone(),
+        None => {
+            return Ok(Response::with((status::InternalServerError)));
+        }
+    };
+    let export = LogExport {
+        pool: pool,
+        owner: try!(parse_param(req, "owner", |raw| Uuid::parse_str(raw).ok())),
+        stream: logs::query_param(req, "stream"),
+        since: try!(parse_param(req, "since", |raw| raw.parse::<i64>().ok())),
+        until: try!(parse_param(req, "until", |raw| raw.parse::<i64>().ok())),
+    };
+    let body: Box<WriteBody> = Box::new(export);
+    Ok(Response::with((status::Ok, body)))
+}
+
+// Checks that the log carries on from what we've already got, either locally or earlier in
+// the same import, and that nothing else has already been chained after its predecessor
+fn check_linkage(conn: &GenericConnection,
+                 log: &Log,
+                 imported: &HashMap<Uuid, Log>)
+                 -> Result<(), ServiceError> {
+    match log.prev {
+        Some(prev) => {
+            let (prev_owner, prev_stream, prev_next) = match imported.get(&prev) {
+                Some(val) => (val.owner, val.stream.clone(), val.next),
+                None => {
+                    let missing = format!("{} refers to missing log {}", log.id, prev);
+                    let existing = try!(try!(pg::read_log(conn, &prev))
+                        .ok_or(ServiceError::BadRequest(missing)));
+                    (existing.owner, existing.stream, existing.next)
+                }
+            };
+            if prev_owner != log.owner || prev_stream != log.stream {
+                return Err(ServiceError::BadRequest(format!("{} is chained to {} from a different chain",
+                                                            log.id,
+                                                            prev)));
+            }
+            if let Some(next) = prev_next {
+                if next != log.id {
+                    return Err(ServiceError::BadRequest(format!("{} already has {} after it, not {}",
+                                                                prev,
+                                                                next,
+                                                                log.id)));
+                }
+            }
+        }
+        None => {
+            if let Some(existing) = try!(pg::get_first(conn, &log.owner, &log.stream)) {
+                if existing != log.id {
+                    return Err(ServiceError::BadRequest(format!("{} starts a chain that already starts \
+                                                                 with {}",
+                                                                log.id,
+                                                                existing)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// What an import did. Quarantined entries are forks of what we've already got, or carry on from
+// one, so they're kept in the forks table rather than being linked in or sent anywhere.
+struct Imported {
+    logs: Vec<Log>,
+    skipped: usize,
+    quarantined: usize,
+}
+
+// Anything wrong with the import itself is a BadRequest, and anything else a storage failure
+fn import_logs(conn: &GenericConnection, body: &str) -> Result<Imported, ServiceError> {
+    let trans = try!(conn.transaction());
+    let mut imported: HashMap<Uuid, Log> = HashMap::new();
+    let mut order = Vec::new();
+    let mut skipped = 0;
+    // Quarantined id -> the entry its fork conflicts with
+    let mut quarantined: HashMap<Uuid, Uuid> = HashMap::new();
+    for (index, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut log: Log = try!(serde_json::from_str(line)
+            .map_err(|err| ServiceError::BadRequest(format!("Line {}: {:?}", index + 1, err))));
+        if let Some(ref stream) = log.stream {
+            try!(api::check_stream_name(stream)
+                .map_err(|err| ServiceError::BadRequest(format!("Line {}: {}", index + 1, err))));
+        }
+        if imported.contains_key(&log.id) || quarantined.contains_key(&log.id) ||
+           try!(pg::read_log(&trans, &log.id)).is_some() {
+            skipped += 1;
+            continue;
+        }
+        if let Some(existing) = log.prev.and_then(|prev| quarantined.get(&prev).cloned()) {
+            try!(forks::quarantine(&trans, &log, &existing));
+            quarantined.insert(log.id, existing);
+            continue;
+        }
+        try!(check_linkage(&trans, &log, &imported));
+        log.next = None;
+        if !try!(nodes::insert_log(&trans, &log)) {
+            let existing = try!(try!(forks::quarantined_against(&trans, &log.id))
+                .ok_or(ServiceError::Storage(format!("{} wasn't inserted or quarantined", log.id))));
+            quarantined.insert(log.id, existing);
+            continue;
+        }
+        if let Some(prev) = log.prev {
+            if let Some(prev_log) = imported.get_mut(&prev) {
+                prev_log.next = Some(log.id);
+            }
+        }
+        order.push(log.id);
+        imported.insert(log.id, log);
+    }
+    try!(trans.commit());
+    Ok(Imported {
+        logs: order.iter().filter_map(|id| imported.remove(id)).collect(),
+        skipped: skipped,
+        quarantined: quarantined.len(),
+    })
+}
+
+pub fn import_log(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let notify = logs::query_param(req, "notify").map(|x| x == "true").unwrap_or(false);
+    let mut body = String::new();
+    try!(req.body.read_to_string(&mut body).map_err(iron_str_error));
+    let result = try!(import_logs(&*conn, &body));
+    let mut counts = serde_json::Map::new();
+    counts.insert(String::from("imported"), serde_json::to_value(&result.logs.len()));
+    counts.insert(String::from("skipped"), serde_json::to_value(&result.skipped));
+    counts.insert(String::from("quarantined"), serde_json::to_value(&result.quarantined));
+    if notify && !result.logs.is_empty() {
+        api::get_core(req).publish_batch(&result.logs);
+    }
+    Ok(Response::with((status::Ok, serde_json::to_string(&counts).unwrap())))
+}
@@ -0,0 +1,145 @@
+use potboiler_common::string_error::StringError;
+use serde_json::Value;
+
+// Subscriber-supplied predicates over log entries, given as the JSON sent to the subscriber (so
+// notifying only has to make that once per entry). Paths are dotted lookups into it (e.g.
+// "data.table"), containment works like JSONB @> against the entry's data.
+#[derive(Clone, Debug)]
+pub enum Filter {
+    Equals(Vec<String>, Value),
+    Prefix(Vec<String>, String),
+    Contains(Value),
+}
+
+fn parse_path(json: &Value) -> Result<Vec<String>, StringError> {
+    let path = try!(json.find("path")
+        .and_then(|path| path.as_str())
+        .ok_or(StringError::from("Filter needs a path")));
+    Ok(path.split('.').map(String::from).collect())
+}
+
+impl Filter {
+    pub fn from_json(json: &Value) -> Result<Filter, StringError> {
+        if !json.is_object() {
+            return Err(StringError::from(format!("Filter isn't a map: {:?}", json)));
+        }
+        if let Some(doc) = json.find("contains") {
+            return Ok(Filter::Contains(doc.clone()));
+        }
+        if let Some(value) = json.find("equals") {
+            return Ok(Filter::Equals(try!(parse_path(json)), value.clone()));
+        }
+        if let Some(prefix) = json.find("prefix") {
+            let prefix = try!(prefix.as_str().ok_or(StringError::from("Filter prefix isn't a string")));
+            return Ok(Filter::Prefix(try!(parse_path(json)), String::from(prefix)));
+        }
+        Err(StringError::from(format!("Filter needs one of contains, equals or prefix: {:?}", json)))
+    }
+
+    pub fn matches(&self, entry: &Value) -> bool {
+        match *self {
+            Filter::Contains(ref doc) => entry.find("data").map(|data| contains(data, doc)).unwrap_or(false),
+            Filter::Equals(ref path, ref expected) => {
+                lookup(entry, path).map(|found| found == expected).unwrap_or(false)
+            }
+            Filter::Prefix(ref path, ref prefix) => {
+                lookup(entry, path)
+                    .and_then(|found| found.as_str())
+                    .map(|found| found.starts_with(prefix.as_str()))
+                    .unwrap_or(false)
+            }
+        }
+    }
+}
+
+fn lookup<'a>(value: &'a Value, path: &Vec<String>) -> Option<&'a Value> {
+    let mut current = value;
+    for key in path {
+        current = match current.find(key) {
+            Some(val) => val,
+            None => return None,
+        };
+    }
+    Some(current)
+}
+
+fn contains(value: &Value, doc: &Value) -> bool {
+    match (value, doc) {
+        (&Value::Object(ref value_map), &Value::Object(ref doc_map)) => {
+            doc_map.iter().all(|(key, doc_value)| {
+                value_map.get(key).map(|val| contains(val, doc_value)).unwrap_or(false)
+            })
+        }
+        (&Value::Array(ref values), &Value::Array(ref docs)) => {
+            docs.iter().all(|doc_value| values.iter().any(|val| contains(val, doc_value)))
+        }
+        (&Value::Array(ref values), _) => values.iter().any(|val| val == doc),
+        _ => value == doc,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use potboiler_common::clock;
+    use potboiler_common::types::Log;
+    use serde_json::{self, Value};
+    use super::Filter;
+    use uuid::Uuid;
+
+    // As it'd be sent to subscribers
+    fn log_with(data: &str) -> Value {
+        serde_json::to_value(&Log {
+            id: Uuid::new_v4(),
+            owner: Uuid::new_v4(),
+            prev: None,
+            next: None,
+            when: clock::get_timestamp_from_state(&clock::init_clock().clock_state),
+            data: serde_json::from_str(data).unwrap(),
+            stream: Some(String::from("kv")),
+            trace: None,
+        })
+    }
+
+    fn filter(raw: &str) -> Filter {
+        Filter::from_json(&serde_json::from_str(raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parses_each_kind() {
+        assert!(Filter::from_json(&Value::String(String::from("equals"))).is_err());
+        assert!(Filter::from_json(&serde_json::from_str(r#"{"path": "data.table"}"#).unwrap()).is_err());
+        assert!(Filter::from_json(&serde_json::from_str(r#"{"equals": "users"}"#).unwrap()).is_err());
+        assert!(Filter::from_json(&serde_json::from_str(r#"{"path": "stream", "prefix": 1}"#).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn equals_looks_up_the_path() {
+        let log = log_with(r#"{"table": "users", "key": "alice"}"#);
+        assert!(filter(r#"{"path": "data.table", "equals": "users"}"#).matches(&log));
+        assert!(filter(r#"{"path": "stream", "equals": "kv"}"#).matches(&log));
+        assert!(!filter(r#"{"path": "data.table", "equals": "orders"}"#).matches(&log));
+        assert!(!filter(r#"{"path": "data.missing.deeper", "equals": "users"}"#).matches(&log));
+    }
+
+    #[test]
+    fn prefix_needs_a_string() {
+        let log = log_with(r#"{"key": "user-1", "count": 1}"#);
+        assert!(filter(r#"{"path": "data.key", "prefix": "user-"}"#).matches(&log));
+        assert!(!filter(r#"{"path": "data.key", "prefix": "order-"}"#).matches(&log));
+        assert!(!filter(r#"{"path": "data.count", "prefix": "1"}"#).matches(&log));
+    }
+
+    #[test]
+    fn contains_works_like_jsonb() {
+        let log = log_with(r#"{"table": "users", "tags": ["a", "b"],
+                              "change": {"name": "alice", "age": 3}}"#);
+        assert!(filter(r#"{"contains": {"table": "users"}}"#).matches(&log));
+        assert!(filter(r#"{"contains": {"change": {"name": "alice"}}}"#).matches(&log));
+        assert!(filter(r#"{"contains": {"tags": ["b"]}}"#).matches(&log));
+        assert!(filter(r#"{"contains": {"tags": "a"}}"#).matches(&log));
+        assert!(!filter(r#"{"contains": {"tags": ["c"]}}"#).matches(&log));
+        assert!(!filter(r#"{"contains": {"change": {"name": "bob"}}}"#).matches(&log));
+        assert!(!filter(r#"{"contains": {"missing": 1}}"#).matches(&log));
+    }
+}
@@ -0,0 +1,88 @@
+use iron::prelude::{IronResult, Request, Response};
+use iron::status;
+use persistent;
+use postgres::GenericConnection;
+use potboiler_common::config;
+use potboiler_common::db;
+use potboiler_common::iron_str_error;
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use serde_json;
+use time;
+use uuid::Uuid;
+
+// A fork is a second entry claiming the same place in an owner's chain as one we already have:
+// either a different successor to the same prev, or a different start to the chain. That only
+// happens if two nodes are running with the same server id (or one node raced with itself),
+// so the later entry is quarantined in the forks table rather than being linked in.
+
+pub fn quarantine(conn: &GenericConnection, log: &Log, existing: &Uuid) -> Result<(), StringError> {
+    error!("Fork detected for owner {} ({:?}): {} conflicts with existing entry {} after {:?}. Quarantining \
+            it.",
+           log.owner,
+           log.stream,
+           log.id,
+           existing,
+           log.prev);
+    try!(conn.execute("INSERT INTO forks (id, owner, stream, prev, existing, body, detected) VALUES ($1, $2, \
+                       $3, $4, $5, $6, $7) ON CONFLICT (id) DO NOTHING",
+                      &[&log.id,
+                        &log.owner,
+                        &log.stream,
+                        &log.prev,
+                        existing,
+                        &serde_json::to_value(log),
+                        &time::get_time().sec]));
+    Ok(())
+}
+
+// If the entry has been quarantined, the one it conflicted with (which is in the log)
+pub fn quarantined_against(conn: &GenericConnection, id: &Uuid) -> Result<Option<Uuid>, StringError> {
+    let rows = try!(conn.query("SELECT existing from forks WHERE id=$1", &[id]));
+    Ok(rows.iter().next().map(|row| row.get("existing")))
+}
+
+pub fn list_forks(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let rows = try!(conn.query("SELECT owner, stream, prev, existing, body, detected from forks", &[])
+        .map_err(iron_str_error));
+    let mut forks = Vec::new();
+    for row in &rows {
+        let mut map = serde_json::Map::new();
+        let owner: Uuid = row.get("owner");
+        let stream: Option<String> = row.get("stream");
+        let prev: Option<Uuid> = row.get("prev");
+        let existing: Uuid = row.get("existing");
+        let body: serde_json::Value = row.get("body");
+        let detected: i64 = row.get("detected");
+        map.insert(String::from("owner"), serde_json::to_value(&owner));
+        map.insert(String::from("stream"), serde_json::to_value(&stream));
+        map.insert(String::from("prev"), serde_json::to_value(&prev));
+        map.insert(String::from("existing"), serde_json::to_value(&existing));
+        map.insert(String::from("quarantined"), body);
+        map.insert(String::from("detected"), serde_json::to_value(&detected));
+        forks.push(serde_json::Value::Object(map));
+    }
+    Ok(Response::with((status::Ok, serde_json::to_string(&forks).unwrap())))
+}
+
+// A node whose own id has forked is probably sharing it with another node, so shouldn't carry on
+// appending under it until someone's looked into it
+pub fn check_own_id(conn: &GenericConnection, server_id: &Uuid) -> Result<(), StringError> {
+    let rows = try!(conn.query("SELECT count(*) from forks WHERE owner=$1", &[server_id]));
+    let count: i64 = rows.get(0).get(0);
+    if count == 0 {
+        return Ok(());
+    }
+    if config::current().allow_forked_id {
+        warn!("Server id {} has {} known forks, but starting anyway as allow_forked_id is set",
+              server_id,
+              count);
+        Ok(())
+    } else {
+        Err(StringError::from(format!("Server id {} has {} known forks (see /log/forks), so it's probably in \
+                                       use by another node. Set ALLOW_FORKED_ID=true to start anyway.",
+                                      server_id,
+                                      count)))
+    }
+}
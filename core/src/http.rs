@@ -0,0 +1,93 @@
+use api::{self, Core};
+use cluster;
+use export;
+use forks;
+use iron::Chain;
+use logger::Logger;
+use logs;
+use membership;
+use nodes;
+use notifications;
+use persistent::Read as PRead;
+use persistent::State;
+use potboiler_common::{auth, db, error, health, metrics, shutdown, trace};
+use potboiler_common::clock::ClockMiddleware;
+use retention;
+use router::Router;
+use snapshots;
+use status;
+
+// The HTTP API, as a thin layer over a Core
+pub fn chain(core: &Core) -> Chain {
+    let (logger_before, logger_after) = Logger::new(None);
+    let (metrics_before, metrics_after) = metrics::middleware();
+    let mut router = Router::new();
+    // Routes any storage backend can serve
+    router.get("/metrics", metrics::serve);
+    router.get("/health", health::live);
+    router.get("/ready", health::ready);
+    router.get("/log", auth::with_scope("log:read", logs::log_lasts));
+    router.post("/log", auth::with_scope("log:write", logs::new_log));
+    router.post("/log/batch", auth::with_scope("log:write", logs::new_log_batch));
+    router.get("/log/first", auth::with_scope("log:read", logs::log_firsts));
+    router.get("/log/streams", auth::with_scope("log:read", logs::stream_list));
+    router.get("/log/streams/:stream", auth::with_scope("log:read", logs::stream_lasts));
+    router.post("/log/streams/:stream", auth::with_scope("log:write", logs::new_stream_log));
+    router.get("/log/streams/:stream/first", auth::with_scope("log:read", logs::stream_firsts));
+    router.post("/log/streams/:stream/batch", auth::with_scope("log:write", logs::new_stream_log_batch));
+    router.get("/log/:entry_id", auth::with_scope("log:read", logs::get_log));
+    router.get("/log/:entry_id/after", auth::with_scope("log:read", logs::log_range));
+    router.post("/log/register", auth::with_scope("log:subscribe", notifications::log_register));
+    router.post("/log/deregister", auth::with_scope("log:subscribe", notifications::log_deregister));
+    if core.cluster().is_some() {
+        // Everything else still needs Postgres
+        router.get("/log/forks", auth::with_scope("log:read", forks::list_forks));
+        router.get("/log/export", auth::with_scope("log:read", export::export_log));
+        router.post("/log/import", auth::with_scope("log:write", export::import_log));
+        router.post("/log/compact", auth::with_scope("log:admin", snapshots::compact));
+        router.get("/snapshots", auth::with_scope("log:read", snapshots::list_snapshots));
+        router.post("/snapshots", auth::with_scope("log:write", snapshots::create_snapshot));
+        router.get("/snapshots/:snapshot_id", auth::with_scope("log:read", snapshots::get_snapshot));
+        router.post("/snapshots/:snapshot_id/ack", auth::with_scope("log:write", snapshots::ack_snapshot));
+        router.get("/retention", auth::with_scope("log:read", retention::list_policies));
+        router.post("/retention", auth::with_scope("log:admin", retention::set_policy));
+        router.delete("/retention", auth::with_scope("log:admin", retention::remove_policy));
+        router.get("/archives", auth::with_scope("log:read", retention::list_archives));
+        router.get("/members", auth::with_scope("nodes:read", membership::member_list));
+        router.post("/members/leave", auth::with_scope("nodes:admin", membership::member_leave));
+        router.get("/nodes", auth::with_scope("nodes:read", nodes::node_list));
+        router.post("/nodes", auth::with_scope("nodes:admin", nodes::node_add));
+        router.delete("/nodes", auth::with_scope("nodes:admin", nodes::node_remove));
+        router.get("/status/replication", auth::with_scope("nodes:read", status::replication));
+        // Node-to-node routes, which need the cluster token
+        router.get("/cluster/log", cluster::peer_only(logs::log_lasts));
+        router.post("/cluster/log/other", cluster::peer_only(logs::other_log));
+        router.get("/cluster/log/first", cluster::peer_only(logs::log_firsts));
+        router.get("/cluster/log/streams", cluster::peer_only(logs::stream_list));
+        router.get("/cluster/log/streams/:stream", cluster::peer_only(logs::stream_lasts));
+        router.get("/cluster/log/streams/:stream/first", cluster::peer_only(logs::stream_firsts));
+        router.get("/cluster/log/:entry_id", cluster::peer_only(logs::get_log));
+        router.get("/cluster/snapshots", cluster::peer_only(snapshots::list_snapshots));
+        router.post("/cluster/gossip", cluster::peer_only(membership::gossip));
+        router.post("/cluster/gossip/ping-req", cluster::peer_only(membership::ping_req));
+    }
+    let mut chain = Chain::new(router);
+    chain.link_before(shutdown::Drain);
+    chain.link_before(metrics_before);
+    chain.link_before(trace::Tracing);
+    chain.link_before(logger_before);
+    chain.link_before(auth::init_auth());
+    chain.link_after(error::JsonErrors);
+    chain.link_after(logger_after);
+    if let Some(cluster) = core.cluster() {
+        chain.link_before(State::<nodes::Nodes>::one(cluster.nodelist.clone()));
+        chain.link_before(PRead::<membership::Members>::one(cluster.members.clone()));
+        chain.link(PRead::<db::PostgresDB>::both(cluster.pool.clone()));
+    }
+    chain.link_before(ClockMiddleware { clock_state: core.clock() });
+    chain.link(PRead::<api::CoreKey>::both(core.clone()));
+    chain.link_after(metrics_after);
+    chain.link_after(trace::Tracing);
+    chain.link_after(shutdown::Drain);
+    chain
+}
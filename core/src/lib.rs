@@ -0,0 +1,52 @@
+#[macro_use]
+extern crate schemamama;
+extern crate schemamama_postgres;
+extern crate postgres;
+#[macro_use]
+extern crate log;
+extern crate iron;
+extern crate router;
+extern crate logger;
+extern crate hyper;
+extern crate url;
+extern crate uuid;
+extern crate serde;
+extern crate serde_json;
+extern crate hybrid_clocks;
+extern crate r2d2;
+extern crate r2d2_postgres;
+extern crate persistent;
+#[macro_use]
+extern crate potboiler_common;
+extern crate urlencoded;
+extern crate plugin;
+extern crate flate2;
+extern crate time;
+extern crate openssl;
+extern crate rusqlite;
+#[macro_use]
+extern crate lazy_static;
+
+pub mod api;
+pub mod cluster;
+mod export;
+pub mod filters;
+mod forks;
+pub mod notifications;
+mod nodes;
+mod orphans;
+mod logs;
+mod membership;
+mod replication;
+mod retention;
+mod runtime;
+mod schema;
+mod snapshots;
+mod status;
+pub mod store;
+#[cfg(test)]
+mod testing;
+mod types;
+pub mod http;
+
+pub use api::{Core, CoreError};
@@ -1,29 +1,26 @@
-use hybrid_clocks;
+use api;
 use iron;
 use iron::modifiers::Redirect;
 use iron::prelude::{IronError, IronResult, Request, Response};
 use iron::status;
-use nodes;
-use notifications;
+use orphans;
 use persistent;
-use postgres::rows::{Row, RowIndex};
-use postgres::types::FromSql;
-use potboiler_common::{clock, db, server_id};
+use plugin::Pluggable;
+use potboiler_common::{db, get_req_key, iron_storage_error, iron_str_error};
+use potboiler_common::error::ServiceError;
+use potboiler_common::string_error::StringError;
 use potboiler_common::types::Log;
 use router::Router;
 use serde_json::{self, Map, Value};
-use std::io::{Cursor, Read};
-use std::ops::Deref;
-use std::sync::Arc;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::Read;
+use urlencoded::UrlEncodedQuery;
 use uuid::Uuid;
 
-fn log_status<T: Into<String>>(req: &mut Request, stmt: T) -> IronResult<Response> {
-    let conn = get_pg_connection!(&req);
-    let stmt = conn.prepare(&stmt.into()).expect("prepare failure");
+fn log_status(ends: HashMap<Uuid, Uuid>) -> IronResult<Response> {
     let mut logs = Map::new();
-    for row in &stmt.query(&[]).expect("last select works") {
-        let id: Uuid = row.get("id");
-        let owner: Uuid = row.get("owner");
+    for (owner, id) in ends {
         logs.insert(owner.to_string(), serde_json::to_value(&id.to_string()));
     }
     let value = Value::Object(logs);
@@ -31,57 +28,51 @@ fn log_status<T: Into<String>>(req: &mut Request, stmt: T) -> IronResult<Respons
 }
 
 pub fn log_lasts(req: &mut Request) -> IronResult<Response> {
-    log_status(req, "SELECT id, owner from log WHERE next is null")
+    log_status(try!(api::get_core(req).heads(&None)))
 }
 
 pub fn log_firsts(req: &mut Request) -> IronResult<Response> {
-    log_status(req, "SELECT id, owner from log WHERE prev is null")
+    log_status(try!(api::get_core(req).firsts(&None)))
 }
 
-fn json_from_body(mut req: &mut Request) -> Result<serde_json::Value, serde_json::Error> {
-    let body_string = {
-        let mut body = String::new();
-        req.body.read_to_string(&mut body).expect("could read from body");
-        body
-    };
-    return match serde_json::de::from_str(&body_string) {
-        Ok(val) => Ok(val),
-        Err(err) => Err(err),
-    };
+fn get_stream_name(req: &Request) -> IronResult<String> {
+    let stream = try!(get_req_key(req, "stream").ok_or(iron_str_error(StringError::from("No stream name"))));
+    try!(api::check_stream_name(&stream));
+    Ok(stream)
 }
 
-pub fn new_log(mut req: &mut Request) -> IronResult<Response> {
-    let conn = get_pg_connection!(&req);
-    let json: Value = match json_from_body(req) {
-        Ok(val) => val,
-        Err(err) => return Err(IronError::new(err, (status::BadRequest, "Bad JSON"))),
-    };
-    let id = Uuid::new_v4();
-    let hyphenated = id.hyphenated().to_string();
-    let server_id = get_server_id!(&req).deref().clone();
-    let stmt = conn.prepare("SELECT id from log WHERE next is null and owner = $1 LIMIT 1")
-        .expect("prepare failure");
-    let results = stmt.query(&[&server_id]).expect("last select works");
-    let previous = if results.is_empty() {
-        None
-    } else {
-        let row = results.get(0);
-        let id: Uuid = row.get("id");
-        Some(id)
-    };
-    let when = clock::get_timestamp(&mut req);
-    let log = Log {
-        id: id,
-        owner: server_id.clone(),
-        prev: previous,
-        next: None,
-        when: when,
-        data: json.clone(),
-    };
-    nodes::insert_log(&conn, &log);
-    let log_arc = Arc::new(log);
-    notifications::notify_everyone(req, log_arc.clone());
-    nodes::notify_everyone(req, log_arc.clone());
+pub fn stream_lasts(req: &mut Request) -> IronResult<Response> {
+    let stream = try!(get_stream_name(req));
+    log_status(try!(api::get_core(req).heads(&Some(stream))))
+}
+
+pub fn stream_firsts(req: &mut Request) -> IronResult<Response> {
+    let stream = try!(get_stream_name(req));
+    log_status(try!(api::get_core(req).firsts(&Some(stream))))
+}
+
+pub fn stream_list(req: &mut Request) -> IronResult<Response> {
+    let streams = try!(api::get_core(req).streams());
+    Ok(Response::with((status::Ok, serde_json::ser::to_string(&streams).unwrap())))
+}
+
+pub fn query_param(req: &mut Request, name: &str) -> Option<String> {
+    match req.get_ref::<UrlEncodedQuery>() {
+        Ok(values) => values.get(name).and_then(|x| x.first()).map(|x| x.clone()),
+        Err(_) => None,
+    }
+}
+
+pub fn json_from_body(req: &mut Request) -> Result<serde_json::Value, ServiceError> {
+    let mut body = String::new();
+    try!(req.body.read_to_string(&mut body));
+    Ok(try!(serde_json::de::from_str(&body)))
+}
+
+fn append_log(req: &mut Request, stream: Option<String>) -> IronResult<Response> {
+    let json = try!(json_from_body(req));
+    let log = try!(api::get_core(req).append(stream, json));
+    let hyphenated = log.id.hyphenated().to_string();
     let new_url = {
         let req_url = req.url.clone();
         let base_url = req_url.into_generic_url();
@@ -92,36 +83,59 @@ pub fn new_log(mut req: &mut Request) -> IronResult<Response> {
                        Redirect(iron::Url::from_generic_url(new_url).expect("URL parsed ok")))))
 }
 
+pub fn new_log(req: &mut Request) -> IronResult<Response> {
+    append_log(req, None)
+}
+
+pub fn new_stream_log(req: &mut Request) -> IronResult<Response> {
+    let stream = try!(get_stream_name(req));
+    append_log(req, Some(stream))
+}
+
+fn append_log_batch(req: &mut Request, stream: Option<String>) -> IronResult<Response> {
+    let items: Vec<Value> = match try!(json_from_body(req)) {
+        Value::Array(val) => val,
+        _ => {
+            let err = ServiceError::BadRequest(String::from("Batch must be a JSON array"));
+            return Err(IronError::from(err));
+        }
+    };
+    let logs = try!(api::get_core(req).append_batch(stream, items));
+    let ids: Vec<String> = logs.iter().map(|log| log.id.hyphenated().to_string()).collect();
+    Ok(Response::with((status::Created, serde_json::to_string(&ids).unwrap())))
+}
+
+pub fn new_log_batch(req: &mut Request) -> IronResult<Response> {
+    append_log_batch(req, None)
+}
+
+pub fn new_stream_log_batch(req: &mut Request) -> IronResult<Response> {
+    let stream = try!(get_stream_name(req));
+    append_log_batch(req, Some(stream))
+}
+
+// Peers say who they are with ?from=[their URL], so we know where to go for anything missing,
+// which is fetched in the background so the peer isn't kept waiting
 pub fn other_log(mut req: &mut Request) -> IronResult<Response> {
-    let json = json_from_body(req).unwrap();
-    let log: Log = serde_json::from_value(json).unwrap();
+    let json = try!(json_from_body(req));
     let conn = get_pg_connection!(&req);
-    let existing = conn.query("SELECT id from log WHERE id=$1 limit 1", &[&log.id])
-        .expect("bad existing query");
-    if existing.is_empty() {
-        nodes::insert_log(&conn, &log);
-        let log_arc = Arc::new(log);
-        notifications::notify_everyone(req, log_arc.clone());
-        nodes::notify_everyone(req, log_arc.clone());
+    let logs: Vec<Log> = if let Value::Array(_) = json {
+        try!(serde_json::from_value(json).map_err(ServiceError::from))
     } else {
-        info!("Told about new log item ({}) I already have", log.id);
+        vec![try!(serde_json::from_value(json).map_err(ServiceError::from))]
+    };
+    let mut added = Vec::new();
+    for log in logs {
+        added.extend(try!(orphans::add(&*conn, log).map_err(iron_storage_error)));
     }
-    Ok(Response::with((status::Ok, "Added")))
-}
-
-fn get_with_null<I, T>(row: &Row, index: I) -> Option<T>
-    where I: RowIndex,
-          T: FromSql
-{
-    match row.get_opt(index) {
-        Some(val) => {
-            match val {
-                Ok(val) => Some(val),
-                Err(_) => None,
-            }
-        }
-        None => None,
+    let core = api::get_core(req);
+    if !added.is_empty() {
+        core.publish_batch(&added);
+    }
+    if let Some(sender) = query_param(req, "from") {
+        core.fill_gaps(&sender);
     }
+    Ok(Response::with((status::Ok, "Added")))
 }
 
 pub fn get_log(req: &mut Request) -> IronResult<Response> {
@@ -137,24 +151,35 @@ pub fn get_log(req: &mut Request) -> IronResult<Response> {
             return Ok(Response::with((status::NotFound, format!("No log {}", query))));
         }
     };
-    let conn = get_pg_connection!(&req);
-    let stmt = conn.prepare("SELECT owner, next, prev, data, hlc_tstamp from log where id=$1")
-        .expect("prepare failure");
-    let results = stmt.query(&[&query_id]).expect("bad query");
-    if results.is_empty() {
-        Ok(Response::with((status::NotFound, format!("No log {}", query))))
-    } else {
-        let row = results.get(0);
-        let hlc_tstamp: Vec<u8> = row.get("hlc_tstamp");
-        let when = hybrid_clocks::Timestamp::read_bytes(Cursor::new(hlc_tstamp)).unwrap();
-        let log = Log {
-            id: query_id,
-            owner: row.get("owner"),
-            prev: get_with_null(&row, "prev"),
-            next: get_with_null(&row, "next"),
-            data: row.get("data"),
-            when: when,
-        };
-        Ok(Response::with((status::Ok, serde_json::to_string(&log).unwrap())))
+    match try!(api::get_core(req).get(&query_id)) {
+        Some(log) => Ok(Response::with((status::Ok, serde_json::to_string(&log).unwrap()))),
+        None => Ok(Response::with((status::NotFound, format!("No log {}", query)))),
+    }
+}
+
+const DEFAULT_RANGE: usize = 100;
+const MAX_RANGE: usize = 1000;
+
+// The entries after the given one in its chain, up to ?limit= of them
+pub fn log_range(req: &mut Request) -> IronResult<Response> {
+    let query = get_req_key(req, "entry_id").unwrap_or(String::new());
+    let after = match Uuid::parse_str(&query) {
+        Ok(val) => val,
+        Err(_) => {
+            return Ok(Response::with((status::NotFound, format!("No log {}", query))));
+        }
+    };
+    let limit = match query_param(req, "limit") {
+        Some(raw) => {
+            try!(raw.parse::<usize>()
+                .map_err(|_| ServiceError::BadRequest(format!("limit needs to be a number, not {}", raw))))
+        }
+        None => DEFAULT_RANGE,
+    };
+    let core = api::get_core(req);
+    if try!(core.get(&after)).is_none() {
+        return Ok(Response::with((status::NotFound, format!("No log {}", query))));
     }
+    let logs = try!(core.range(&after, cmp::min(limit, MAX_RANGE)));
+    Ok(Response::with((status::Ok, serde_json::to_string(&logs).unwrap())))
 }
@@ -0,0 +1,545 @@
+use cluster;
+use hybrid_clocks::{Timestamp, WallT};
+use hyper;
+use hyper::client::Client;
+use iron::prelude::{IronResult, Request, Response};
+use iron::status;
+use iron::typemap::Key;
+use logs;
+use nodes::{self, NodeList};
+use persistent;
+use postgres::GenericConnection;
+use potboiler_common::{clock, config, get_raw_timestamp, iron_str_error};
+use potboiler_common::string_error::StringError;
+use r2d2;
+use r2d2_postgres;
+use runtime::Stop;
+use serde_json;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use store::SharedStore;
+use types::{Gossip, Member, MemberState, PingRequest};
+use uuid::Uuid;
+
+pub type PostgresPool = r2d2::Pool<r2d2_postgres::PostgresConnectionManager>;
+
+const GOSSIP_INTERVAL_MS: u64 = 1000;
+const SUSPECT_TIMEOUT_SECS: u64 = 15;
+const INDIRECT_PINGS: usize = 3;
+// Every this many rounds, we also try one of the members we think are dead, so that two sides of
+// a partition that have each declared the other dead find each other again once it heals
+const DEAD_PROBE_ROUNDS: u64 = 10;
+
+lazy_static! {
+    // Quick to give up, as a slow answer counts as none
+    static ref GOSSIP_CLIENT: Client = cluster::client(Duration::from_secs(2));
+    static ref PING_REQ_CLIENT: Client = cluster::client(Duration::from_secs(5));
+}
+
+// SWIM-style membership. Nodes are known by their server id, and carry the URL they
+// advertise. Every round we probe one member (round-robin); if neither it nor any of a few
+// helpers asked to ping it on our behalf can reach it, it becomes suspect, and suspects that
+// don't refute it (by bumping their incarnation) within the timeout are declared dead.
+// Removal is different: it's a timestamped tombstone that nothing can supersede, so a
+// decommissioned node stays out of the cluster even if it (or a stale seed list) tries to
+// bring it back.
+struct MembershipState {
+    me: Member,
+    members: HashMap<Uuid, Member>,
+    suspected_at: HashMap<Uuid, Instant>,
+    seeds: Vec<String>,
+    next_probe: usize,
+    rounds: u64,
+    next_dead_probe: usize,
+}
+
+#[derive(Clone)]
+pub struct Membership {
+    state: Arc<Mutex<MembershipState>>,
+    nodelist: NodeList,
+    pool: PostgresPool,
+}
+
+#[derive(Copy, Clone)]
+pub struct Members;
+
+impl Key for Members {
+    type Value = Membership;
+}
+
+fn precedence(state: &MemberState) -> u8 {
+    match *state {
+        MemberState::Alive => 0,
+        MemberState::Suspect => 1,
+        MemberState::Dead | MemberState::Left => 2,
+        MemberState::Removed => 3,
+    }
+}
+
+// Newer incarnations always win, and for the same incarnation dead/left beats suspect beats alive
+fn supersedes(new: &Member, old: &Member) -> bool {
+    if old.state == MemberState::Removed {
+        return false;
+    }
+    if new.state == MemberState::Removed {
+        return true;
+    }
+    if new.incarnation != old.incarnation {
+        new.incarnation > old.incarnation
+    } else {
+        precedence(&new.state) > precedence(&old.state)
+    }
+}
+
+fn is_active(member: &Member) -> bool {
+    member.state == MemberState::Alive || member.state == MemberState::Suspect
+}
+
+fn state_to_string(state: &MemberState) -> String {
+    // FIXME: serialises with quotes, so strip them back off
+    serde_json::to_string(state).unwrap().trim_matches('"').to_string()
+}
+
+fn state_from_string(raw: &str) -> Result<MemberState, StringError> {
+    // FIXME: format! bit is a hacky workaround for https://github.com/serde-rs/serde/issues/251
+    Ok(try!(serde_json::from_str(&format!("\"{}\"", raw))))
+}
+
+pub fn advertise_url() -> String {
+    config::current().advertise_url
+}
+
+fn persist(conn: &GenericConnection, member: &Member) -> Result<(), StringError> {
+    let removed_at = member.removed_at.as_ref().map(get_raw_timestamp);
+    try!(conn.execute("INSERT INTO members (id, url, incarnation, state, removed_at) VALUES ($1, $2, $3, $4, \
+                       $5) ON CONFLICT (id) DO UPDATE SET url = $2, incarnation = $3, state = $4, removed_at \
+                       = $5",
+                      &[&member.id,
+                        &member.url,
+                        &(member.incarnation as i64),
+                        &state_to_string(&member.state),
+                        &removed_at]));
+    Ok(())
+}
+
+fn read_removed_at(raw: Option<Vec<u8>>) -> Result<Option<Timestamp<WallT>>, StringError> {
+    match raw {
+        Some(bytes) => {
+            Ok(Some(try!(Timestamp::read_bytes(Cursor::new(bytes))
+                .map_err(|err| StringError::from(format!("Bad removal timestamp: {:?}", err))))))
+        }
+        None => Ok(None),
+    }
+}
+
+fn send_gossip(url: &str, message: &Gossip) -> Result<Gossip, StringError> {
+    let mut res = try!(cluster::post(&GOSSIP_CLIENT, &format!("{}/cluster/gossip", url))
+        .body(&try!(serde_json::to_string(message)))
+        .send()
+        .map_err(|err| StringError::from(format!("Failed to gossip with {}: {:?}", url, err))));
+    if res.status != hyper::status::StatusCode::Ok {
+        return Err(StringError::from(format!("Bad status from {}: {:?}", url, res.status)));
+    }
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body));
+    Ok(try!(serde_json::from_str(&body)))
+}
+
+fn send_ping_req(helper: &str, target: &str) -> Result<Gossip, StringError> {
+    let request = PingRequest { target: target.to_string() };
+    let mut res = try!(cluster::post(&PING_REQ_CLIENT, &format!("{}/cluster/gossip/ping-req", helper))
+        .body(&try!(serde_json::to_string(&request)))
+        .send()
+        .map_err(|err| StringError::from(format!("Failed to ask {} to ping: {:?}", helper, err))));
+    if res.status != hyper::status::StatusCode::Ok {
+        return Err(StringError::from(format!("{} couldn't reach {}: {:?}", helper, target, res.status)));
+    }
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body));
+    Ok(try!(serde_json::from_str(&body)))
+}
+
+impl Membership {
+    pub fn new(pool: PostgresPool,
+               store: &SharedStore,
+               nodelist: NodeList,
+               server_id: Uuid)
+               -> Result<Membership, StringError> {
+        let conn = try!(pool.get()
+            .map_err(|err| StringError::from(format!("Couldn't get a connection: {:?}", err))));
+        let mut me = Member {
+            id: server_id,
+            url: advertise_url(),
+            incarnation: 0,
+            state: MemberState::Alive,
+            removed_at: None,
+        };
+        let mut members = HashMap::new();
+        let stmt = try!(conn.prepare("select id, url, incarnation, state, removed_at from members"));
+        for row in &try!(stmt.query(&[])) {
+            let raw_state: String = row.get("state");
+            let incarnation: i64 = row.get("incarnation");
+            let member = Member {
+                id: row.get("id"),
+                url: row.get("url"),
+                incarnation: incarnation as u64,
+                state: try!(state_from_string(&raw_state)),
+                removed_at: try!(read_removed_at(row.get("removed_at"))),
+            };
+            if member.id == server_id && member.state == MemberState::Removed {
+                // Removal is permanent, so don't try to rejoin
+                warn!("This node ({}) has been removed from the cluster, so won't rejoin it",
+                      server_id);
+                me = member;
+            } else if member.id == server_id {
+                // Start above anything we said last time, so restarting always rejoins
+                me.incarnation = member.incarnation + 1;
+            } else {
+                members.insert(member.id, member);
+            }
+        }
+        try!(persist(&*conn, &me));
+        let mut seeds = config::current().seeds;
+        seeds.extend(try!(store.nodes()));
+        for member in members.values() {
+            if is_active(member) {
+                nodes::start_checking(&nodelist, &member.url);
+            }
+        }
+        info!("Membership starting as {} ({}) with seeds {:?}",
+              me.id,
+              me.url,
+              seeds);
+        Ok(Membership {
+            state: Arc::new(Mutex::new(MembershipState {
+                me: me,
+                members: members,
+                suspected_at: HashMap::new(),
+                seeds: seeds,
+                next_probe: 0,
+                rounds: 0,
+                next_dead_probe: 0,
+            })),
+            nodelist: nodelist,
+            pool: pool,
+        })
+    }
+
+    fn persist(&self, member: &Member) {
+        let result = match self.pool.get() {
+            Ok(conn) => persist(&*conn, member),
+            Err(err) => Err(StringError::from(format!("{:?}", err))),
+        };
+        if let Err(err) = result {
+            warn!("Failed to store member {}: {:?}", member.id, err);
+        }
+    }
+
+    pub fn apply(&self, update: Member) {
+        // What to store, and which nodes to stop and start checking, which are done once we've let
+        // go of the state, as they can wait on the database and for room on the runtime
+        let mut changed = None;
+        let mut stop = Vec::new();
+        let mut start = None;
+        {
+            let mut state = self.state.lock().unwrap();
+            if update.id == state.me.id {
+                if update.state == MemberState::Removed && state.me.state != MemberState::Removed {
+                    warn!("We've been removed from the cluster, so dropping out of it");
+                    state.me = update;
+                    changed = Some(state.me.clone());
+                    stop.extend(state.members.values().map(|member| member.url.clone()));
+                } else if update.state != MemberState::Alive && state.me.state == MemberState::Alive &&
+                          update.incarnation >= state.me.incarnation {
+                    state.me.incarnation = update.incarnation + 1;
+                    info!("Refuting {:?} about us with incarnation {}",
+                          update.state,
+                          state.me.incarnation);
+                    changed = Some(state.me.clone());
+                }
+            } else {
+                let old = state.members.get(&update.id).map(|x| x.clone());
+                if let Some(ref old) = old {
+                    if !supersedes(&update, old) {
+                        return;
+                    }
+                }
+                info!("Member {} ({}) is now {:?} at incarnation {}",
+                      update.id,
+                      update.url,
+                      update.state,
+                      update.incarnation);
+                if update.state == MemberState::Suspect {
+                    state.suspected_at.entry(update.id).or_insert(Instant::now());
+                } else {
+                    state.suspected_at.remove(&update.id);
+                }
+                if let Some(ref old) = old {
+                    if old.url != update.url {
+                        stop.push(old.url.clone());
+                    }
+                }
+                if is_active(&update) {
+                    start = Some(update.url.clone());
+                } else {
+                    stop.push(update.url.clone());
+                }
+                changed = Some(update.clone());
+                state.members.insert(update.id, update);
+            }
+        }
+        if let Some(member) = changed {
+            self.persist(&member);
+        }
+        for url in stop {
+            nodes::stop_checking(&self.nodelist, &url);
+        }
+        if let Some(url) = start {
+            nodes::start_checking(&self.nodelist, &url);
+        }
+    }
+
+    fn message(&self) -> Gossip {
+        let state = self.state.lock().unwrap();
+        Gossip {
+            from: state.me.clone(),
+            members: state.members.values().map(|x| x.clone()).collect(),
+        }
+    }
+
+    fn merge(&self, gossip: Gossip) {
+        self.apply(gossip.from);
+        for member in gossip.members {
+            self.apply(member);
+        }
+    }
+
+    pub fn handle_gossip(&self, gossip: Gossip) -> Gossip {
+        self.merge(gossip);
+        self.message()
+    }
+
+    fn ping(&self, url: &str) -> Result<(), StringError> {
+        let reply = try!(send_gossip(url, &self.message()));
+        self.merge(reply);
+        Ok(())
+    }
+
+    pub fn join(&self, url: &String) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.seeds.contains(url) {
+                state.seeds.push(url.clone());
+            }
+        }
+        if let Err(err) = self.ping(url) {
+            warn!("Failed to join via {}: {}", url, err);
+        }
+    }
+
+    // Tells everyone we're going. A permanent leave (with a removal time) tombstones us, otherwise
+    // we'll rejoin next time we start up
+    pub fn leave(&self, removed_at: Option<Timestamp<WallT>>) {
+        let (me, targets) = {
+            let mut state = self.state.lock().unwrap();
+            if state.me.state == MemberState::Removed {
+                return;
+            }
+            state.me.incarnation += 1;
+            state.me.state = if removed_at.is_some() {
+                MemberState::Removed
+            } else {
+                MemberState::Left
+            };
+            state.me.removed_at = removed_at;
+            (state.me.clone(),
+             state.members.values().filter(|x| is_active(x)).map(|x| x.url.clone()).collect::<Vec<String>>())
+        };
+        self.persist(&me);
+        let message = self.message();
+        for url in targets {
+            nodes::stop_checking(&self.nodelist, &url);
+            if let Err(err) = send_gossip(&url, &message) {
+                warn!("Failed to tell {} we're leaving: {}", url, err);
+            }
+        }
+    }
+
+    // Tombstones every member at the URL, and lets gossip spread the word. Returns false if
+    // there wasn't anyone there to remove.
+    pub fn remove_url(&self, url: &String, when: Timestamp<WallT>) -> bool {
+        let removals: Vec<Member> = {
+            let mut state = self.state.lock().unwrap();
+            state.seeds.retain(|x| x != url);
+            state.members
+                .values()
+                .filter(|x| &x.url == url && x.state != MemberState::Removed)
+                .map(|x| x.clone())
+                .collect()
+        };
+        let found = !removals.is_empty();
+        for member in removals {
+            self.apply(Member {
+                state: MemberState::Removed,
+                removed_at: Some(when.clone()),
+                ..member
+            });
+        }
+        found
+    }
+
+    pub fn alive_urls(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.members.values().filter(|x| is_active(x)).map(|x| x.url.clone()).collect()
+    }
+
+    pub fn others(&self) -> Vec<Member> {
+        let state = self.state.lock().unwrap();
+        state.members.values().map(|x| x.clone()).collect()
+    }
+
+    pub fn view(&self) -> Vec<Member> {
+        let state = self.state.lock().unwrap();
+        let mut view = vec![state.me.clone()];
+        view.extend(state.members.values().map(|x| x.clone()));
+        view
+    }
+
+    fn probe_once(&self) {
+        let (unjoined, target, helpers, expired, revive) = {
+            let mut state = self.state.lock().unwrap();
+            if state.me.state == MemberState::Left || state.me.state == MemberState::Removed {
+                return;
+            }
+            let known_urls: Vec<String> = state.members.values().map(|x| x.url.clone()).collect();
+            let unjoined: Vec<String> = state.seeds
+                .iter()
+                .filter(|x| !known_urls.contains(x) && *x != &state.me.url)
+                .map(|x| x.clone())
+                .collect();
+            let mut active: Vec<Member> =
+                state.members.values().filter(|x| is_active(x)).map(|x| x.clone()).collect();
+            active.sort_by(|a, b| a.id.as_bytes().cmp(b.id.as_bytes()));
+            let target = if active.is_empty() {
+                None
+            } else {
+                state.next_probe = (state.next_probe + 1) % active.len();
+                Some(active[state.next_probe].clone())
+            };
+            let helpers: Vec<String> = active.iter()
+                .filter(|x| x.state == MemberState::Alive && Some(x.id) != target.as_ref().map(|t| t.id))
+                .take(INDIRECT_PINGS)
+                .map(|x| x.url.clone())
+                .collect();
+            let timeout = Duration::from_secs(SUSPECT_TIMEOUT_SECS);
+            let expired: Vec<Member> = state.suspected_at
+                .iter()
+                .filter(|&(_, at)| at.elapsed() > timeout)
+                .filter_map(|(id, _)| state.members.get(id))
+                .map(|x| x.clone())
+                .collect();
+            state.rounds += 1;
+            let mut dead: Vec<Member> =
+                state.members.values().filter(|x| x.state == MemberState::Dead).map(|x| x.clone()).collect();
+            let revive = if state.rounds % DEAD_PROBE_ROUNDS == 0 && !dead.is_empty() {
+                dead.sort_by(|a, b| a.id.as_bytes().cmp(b.id.as_bytes()));
+                state.next_dead_probe = (state.next_dead_probe + 1) % dead.len();
+                Some(dead[state.next_dead_probe].clone())
+            } else {
+                None
+            };
+            (unjoined, target, helpers, expired, revive)
+        };
+        for seed in unjoined {
+            if let Err(err) = self.ping(&seed) {
+                debug!("Seed {} isn't answering: {}", seed, err);
+            }
+        }
+        if let Some(target) = target {
+            if let Err(err) = self.ping(&target.url) {
+                debug!("Direct ping of {} failed: {}", target.url, err);
+                let mut reached = false;
+                for helper in helpers {
+                    match send_ping_req(&helper, &target.url) {
+                        Ok(reply) => {
+                            self.merge(reply);
+                            reached = true;
+                            break;
+                        }
+                        Err(err) => debug!("Indirect ping of {} failed: {}", target.url, err),
+                    }
+                }
+                if !reached && target.state == MemberState::Alive {
+                    self.apply(Member { state: MemberState::Suspect, ..target });
+                }
+            }
+        }
+        for member in expired {
+            if member.state == MemberState::Suspect {
+                self.apply(Member { state: MemberState::Dead, ..member });
+            }
+        }
+        // If it's back, it'll refute being dead when it sees our gossip, and its answer brings it
+        // back to life for us
+        if let Some(member) = revive {
+            if let Err(err) = self.ping(&member.url) {
+                debug!("{} is still unreachable: {}", member.url, err);
+            }
+        }
+    }
+}
+
+pub fn gossip_loop(membership: Membership, stop: Stop) {
+    let sleep_time = Duration::from_millis(GOSSIP_INTERVAL_MS);
+    let mut joined = false;
+    loop {
+        membership.probe_once();
+        if !joined {
+            // The first round has tried every seed
+            nodes::joined(&membership.nodelist);
+            joined = true;
+        }
+        if !stop.wait(sleep_time) {
+            return;
+        }
+    }
+}
+
+pub fn get_membership(req: &Request) -> Membership {
+    req.extensions.get::<persistent::Read<Members>>().unwrap().as_ref().clone()
+}
+
+pub fn gossip(req: &mut Request) -> IronResult<Response> {
+    let json = try!(logs::json_from_body(req));
+    let incoming: Gossip = try!(serde_json::from_value(json).map_err(iron_str_error));
+    let reply = get_membership(req).handle_gossip(incoming);
+    Ok(Response::with((status::Ok, serde_json::to_string(&reply).unwrap())))
+}
+
+pub fn ping_req(req: &mut Request) -> IronResult<Response> {
+    let json = try!(logs::json_from_body(req));
+    let request: PingRequest = try!(serde_json::from_value(json).map_err(iron_str_error));
+    let members = get_membership(req);
+    match send_gossip(&request.target, &members.message()) {
+        Ok(reply) => Ok(Response::with((status::Ok, serde_json::to_string(&reply).unwrap()))),
+        Err(err) => Ok(Response::with((status::BadGateway, format!("{}", err)))),
+    }
+}
+
+pub fn member_list(req: &mut Request) -> IronResult<Response> {
+    let view = get_membership(req).view();
+    Ok(Response::with((status::Ok, serde_json::to_string(&view).unwrap())))
+}
+
+pub fn member_leave(req: &mut Request) -> IronResult<Response> {
+    let permanent = logs::query_param(req, "permanent").map(|x| x == "true").unwrap_or(false);
+    let removed_at = if permanent {
+        Some(clock::get_timestamp(req))
+    } else {
+        None
+    };
+    get_membership(req).leave(removed_at);
+    Ok(Response::with(status::NoContent))
+}
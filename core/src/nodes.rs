@@ -1,32 +1,39 @@
+use api;
 use hybrid_clocks::{Clock, Timestamp, Wall, WallT};
 use hyper;
 use iron::prelude::{IronError, IronResult, Request, Response};
 use iron::status;
 use iron::typemap::Key;
-use persistent;
+use cluster;
+use forks;
+use logs;
+use membership;
+use notifications::{self, Notifier};
+use orphans;
 use persistent::State;
-use plugin::Pluggable;
-use postgres;
-use postgres::error::SqlState;
-use potboiler_common::{clock, db, get_raw_timestamp, url_from_body};
+use postgres::GenericConnection;
+use potboiler_common::{clock, config, health, url_from_body};
+use potboiler_common::metrics::{self, Counter, Gauge};
 use potboiler_common::string_error::StringError;
+use potboiler_common::trace::{self, Kind};
 use potboiler_common::types::Log;
 use r2d2;
 use r2d2_postgres;
-use resolve;
+use replication;
+use runtime;
 use serde_json;
+use snapshots;
+use std::cmp;
 use std::collections::{HashMap, HashSet};
-use std::error::Error;
 use std::io::Read;
-use std::iter::FromIterator;
-use std::ops::{Deref, DerefMut};
-use std::sync::{Mutex, RwLock};
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex, RwLock};
 use std::sync::Arc;
-use std::sync::mpsc::{Receiver, Sender, channel};
-use std::thread;
-use std::time::Duration;
-use url::Url;
-use urlencoded::UrlEncodedQuery;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, channel};
+use std::time::{Duration, Instant};
+use store::pg;
+use time::{self, Timespec};
 use uuid::Uuid;
 
 pub type LockedNode = Arc<RwLock<HashMap<String, NodeInfo>>>;
@@ -38,7 +45,137 @@ pub type SyncClock = Arc<RwLock<Clock<Wall>>>;
 pub struct Nodes;
 
 pub struct NodeInfo {
-    sender: Mutex<Sender<()>>,
+    checks: Arc<Checks>,
+    health: Arc<Mutex<PeerHealth>>,
+    replicator: replication::Replicator,
+}
+
+// Whether a peer's still being checked, and whether a check's running right now, so stopping can
+// wait for it to finish
+struct Checks {
+    state: Mutex<CheckState>,
+    idle: Condvar,
+}
+
+struct CheckState {
+    stopped: bool,
+    running: bool,
+}
+
+impl Checks {
+    fn new() -> Checks {
+        Checks {
+            state: Mutex::new(CheckState {
+                stopped: false,
+                running: false,
+            }),
+            idle: Condvar::new(),
+        }
+    }
+
+    // Returns false if we've stopped checking
+    fn begin(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return false;
+        }
+        state.running = true;
+        true
+    }
+
+    // Returns false if we've stopped checking since the check began
+    fn end(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        self.idle.notify_all();
+        !state.stopped
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+    }
+
+    // Whether the check that's running (if any) finished before the deadline
+    fn wait_until(&self, deadline: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.running {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.idle.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerHealth {
+    pub last_sync: Option<Timespec>,
+    pub failures: u32,
+    pub last_error: Option<String>,
+    pub next_check: Timespec,
+    // Owner -> head id, per stream, as of the last successful sync
+    pub advertised: Heads,
+}
+
+pub type Heads = HashMap<Option<String>, HashMap<Uuid, Uuid>>;
+
+const MAX_BACKOFF_SECS: u64 = 300;
+// Failed checks in a row, without ever syncing, before a peer stops holding up readiness
+const UNREACHABLE_FAILURES: u32 = 3;
+
+const FETCHES: Counter = Counter {
+    name: "potboiler_replication_fetches_total",
+    help: "Checks of a peer for new entries, by peer and result",
+};
+
+const FETCHED: Counter = Counter {
+    name: "potboiler_replication_fetched_entries_total",
+    help: "Entries fetched from a peer that we didn't have, by peer",
+};
+
+const PEER_LAG: Gauge = Gauge {
+    name: "potboiler_replication_lag_seconds",
+    help: "Time since we last caught up with a peer, by peer",
+};
+
+const PEER_FAILURES: Gauge = Gauge {
+    name: "potboiler_replication_failures",
+    help: "Failed checks of a peer in a row, by peer",
+};
+
+impl PeerHealth {
+    fn new() -> PeerHealth {
+        PeerHealth {
+            last_sync: None,
+            failures: 0,
+            last_error: None,
+            next_check: time::get_time(),
+            advertised: HashMap::new(),
+        }
+    }
+
+    // The sync interval, doubled for every consecutive failure, up to MAX_BACKOFF_SECS
+    fn delay(&self) -> Duration {
+        let factor = 1u64 << cmp::min(self.failures, 16);
+        let interval = config::current().sync_interval_secs;
+        Duration::from_secs(cmp::min(interval * factor, MAX_BACKOFF_SECS))
+    }
+
+    fn succeeded(&mut self, advertised: Heads) {
+        self.last_sync = Some(time::get_time());
+        self.advertised = advertised;
+        self.failures = 0;
+        self.last_error = None;
+        self.next_check = time::get_time() + time::Duration::seconds(self.delay().as_secs() as i64);
+    }
+
+    fn failed(&mut self, err: &StringError) {
+        self.failures += 1;
+        self.last_error = Some(format!("{}", err));
+        self.next_check = time::get_time() + time::Duration::seconds(self.delay().as_secs() as i64);
+    }
 }
 
 #[derive(Clone)]
@@ -46,6 +183,10 @@ pub struct NodeList {
     nodes: LockedNode,
     pool: PostgresPool,
     clock: SyncClock,
+    // Core's subscribers, for snapshots we bootstrap from
+    notifiers: Arc<RwLock<Vec<Notifier>>>,
+    // Membership has had its first go at finding the rest of the cluster
+    joined: Arc<AtomicBool>,
 }
 
 impl Key for Nodes {
@@ -88,14 +229,47 @@ fn parse_object_from_request(raw_result: Result<hyper::client::Response, hyper::
 
 fn check_host_once(host_url: &String,
                    conn: &PostgresConnection,
-                   clock_state: SyncClock)
-                   -> Result<(), StringError> {
-    let mut client = hyper::client::Client::new();
-    client.set_read_timeout(Some(Duration::from_secs(10)));
-    client.set_write_timeout(Some(Duration::from_secs(10)));
-    let check_url = format!("{}/log", &host_url);
-    info!("Checking {} ({})", host_url, check_url);
-    let raw_result = client.get(&check_url).send();
+                   clock_state: SyncClock,
+                   notifiers: &Arc<RwLock<Vec<Notifier>>>)
+                   -> Result<Heads, StringError> {
+    let client = cluster::peer_client();
+    let mut advertised = HashMap::new();
+    advertised.insert(None,
+                      try!(check_stream_once(&client, host_url, conn, clock_state.clone(), None)));
+    let streams_url = format!("{}/cluster/log/streams", &host_url);
+    let raw_result = cluster::get(&client, &streams_url).send();
+    let streams = match parse_json_from_request(raw_result) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(StringError::from(format!("Error while getting streams from {:?}: {:?}",
+                                                 host_url,
+                                                 err)));
+        }
+    };
+    let stream_array = try!(streams.as_array().ok_or(StringError::from("streams isn't an array!")));
+    for stream in try!(hashset_from_json_array(stream_array)) {
+        let heads = try!(check_stream_once(&client,
+                                           host_url,
+                                           conn,
+                                           clock_state.clone(),
+                                           Some(stream.clone())));
+        advertised.insert(Some(stream), heads);
+    }
+    return Ok(advertised);
+}
+
+fn check_stream_once(client: &hyper::client::Client,
+                     host_url: &String,
+                     conn: &PostgresConnection,
+                     clock_state: SyncClock,
+                     stream: Option<String>)
+                     -> Result<HashMap<Uuid, Uuid>, StringError> {
+    let stream_url = match stream {
+        Some(ref name) => format!("{}/cluster/log/streams/{}", &host_url, name),
+        None => format!("{}/cluster/log", &host_url),
+    };
+    info!("Checking {} ({})", host_url, stream_url);
+    let raw_result = cluster::get(&client, &stream_url).send();
     let kv = match parse_object_from_request(raw_result) {
         Ok(val) => val,
         Err(err) => {
@@ -103,6 +277,7 @@ fn check_host_once(host_url: &String,
         }
     };
     let stmt = try!(conn.prepare("SELECT 1 from log where id=$1"));
+    let mut heads = HashMap::new();
     for (key, value) in kv.iter() {
         let value_uuid = match Uuid::parse_str(try!(value.as_str()
             .ok_or(StringError::from("Not a UUID!")))) {
@@ -119,18 +294,26 @@ fn check_host_once(host_url: &String,
                 continue;
             }
         };
+        heads.insert(key_uuid, value_uuid);
         let single_item = try!(stmt.query(&[&value_uuid]));
         if !single_item.is_empty() {
             debug!("Already have {} locally", value_uuid);
             continue;
         }
 
-        let first_item = try!(conn.query("SELECT id from log WHERE prev is null and owner=$1 limit 1",
-                                         &[&key_uuid]));
+        let first_item = try!(conn.query("SELECT id from log WHERE prev is null and owner=$1 and stream is \
+                                          not distinct from $2 limit 1",
+                                         &[&key_uuid, &stream]));
         let start_uuid = if first_item.is_empty() {
-            let first_url = format!("{}/log/first", &host_url);
+            match snapshots::fetch_latest(client, host_url, &**conn, &stream) {
+                // We won't have what came before it, so our subscribers need to start from it
+                Ok(Some(snapshot)) => notifications::notify_snapshot(&notifiers.read().unwrap(), &snapshot),
+                Ok(None) => {}
+                Err(err) => warn!("Error while getting snapshots from {:?}: {:?}", host_url, err),
+            }
+            let first_url = format!("{}/first", &stream_url);
             debug!("Get first from {:?}", host_url);
-            let res = client.get(&first_url).send();
+            let res = cluster::get(client, &first_url).send();
             let first_entry = match parse_object_from_request(res) {
                 Ok(val) => val,
                 Err(err) => {
@@ -144,17 +327,16 @@ fn check_host_once(host_url: &String,
         } else {
             info!("Already have an entry from the list with server id {:?}",
                   key);
-            let last_items = try!(conn.query("SELECT id from log WHERE next is null and owner=$1 limit 1",
-                                             &[&key_uuid]));
-            if last_items.is_empty() {
-                return Err(StringError::from(format!("Can't find end entry for server id {:?}", key)));
-            }
-            let last_item = last_items.get(0);
-            let last_item_id: Uuid = last_item.get("id");
+            let last_item_id = match try!(pg::get_head(&**conn, &key_uuid, &stream)) {
+                Some(val) => val,
+                None => {
+                    return Err(StringError::from(format!("Can't find end entry for server id {:?}", key)));
+                }
+            };
             info!("Last item: {:?}", last_item_id);
-            let last_url = format!("{}/log/{}", &host_url, last_item_id);
+            let last_url = format!("{}/cluster/log/{}", &host_url, last_item_id);
             debug!("Get last from {:?}", host_url);
-            let res = client.get(&last_url).send();
+            let res = cluster::get(client, &last_url).send();
             let last_entry = match parse_object_from_request(res) {
                 Ok(val) => val,
                 Err(err) => {
@@ -172,9 +354,9 @@ fn check_host_once(host_url: &String,
                 let str_uuid = current_uuid.as_str();
                 String::from(try!(str_uuid.ok_or(format!("Current id ({}) is not a UUID", current_uuid))))
             };
-            let current_url = format!("{}/log/{}", &host_url, real_uuid);
+            let current_url = format!("{}/cluster/log/{}", &host_url, real_uuid);
             debug!("Get {} from {}", real_uuid, host_url);
-            let res = client.get(&current_url).send();
+            let res = cluster::get(client, &current_url).send();
             let current_entry = match parse_object_from_request(res) {
                 Ok(val) => val,
                 Err(err) => {
@@ -195,15 +377,20 @@ fn check_host_once(host_url: &String,
                 prev: get_uuid_from_map(&current_entry, "prev"),
                 data: try!(current_entry.get("data").ok_or(StringError::from("No data key"))).clone(),
                 when: clock::get_timestamp_from_state(&clock_state),
+                stream: stream.clone(),
+                trace: current_entry.get("trace").and_then(|trace| trace.as_str()).map(String::from),
             };
-            insert_log(conn, &log);
+            if try!(insert_log(&**conn, &log)) {
+                FETCHED.inc(&[("peer", host_url.as_str())]);
+                try!(orphans::promote(&**conn, &log.id));
+            }
             if next.is_null() {
                 break;
             }
             current_uuid = next.clone();
         }
     }
-    return Ok(());
+    return Ok(heads);
 }
 
 fn get_uuid_from_map(map: &serde_json::value::Map<String, serde_json::Value>, key: &str) -> Option<Uuid> {
@@ -224,17 +411,27 @@ fn get_uuid_from_map(map: &serde_json::value::Map<String, serde_json::Value>, ke
     };
 }
 
-pub fn insert_log(conn: &PostgresConnection, log: &Log) {
+// For entries from elsewhere. Returns false if the log was quarantined as a fork rather than
+// inserted.
+pub fn insert_log(conn: &GenericConnection, log: &Log) -> Result<bool, StringError> {
     debug!("Inserting {:?}", log);
-    if log.prev.is_some() {
-        conn.execute("UPDATE log set next = $1 where owner = $2 and id = $3",
-                     &[&log.id, &log.owner, &log.prev])
-            .expect("update worked");
+    let span = trace::for_entry("store replicated entry", Kind::Consumer, &log.trace);
+    // It may have been buffered waiting for its predecessor, but we've got it from elsewhere now
+    try!(conn.execute("DELETE from orphans where id = $1", &[&log.id]));
+    let inserted = match try!(pg::link_log(conn, log)) {
+        Some(existing) => {
+            try!(forks::quarantine(conn, log, &existing));
+            false
+        }
+        None => true,
+    };
+    if let Some(mut span) = span {
+        span.attribute("entry", log.id);
+        span.attribute("owner", log.owner);
+        span.attribute("quarantined", !inserted);
+        span.finish();
     }
-    let raw_timestamp = get_raw_timestamp(&log.when);
-    conn.execute("INSERT INTO log (id, owner, data, prev, hlc_tstamp) VALUES ($1, $2, $3, $4, $5)",
-                 &[&log.id, &log.owner, &log.data, &log.prev, &raw_timestamp])
-        .expect("insert worked");
+    Ok(inserted)
 }
 
 fn hashset_from_json_array(nodes: &Vec<serde_json::Value>) -> Result<HashSet<String>, StringError> {
@@ -252,278 +449,307 @@ fn hashset_from_json_array(nodes: &Vec<serde_json::Value>) -> Result<HashSet<Str
     return Ok(ret);
 }
 
-fn check_new_nodes(host_url: &String,
-                   conn: &PostgresConnection,
-                   nodelist: NodeList)
-                   -> Result<(), StringError> {
-    let client = hyper::client::Client::new();
-    let check_url = format!("{}/nodes?query_port=8000", &host_url);
-    info!("Checking {} ({})", host_url, check_url);
-    let raw_result = client.get(&check_url).send();
-    let remote_nodes = match parse_json_from_request(raw_result) {
-        Ok(val) => val,
-        Err(err) => {
-            warn!("Error while getting from {}: {:?}", host_url, err);
-            return Err(err);
+// One check of a peer, as a job on the runtime, which schedules the next one unless we've
+// stopped checking it
+fn check_host(host_url: String, nodelist: NodeList, checks: Arc<Checks>, health: Arc<Mutex<PeerHealth>>) {
+    if !checks.begin() {
+        return;
+    }
+    let result = nodelist.pool
+        .get()
+        .map_err(|err| StringError::from(format!("Couldn't get a connection: {:?}", err)))
+        .and_then(|conn| check_host_once(&host_url, &conn, nodelist.clock.clone(), &nodelist.notifiers));
+    let delay = match result {
+        Ok(advertised) => {
+            FETCHES.inc(&[("peer", host_url.as_str()), ("result", "ok")]);
+            let mut health = health.lock().unwrap();
+            health.succeeded(advertised);
+            health.delay()
         }
-    };
-    let remote_node_array = try!(remote_nodes.as_array()
-        .ok_or(StringError::from("remote_nodes isn't an array!")));
-    let remote_node_set: HashSet<String> = try!(hashset_from_json_array(remote_node_array));
-    let existing_nodes = try!(conn.query("SELECT url from nodes", &[]));
-    let existing_nodes_set: HashSet<String> = HashSet::from_iter(existing_nodes.iter()
-        .map(|x| x.get::<&str, String>("url")));
-    let extra_nodes = remote_node_set.difference(&existing_nodes_set)
-        .map(|x| x.clone())
-        .collect::<Vec<String>>();
-    debug!("From {} remote nodes: {:?}", check_url, remote_node_set);
-    info!("Extra nodes from {}: {:?}", check_url, extra_nodes);
-    let mut nodes = try!(nodelist.nodes.write().map_err(|x| StringError::from(x.description())));
-    for extra in extra_nodes {
-        match node_insert(&conn, &extra) {
-            InsertResult::Inserted => {
-                let (send, recv) = channel();
-                nodes.insert(extra.clone(), NodeInfo { sender: Mutex::new(send) });
-                let nodeslist = nodelist.clone();
-                thread::spawn(move || check_host(extra.clone(), nodeslist, recv));
-            }
-            InsertResult::Existing => {}
-            InsertResult::Error(err) => {
-                warn!("Error while inserting node: {:?}", err);
-            }
+        Err(msg) => {
+            FETCHES.inc(&[("peer", host_url.as_str()), ("result", "error")]);
+            let mut health = health.lock().unwrap();
+            health.failed(&msg);
+            warn!("Got an error while checking for new log items on {} ({} failures in a row, next try in \
+                   {}s): {}",
+                  host_url,
+                  health.failures,
+                  health.delay().as_secs(),
+                  msg);
+            health.delay()
         }
+    };
+    if checks.end() {
+        runtime::spawn_after(delay, move || check_host(host_url, nodelist, checks, health));
+    } else {
+        info!("Stopped checking {}", host_url);
     }
-    return Ok(());
 }
 
-macro_rules! check_should_exit {
-    ($recv:ident, $host_url:ident) => {{
-        match $recv.try_recv() {
-            Ok(_) => {
-                // Only message is "quit" at the moment
-                info!("Quitting check thread for {}", $host_url);
-                return;
-            }
-            Err(_) => {}
-        };
-    }};
-}
-
-fn check_host(host_url: String, nodelist: NodeList, recv: Receiver<()>) {
-    let sleep_time = Duration::from_secs(5);
-    let conn = nodelist.pool.get().unwrap();
-    loop {
-        check_should_exit!(recv, host_url);
-        match check_host_once(&host_url, &conn, nodelist.clock.clone()) {
-            Ok(_) => {}
-            Err(msg) => {
-                warn!("Got an error while checking for new log items on {}: {}",
-                      host_url,
-                      msg);
-            }
-        };
-        check_should_exit!(recv, host_url);
-        match check_new_nodes(&host_url, &conn, nodelist.clone()) {
-            Ok(_) => {}
-            Err(msg) => {
-                warn!("Got an error while checking for new nodes on {}: {}",
-                      host_url,
-                      msg);
+pub fn init_nodelist(pool: PostgresPool,
+                     clock_state: SyncClock,
+                     notifiers: Arc<RwLock<Vec<Notifier>>>)
+                     -> NodeList {
+    let nodelist = NodeList {
+        nodes: Arc::new(RwLock::new(HashMap::new())),
+        pool: pool,
+        clock: clock_state,
+        notifiers: notifiers,
+        joined: Arc::new(AtomicBool::new(false)),
+    };
+    let watched = nodelist.clone();
+    metrics::add_collector(move || {
+        let now = time::get_time();
+        PEER_LAG.reset();
+        PEER_FAILURES.reset();
+        for (url, health) in all_peer_health(&watched) {
+            // Peers we've never synced with don't get a lag, rather than a made-up one
+            if let Some(last_sync) = health.last_sync {
+                PEER_LAG.set(&[("peer", url.as_str())], (now - last_sync).num_milliseconds() as f64 / 1000.0);
             }
+            PEER_FAILURES.set(&[("peer", url.as_str())], health.failures as f64);
+        }
+    });
+    // Ready once we've synced with every peer we know of, apart from ones that look dead, so one
+    // down node doesn't keep the rest from being ready. After that, falling behind shows up in
+    // the metrics and /status/replication instead.
+    let caught_up = Arc::new(AtomicBool::new(false));
+    let checked = nodelist.clone();
+    health::add_probe("caught_up", move || {
+        if caught_up.load(Ordering::SeqCst) {
+            return Ok(());
         }
-        check_should_exit!(recv, host_url);
-        thread::sleep(sleep_time);
+        // Until then, we don't know who there is to catch up with
+        if !checked.joined.load(Ordering::SeqCst) {
+            return Err(String::from("Still finding the rest of the cluster"));
+        }
+        let waiting: Vec<String> = all_peer_health(&checked)
+            .into_iter()
+            .filter(|&(_, ref health)| health.last_sync.is_none() && health.failures < UNREACHABLE_FAILURES)
+            .map(|(url, _)| url)
+            .collect();
+        if waiting.is_empty() {
+            caught_up.store(true, Ordering::SeqCst);
+            Ok(())
+        } else {
+            Err(format!("Still catching up with {}", waiting.join(", ")))
+        }
+    });
+    nodelist
+}
+
+// Called once membership has tried all the seeds (and the members it knew of), so we know who
+// there is to catch up with
+pub fn joined(nodelist: &NodeList) {
+    nodelist.joined.store(true, Ordering::SeqCst);
+}
+
+// Starts pulling from (and pushing to) a node, unless we already are
+pub fn start_checking(nodelist: &NodeList, url: &String) {
+    let checks = Arc::new(Checks::new());
+    let health = Arc::new(Mutex::new(PeerHealth::new()));
+    let replicator = replication::Replicator::new(nodelist.pool.clone(), url.clone());
+    {
+        let mut nodes = nodelist.nodes.write().unwrap();
+        if nodes.contains_key(url) {
+            return;
+        }
+        info!("Starting to check {}", url);
+        nodes.insert(url.clone(),
+                     NodeInfo {
+                         checks: checks.clone(),
+                         health: health.clone(),
+                         replicator: replicator.clone(),
+                     });
     }
+    // Outside the lock, as spawning waits if the runtime's queue is full
+    replicator.start();
+    let nodeslist = nodelist.clone();
+    let host_url = url.clone();
+    runtime::spawn(move || check_host(host_url, nodeslist, checks, health));
 }
 
-pub fn initial_nodes(pool: PostgresPool, clock_state: SyncClock) -> NodeList {
-    let conn = pool.get().unwrap();
-    let stmt = conn.prepare("select url from nodes").expect("prepare failure");
-    let locked_nodes = Arc::new(RwLock::new(HashMap::new()));
-    let mut nodes = locked_nodes.write().unwrap();
-    for row in &stmt.query(&[]).expect("nodes select works") {
-        let url: String = row.get("url");
-        let (send, recv) = channel();
-        nodes.insert(url.clone(), NodeInfo { sender: Mutex::new(send) });
-        let nodeslist = NodeList {
-            nodes: locked_nodes.clone(),
-            pool: pool.clone(),
-            clock: clock_state.clone(),
-        };
-        thread::spawn(move || check_host(url.clone(), nodeslist, recv));
+pub fn stop_checking(nodelist: &NodeList, url: &String) -> bool {
+    let mut nodes = nodelist.nodes.write().unwrap();
+    match nodes.remove(url) {
+        Some(info) => {
+            info.checks.stop();
+            info.replicator.quit();
+            true
+        }
+        None => false,
     }
-    return NodeList {
-        nodes: locked_nodes.clone(),
-        pool: pool,
-        clock: clock_state,
+}
+
+// Whether the other end said it's done (or went away) before the deadline
+fn wait_until(recv: &Receiver<()>, deadline: Instant) -> bool {
+    let now = Instant::now();
+    let remaining = if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_millis(0)
     };
+    match recv.recv_timeout(remaining) {
+        Err(RecvTimeoutError::Timeout) => false,
+        _ => true,
+    }
 }
 
-fn get_nodes_list(req: &Request) -> Vec<String> {
-    let state_ref = req.extensions.get::<State<Nodes>>().unwrap().read().unwrap();
-    let nodes = state_ref.deref().nodes.read().unwrap();
-    let mut vec = Vec::with_capacity(nodes.len());
-    for key in nodes.keys() {
-        vec.push(key.clone());
+// Stops pulling from every node, and waits (until the deadline) for any check that's part-way
+// through to finish, and for replication to each of them to have one last go at sending its queue
+pub fn stop_all(nodelist: &NodeList, deadline: Instant) {
+    let stopping: Vec<(String, NodeInfo)> = nodelist.nodes.write().unwrap().drain().collect();
+    let mut waiting = Vec::new();
+    for (url, info) in stopping {
+        info.checks.stop();
+        let (done, flushed) = channel();
+        info.replicator.flush(done);
+        waiting.push((url, info, flushed));
     }
-    return vec;
-}
-
-fn insert_node(req: &mut Request, to_notify: &String) {
-    let (send, recv) = channel();
-    let nodelist = {
-        let mut nodelist = req.extensions
-            .get_mut::<State<Nodes>>()
-            .unwrap()
-            .write()
-            .unwrap();
-        let nodelist_dm = nodelist.deref_mut();
-        nodelist_dm.nodes.write().unwrap().insert(to_notify.clone(), NodeInfo { sender: Mutex::new(send) });
-        NodeList {
-            nodes: nodelist_dm.nodes.clone(),
-            pool: nodelist_dm.pool.clone(),
-            clock: nodelist_dm.clock.clone(),
+    for (url, info, flushed) in waiting {
+        if !info.checks.wait_until(deadline) {
+            warn!("Check of {} didn't finish in time", url);
         }
-    };
-
-    let url = to_notify.clone();
-    thread::spawn(move || check_host(url, nodelist, recv));
+        if !wait_until(&flushed, deadline) {
+            warn!("Replication to {} didn't flush in time", url);
+        }
+    }
 }
 
-pub fn notify_everyone(req: &Request, log_arc: Arc<Log>) {
-    let nodes = get_nodes_list(req);
-    for node in nodes {
-        let local_log = log_arc.clone();
-        thread::spawn(move || {
-            let client = hyper::client::Client::new();
-            let notify_url = format!("{}/log/other", node);
-            debug!("Notifying (node) {}", notify_url);
-            let res = client.post(&notify_url)
-                .body(&serde_json::ser::to_string(&local_log).unwrap())
-                .send();
-            match res {
-                Ok(val) => {
-                    if val.status != hyper::status::StatusCode::Ok {
-                        warn!("Failed to notify {:?}: {:?}", &node, val.status);
-                    }
-                }
-                Err(val) => {
-                    warn!("Failed to notify {:?}: {:?}", &node, val);
-                }
-            };
-        });
-    }
+pub fn peer_health(nodelist: &NodeList, url: &String) -> Option<PeerHealth> {
+    let nodes = nodelist.nodes.read().unwrap();
+    nodes.get(url).map(|info| info.health.lock().unwrap().clone())
 }
 
-enum InsertResult {
-    Inserted,
-    Existing,
-    Error(postgres::error::Error),
+pub fn all_peer_health(nodelist: &NodeList) -> HashMap<String, PeerHealth> {
+    let nodes = nodelist.nodes.read().unwrap();
+    nodes.iter().map(|(url, info)| (url.clone(), info.health.lock().unwrap().clone())).collect()
 }
 
-fn node_insert(conn: &PostgresConnection, url: &String) -> InsertResult {
-    return match conn.execute("INSERT INTO nodes (url) VALUES ($1)", &[&url]) {
-        Ok(_) => InsertResult::Inserted,
-        Err(err) => {
-            if let postgres::error::Error::Db(dberr) = err {
-                match dberr.code {
-                    SqlState::UniqueViolation => InsertResult::Existing,
-                    _ => InsertResult::Error(postgres::error::Error::Db(dberr)),
-                }
-            } else {
-                InsertResult::Error(err)
-            }
-        }
-    };
+pub fn get_nodelist(req: &Request) -> NodeList {
+    req.extensions.get::<State<Nodes>>().unwrap().read().unwrap().deref().clone()
 }
 
-fn node_add_core(conn: &PostgresConnection,
-                 url: &String,
-                 req: &mut Request)
-                 -> Result<(), postgres::error::Error> {
-    match node_insert(&conn, &url) {
-        InsertResult::Inserted => {
-            insert_node(req, &url);
-            Ok(())
-        }
-        InsertResult::Existing => Ok(()),
-        InsertResult::Error(err) => Err(err),
+// Hands the entries to each node's replication sender, which batches and retries them
+pub fn notify_nodes(nodelist: &NodeList, pending: Arc<Vec<replication::Pending>>) {
+    // Enqueueing can wait for room on the runtime, so not while holding the lock
+    let replicators: Vec<(String, replication::Replicator)> = nodelist.nodes
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(url, info)| (url.clone(), info.replicator.clone()))
+        .collect();
+    for (url, replicator) in replicators {
+        debug!("Queueing {} entries for {}", pending.len(), url);
+        replicator.enqueue(pending.clone());
     }
 }
 
 pub fn node_add(req: &mut Request) -> IronResult<Response> {
-    let conn = get_pg_connection!(&req);
-    let url = url_from_body(req).unwrap().unwrap();
-    debug!("Registering node {}", url);
-    match Url::parse(&url) {
-        Err(err) => Err(IronError::new(err, (status::BadRequest, "Bad URL"))),
-        Ok(_) => {
-            match node_add_core(&conn, &url, req) {
-                Ok(_) => Ok(Response::with((status::NoContent))),
-                Err(err) => Err(IronError::new(err, (status::BadRequest, "Some other error"))),
-            }
-        }
-    }
+    let core = api::get_core(req);
+    let url = try!(url_from_body(req));
+    try!(core.add_node(&url));
+    Ok(Response::with((status::NoContent)))
 }
 
 pub fn node_remove(req: &mut Request) -> IronResult<Response> {
-    let conn = get_pg_connection!(&req);
-    let notifier = url_from_body(req).unwrap().unwrap();
-    conn.execute("DELETE from nodes where url = $1", &[&notifier])
-        .expect("delete worked");
-    let mut nodelist = req.extensions
-        .get_mut::<State<Nodes>>()
-        .unwrap()
-        .write()
-        .unwrap();
-    let nodelist_dm = nodelist.deref_mut();
-    let mut nodes = nodelist_dm.nodes.write().unwrap();
-    {
-        // Limit the immutable borrow of nodes
-        let info = match nodes.get(&notifier) {
-            Some(val) => val,
-            None => {
-                return Err(IronError::new(StringError::from(format!("No such notifier {} registered",
-                                                                    &notifier)),
-                                          (status::NotFound)));
-            }
-        };
-        info.sender.lock().unwrap().deref().send(()).unwrap();
+    let core = api::get_core(req);
+    let notifier = try!(url_from_body(req));
+    if !try!(core.remove_node(&notifier)) {
+        return Err(IronError::new(StringError::from(format!("No such notifier {} registered", &notifier)),
+                                  (status::NotFound)));
     }
-    nodes.remove(&notifier);
     Ok(Response::with((status::NoContent)))
 }
 
-fn add_node_from_req(req: &mut Request,
-                     nodes: &Vec<String>,
-                     conn: &PostgresConnection)
-                     -> Result<(), StringError> {
-    let host = try!(resolve::resolver::resolve_addr(&req.remote_addr.ip()));
-    let port = {
-        let query = req.get_ref::<UrlEncodedQuery>();
-        let values = try!(query.map_err(|x| StringError::from(x.description())));
-        let ports = try!(values.get("query_port").ok_or(StringError::from("Can't find query_port")));
-        let port_str = try!(ports.first().ok_or(StringError::from("Zero query_port's somehow...")));
-        try!(port_str.parse::<u32>().map_err(|x| StringError::from(x.description())))
-    };
-    let query_url = format!("http://{}:{}", host, port);
-    if !nodes.contains(&query_url) {
-        info!("{} is missing from nodes", query_url);
-        return node_add_core(conn, &query_url, req).map_err(|x| StringError::from(x.description()));
+fn timespec_to_json(when: &Option<Timespec>) -> serde_json::Value {
+    match *when {
+        Some(ref when) => serde_json::to_value(&when.sec),
+        None => serde_json::Value::Null,
     }
-    return Ok(());
 }
 
-pub fn node_list(req: &mut Request) -> IronResult<Response> {
-    let conn = get_pg_connection!(&req);
-    let stmt = conn.prepare("select url from nodes").expect("prepare failure");
+fn verbose_node_list(req: &mut Request) -> Vec<serde_json::Value> {
+    let members = membership::get_membership(req);
+    let nodelist = get_nodelist(req);
+    let now = time::get_time();
     let mut nodes = Vec::new();
-    for row in &stmt.query(&[]).expect("last select works") {
-        let url: String = row.get("url");
-        nodes.push(url);
+    for member in members.others() {
+        let mut map = serde_json::Map::new();
+        map.insert(String::from("id"), serde_json::to_value(&member.id));
+        map.insert(String::from("url"), serde_json::to_value(&member.url));
+        map.insert(String::from("state"), serde_json::to_value(&member.state));
+        map.insert(String::from("incarnation"), serde_json::to_value(&member.incarnation));
+        match peer_health(&nodelist, &member.url) {
+            Some(health) => {
+                let retry_in = cmp::max((health.next_check - now).num_seconds(), 0);
+                map.insert(String::from("last_sync"), timespec_to_json(&health.last_sync));
+                map.insert(String::from("failures"), serde_json::to_value(&health.failures));
+                map.insert(String::from("last_error"), serde_json::to_value(&health.last_error));
+                map.insert(String::from("next_check_in"), serde_json::to_value(&retry_in));
+            }
+            None => {
+                map.insert(String::from("last_sync"), serde_json::Value::Null);
+                map.insert(String::from("failures"), serde_json::Value::Null);
+                map.insert(String::from("last_error"), serde_json::Value::Null);
+                map.insert(String::from("next_check_in"), serde_json::Value::Null);
+            }
+        }
+        nodes.push(serde_json::Value::Object(map));
     }
-    if let Err(err) = add_node_from_req(req, &nodes, &conn) {
-        warn!("Error from add_node_from_req: {}", err);
+    nodes
+}
+
+pub fn node_list(req: &mut Request) -> IronResult<Response> {
+    if logs::query_param(req, "verbose").map(|x| x == "true").unwrap_or(false) {
+        let nodes = verbose_node_list(req);
+        return Ok(Response::with((status::Ok, serde_json::ser::to_string(&nodes).unwrap())));
     }
+    let members = membership::get_membership(req);
+    let nodes = members.alive_urls();
     Ok(Response::with((status::Ok, serde_json::ser::to_string(&nodes).unwrap())))
 }
+
+#[cfg(test)]
+mod tests {
+    use potboiler_common::config;
+    use potboiler_common::string_error::StringError;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use super::{MAX_BACKOFF_SECS, PeerHealth};
+
+    #[test]
+    fn backs_off_with_each_failure() {
+        let interval = config::current().sync_interval_secs;
+        let mut health = PeerHealth::new();
+        assert_eq!(health.delay(), Duration::from_secs(interval));
+        health.failed(&StringError::from("down"));
+        assert_eq!(health.delay(), Duration::from_secs(interval * 2));
+        health.failed(&StringError::from("down"));
+        assert_eq!(health.delay(), Duration::from_secs(interval * 4));
+        assert_eq!(health.failures, 2);
+        assert_eq!(health.last_error, Some(String::from("down")));
+    }
+
+    #[test]
+    fn backoff_has_a_limit() {
+        let mut health = PeerHealth::new();
+        for _ in 0..40 {
+            health.failed(&StringError::from("down"));
+        }
+        assert_eq!(health.delay(), Duration::from_secs(MAX_BACKOFF_SECS));
+    }
+
+    #[test]
+    fn success_resets_the_backoff() {
+        let mut health = PeerHealth::new();
+        for _ in 0..5 {
+            health.failed(&StringError::from("down"));
+        }
+        health.succeeded(HashMap::new());
+        assert_eq!(health.failures, 0);
+        assert_eq!(health.last_error, None);
+        assert!(health.last_sync.is_some());
+        assert_eq!(health.delay(), Duration::from_secs(config::current().sync_interval_secs));
+    }
+}
@@ -1,109 +1,198 @@
+use api;
+use filters::Filter;
 use hyper;
+use hyper::header::{Authorization, Bearer};
 use iron::Request;
 use iron::prelude::{IronError, IronResult, Response};
 use iron::status;
-use iron::typemap::Key;
-use persistent;
-use persistent::State;
-use postgres;
-use postgres::error::SqlState;
-use potboiler_common::{db, url_from_body};
-use potboiler_common::types::Log;
-use r2d2;
-use r2d2_postgres;
-use serde_json;
-use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
+use logs;
+use potboiler_common::config;
+use potboiler_common::metrics::{BoundedLabel, Counter};
+use potboiler_common::url_from_body;
+use potboiler_common::string_error::StringError;
+use potboiler_common::trace::{self, Kind, TraceParent};
+use potboiler_common::types::{Log, Snapshot};
+use runtime;
+use serde_json::{self, Value};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
 use std::thread;
-use url::Url;
-pub type PostgresConnection = r2d2::PooledConnection<r2d2_postgres::PostgresConnectionManager>;
+use std::time::{Duration, Instant};
+use store::SharedStore;
 
-#[derive(Copy, Clone)]
-pub struct Notifications;
+const NOTIFICATIONS: Counter = Counter {
+    name: "potboiler_notifications_total",
+    help: "Notifications sent to subscribers, by subscriber and whether they were delivered",
+};
 
-impl Key for Notifications {
-    type Value = Vec<String>;
+// Anyone who can register can add subscribers, so only this many get a series of their own
+const MAX_SUBSCRIBER_LABELS: usize = 100;
+
+// Notifications still being sent
+static IN_FLIGHT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    // Shared, so connections to subscribers are kept alive and reused
+    static ref CLIENT: hyper::client::Client = {
+        let mut client = hyper::client::Client::new();
+        client.set_read_timeout(Some(config::current().timeout()));
+        client.set_write_timeout(Some(config::current().timeout()));
+        client
+    };
+    static ref SUBSCRIBER_LABELS: BoundedLabel = BoundedLabel::new(MAX_SUBSCRIBER_LABELS);
 }
 
-pub fn init_notifiers(conn: &PostgresConnection) -> Vec<String> {
-    let mut notifiers = Vec::new();
-    let stmt = conn.prepare("select url from notifications").expect("prepare failure");
-    for row in &stmt.query(&[]).expect("notifications select works") {
-        let url: String = row.get("url");
-        notifiers.push(url);
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    pub url: String,
+    pub stream: Option<String>,
+    pub filter: Option<Filter>,
+    // Sent as a bearer token with each notification, for subscribers that check credentials
+    pub token: Option<String>,
+}
+
+impl Notifier {
+    // entry is the log as JSON, which the filter looks at
+    fn wants(&self, log: &Log, entry: &Value) -> bool {
+        let stream_ok = match self.stream {
+            Some(ref stream) => log.stream.as_ref() == Some(stream),
+            None => true,
+        };
+        let filter_ok = match self.filter {
+            Some(ref filter) => filter.matches(entry),
+            None => true,
+        };
+        stream_ok && filter_ok
     }
-    return notifiers;
 }
 
-fn get_notifications_list(req: &Request) -> Vec<String> {
-    req.extensions.get::<State<Notifications>>().unwrap().read().unwrap().deref().clone()
+pub fn init_notifiers(store: &SharedStore) -> Result<Vec<Notifier>, StringError> {
+    let mut notifiers = Vec::new();
+    for record in try!(store.notifiers()) {
+        let filter = match record.filter {
+            Some(ref raw) => Some(try!(Filter::from_json(raw))),
+            None => None,
+        };
+        notifiers.push(Notifier {
+            url: record.url,
+            stream: record.stream,
+            filter: filter,
+            token: record.token,
+        });
+    }
+    return Ok(notifiers);
 }
 
-fn insert_notifier(req: &mut Request, to_notify: &String) {
-    req.extensions
-        .get_mut::<State<Notifications>>()
-        .unwrap()
-        .write()
-        .unwrap()
-        .deref_mut()
-        .push(to_notify.clone());
+// Each entry's turned into JSON once, for all the filters and subscribers
+pub fn notify_everyone(notifiers: &[Notifier], log: &Log) {
+    if notifiers.is_empty() {
+        return;
+    }
+    let entry = serde_json::to_value(log);
+    let body = serde_json::ser::to_string(&entry).unwrap();
+    for notifier in notifiers {
+        if notifier.wants(log, &entry) {
+            send_notification(notifier.clone(), body.clone(), log.trace.clone());
+        }
+    }
 }
 
-pub fn notify_everyone(req: &Request, log_arc: Arc<Log>) {
-    let notifications = get_notifications_list(req);
-    for notifier in notifications {
-        let local_log = log_arc.clone();
-        thread::spawn(move || {
-            let client = hyper::client::Client::new();
-            debug!("Notifying {:?}", notifier);
-            let res = client.post(&notifier)
-                .body(&serde_json::ser::to_string(&local_log).unwrap())
-                .send();
-            match res {
-                Ok(val) => {
-                    if val.status != hyper::status::StatusCode::NoContent {
-                        warn!("Failed to notify {:?}: {:?}", &notifier, val.status);
-                    }
-                }
-                Err(val) => {
-                    warn!("Failed to notify {:?}: {:?}", &notifier, val);
-                }
-            };
-        });
+pub fn notify_everyone_batch(notifiers: &[Notifier], logs: &[Log]) {
+    if notifiers.is_empty() {
+        return;
+    }
+    let entries: Vec<Value> = logs.iter().map(serde_json::to_value).collect();
+    for notifier in notifiers {
+        let wanted: Vec<(&Log, &Value)> = logs.iter()
+            .zip(entries.iter())
+            .filter(|&(log, entry)| notifier.wants(log, entry))
+            .collect();
+        if !wanted.is_empty() {
+            // A batch is almost always from the one request, so it goes in the first entry's trace
+            let trace = wanted.iter().filter_map(|&(log, _)| log.trace.clone()).next();
+            let body: Vec<&Value> = wanted.iter().map(|&(_, entry)| entry).collect();
+            send_notification(notifier.clone(), serde_json::ser::to_string(&body).unwrap(), trace);
+        }
     }
 }
 
-pub fn log_register(req: &mut Request) -> IronResult<Response> {
-    let conn = get_pg_connection!(&req);
-    let url = url_from_body(req).unwrap().unwrap();
-    debug!("Registering {:?}", url);
-    match Url::parse(&url) {
-        Err(err) => Err(IronError::new(err, (status::BadRequest, "Bad URL"))),
-        Ok(_) => {
-            match conn.execute("INSERT INTO notifications (url) VALUES ($1)", &[&url]) {
-                Ok(_) => {
-                    insert_notifier(req, &url);
-                    Ok(Response::with((status::NoContent)))
-                }
-                Err(err) => {
-                    if let postgres::error::Error::Db(dberr) = err {
-                        match dberr.code {
-                            SqlState::UniqueViolation => Ok(Response::with((status::NoContent))),
-                            _ => Err(IronError::new(dberr, (status::BadRequest, "Some other error"))),
-                        }
-                    } else {
-                        Err(IronError::new(err, (status::BadRequest, "Some other error")))
-                    }
+fn send_notification(notifier: Notifier, body: String, trace: Option<String>) {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    runtime::spawn_notification(move || {
+        debug!("Notifying {:?}", notifier.url);
+        let span = trace::for_entry("notify", Kind::Producer, &trace);
+        let mut builder = CLIENT.post(&notifier.url).body(&body);
+        if let Some(ref token) = notifier.token {
+            builder = builder.header(Authorization(Bearer { token: token.clone() }));
+        }
+        if let Some(ref span) = span {
+            builder = builder.header(TraceParent(span.context().traceparent()));
+        }
+        let delivered = match builder.send() {
+            Ok(val) => {
+                if val.status != hyper::status::StatusCode::NoContent {
+                    warn!("Failed to notify {:?}: {:?}", &notifier.url, val.status);
+                    false
+                } else {
+                    true
                 }
             }
+            Err(val) => {
+                warn!("Failed to notify {:?}: {:?}", &notifier.url, val);
+                false
+            }
+        };
+        let result = if delivered { "delivered" } else { "failed" };
+        NOTIFICATIONS.inc(&[("subscriber", SUBSCRIBER_LABELS.value(&notifier.url)), ("result", result)]);
+        if let Some(mut span) = span {
+            span.attribute("subscriber", &notifier.url);
+            if !delivered {
+                span.failed();
+            }
+            span.finish();
+        }
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+// For a snapshot this node bootstrapped from, sent to the subscribers for its stream as
+// {"snapshot": [snapshot]}
+pub fn notify_snapshot(notifiers: &[Notifier], snapshot: &Snapshot) {
+    for notifier in notifiers {
+        if notifier.stream.is_none() || notifier.stream == snapshot.stream {
+            let mut body = serde_json::Map::new();
+            body.insert(String::from("snapshot"), serde_json::to_value(snapshot));
+            send_notification(notifier.clone(), serde_json::to_string(&body).unwrap(), None);
         }
     }
 }
 
+// Waits (until the deadline) for the notifications that are still being sent
+pub fn wait_for_deliveries(deadline: Instant) {
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+    let left = IN_FLIGHT.load(Ordering::SeqCst);
+    if left > 0 {
+        warn!("Gave up waiting for {} notifications to be sent", left);
+    }
+}
+
+pub fn log_register(req: &mut Request) -> IronResult<Response> {
+    let core = api::get_core(req);
+    let json = try!(logs::json_from_body(req));
+    let url = try!(json.find("url")
+        .and_then(|url| url.as_str())
+        .ok_or(IronError::new(StringError::from("No url"), (status::BadRequest, "No url"))));
+    let stream = json.find("stream").and_then(|stream| stream.as_str()).map(String::from);
+    let raw_filter = json.find("filter").map(|filter| filter.clone());
+    let token = json.find("token").and_then(|token| token.as_str()).map(String::from);
+    try!(core.register(url, stream, raw_filter, token));
+    Ok(Response::with((status::NoContent)))
+}
+
 pub fn log_deregister(req: &mut Request) -> IronResult<Response> {
-    let conn = get_pg_connection!(&req);
-    conn.execute("DELETE from notifications where url = $1",
-                 &[&url_from_body(req).unwrap().unwrap()])
-        .expect("delete worked");
+    let core = api::get_core(req);
+    let url = try!(url_from_body(req));
+    try!(core.deregister(&url));
     Ok(Response::with((status::NoContent)))
 }
@@ -0,0 +1,232 @@
+use cluster;
+use forks;
+use hyper;
+use nodes;
+use postgres::GenericConnection;
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use serde_json;
+use std::collections::HashSet;
+use std::io::Read;
+use uuid::Uuid;
+
+// Entries that turn up before their predecessor are kept to one side in the orphans table,
+// rather than in the log, so that a chain is only ever visible up to its first gap.
+
+// Most predecessors we'll fetch for one delivery, so a bad sender can't keep us busy forever
+const MAX_FETCHES: usize = 1000;
+
+fn is_linkable(conn: &GenericConnection, log: &Log) -> Result<bool, StringError> {
+    match log.prev {
+        Some(prev) => Ok(!try!(conn.query("SELECT 1 from log WHERE id=$1", &[&prev])).is_empty()),
+        None => Ok(true),
+    }
+}
+
+// In the log, waiting in orphans, or quarantined
+fn have_log(conn: &GenericConnection, id: &Uuid) -> Result<bool, StringError> {
+    let existing = try!(conn.query("SELECT 1 from log WHERE id=$1 UNION SELECT 1 from orphans WHERE id=$1 \
+                                    UNION SELECT 1 from forks WHERE id=$1",
+                                   &[id]));
+    Ok(!existing.is_empty())
+}
+
+fn buffer(conn: &GenericConnection, log: &Log) -> Result<(), StringError> {
+    info!("Buffering {} until we have its predecessor {:?}", log.id, log.prev);
+    try!(conn.execute("INSERT INTO orphans (id, owner, prev, body) VALUES ($1, $2, $3, $4)",
+                      &[&log.id, &log.owner, &log.prev, &serde_json::to_value(log)]));
+    Ok(())
+}
+
+// Anything following on from a quarantined entry is part of the same fork, so it goes to the forks
+// table too, rather than waiting in orphans for a predecessor that'll never be linked in
+fn quarantine_descendants(conn: &GenericConnection, id: &Uuid, existing: &Uuid) -> Result<(), StringError> {
+    let mut current = *id;
+    loop {
+        let rows = try!(conn.query("SELECT body from orphans WHERE prev=$1", &[&current]));
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let body: serde_json::Value = rows.get(0).get("body");
+        let log: Log = try!(serde_json::from_value(body));
+        try!(conn.execute("DELETE from orphans WHERE id=$1", &[&log.id]));
+        try!(forks::quarantine(conn, &log, existing));
+        current = log.id;
+    }
+}
+
+// Moves any orphans that now follow on from the given entry into the log, and so on down
+// their chain. Returns them in chain order.
+pub fn promote(conn: &GenericConnection, id: &Uuid) -> Result<Vec<Log>, StringError> {
+    let mut promoted = Vec::new();
+    let mut current = *id;
+    loop {
+        let rows = try!(conn.query("SELECT body from orphans WHERE prev=$1", &[&current]));
+        if rows.is_empty() {
+            break;
+        }
+        let body: serde_json::Value = rows.get(0).get("body");
+        let log: Log = try!(serde_json::from_value(body));
+        try!(conn.execute("DELETE from orphans WHERE id=$1", &[&log.id]));
+        info!("Predecessor of {} has arrived, so adding it", log.id);
+        if !try!(nodes::insert_log(conn, &log)) {
+            if let Some(existing) = try!(forks::quarantined_against(conn, &log.id)) {
+                try!(quarantine_descendants(conn, &log.id, &existing));
+            }
+            break;
+        }
+        current = log.id;
+        promoted.push(log);
+    }
+    Ok(promoted)
+}
+
+// Adds the entry to the log if its chain is contiguous, or buffers it if not. Returns
+// everything that's now visible as a result, in chain order.
+pub fn add(conn: &GenericConnection, log: Log) -> Result<Vec<Log>, StringError> {
+    if try!(have_log(conn, &log.id)) {
+        info!("Told about new log item ({}) I already have", log.id);
+        return Ok(Vec::new());
+    }
+    if let Some(prev) = log.prev {
+        if let Some(existing) = try!(forks::quarantined_against(conn, &prev)) {
+            try!(forks::quarantine(conn, &log, &existing));
+            try!(quarantine_descendants(conn, &log.id, &existing));
+            return Ok(Vec::new());
+        }
+    }
+    if !try!(is_linkable(conn, &log)) {
+        try!(buffer(conn, &log));
+        return Ok(Vec::new());
+    }
+    if !try!(nodes::insert_log(conn, &log)) {
+        if let Some(existing) = try!(forks::quarantined_against(conn, &log.id)) {
+            try!(quarantine_descendants(conn, &log.id, &existing));
+        }
+        return Ok(Vec::new());
+    }
+    let id = log.id;
+    let mut added = vec![log];
+    added.extend(try!(promote(conn, &id)));
+    Ok(added)
+}
+
+// Quarantined entries aren't missing, as we've got them and they're never going in the log
+fn missing_predecessors(conn: &GenericConnection) -> Result<Vec<Uuid>, StringError> {
+    let rows = try!(conn.query("SELECT DISTINCT prev from orphans o WHERE NOT EXISTS (SELECT 1 from log \
+                                WHERE id = o.prev) and NOT EXISTS (SELECT 1 from orphans WHERE id = \
+                                o.prev) and NOT EXISTS (SELECT 1 from forks WHERE id = o.prev)",
+                               &[]));
+    Ok(rows.iter().map(|row| row.get("prev")).collect())
+}
+
+fn fetch_log(client: &hyper::client::Client, host_url: &String, id: &Uuid) -> Result<Log, StringError> {
+    let mut res = try!(cluster::get(client, &format!("{}/cluster/log/{}", host_url, id))
+        .send()
+        .map_err(|err| StringError::from(format!("Failed to get {} from {}: {:?}", id, host_url, err))));
+    if res.status != hyper::status::StatusCode::Ok {
+        return Err(StringError::from(format!("Bad status getting {} from {}: {:?}",
+                                             id,
+                                             host_url,
+                                             res.status)));
+    }
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body));
+    Ok(try!(serde_json::from_str(&body)))
+}
+
+// Asks the host that sent us the orphans for the entries they're waiting on, working
+// backwards until the gaps are filled (or the host can't help). Returns everything that
+// became visible, in the order it was added.
+pub fn fetch_missing(conn: &GenericConnection, host_url: &String) -> Result<Vec<Log>, StringError> {
+    let client = cluster::peer_client();
+    let mut added = Vec::new();
+    let mut failed = HashSet::new();
+    let mut fetches = 0;
+    // Orphans left waiting on a quarantined entry (from before they were quarantined along with it)
+    let stranded = try!(conn.query("SELECT DISTINCT o.prev, f.existing from orphans o JOIN forks f ON f.id = \
+                                    o.prev",
+                                   &[]));
+    for row in &stranded {
+        let prev: Uuid = row.get("prev");
+        let existing: Uuid = row.get("existing");
+        try!(quarantine_descendants(conn, &prev, &existing));
+    }
+    loop {
+        let missing: Vec<Uuid> =
+            try!(missing_predecessors(conn)).into_iter().filter(|id| !failed.contains(id)).collect();
+        if missing.is_empty() {
+            break;
+        }
+        for id in missing {
+            if fetches >= MAX_FETCHES {
+                warn!("Gave up filling gaps from {} after {} fetches", host_url, fetches);
+                return Ok(added);
+            }
+            fetches += 1;
+            debug!("Fetching missing predecessor {} from {}", id, host_url);
+            match fetch_log(&client, host_url, &id) {
+                Ok(mut log) => {
+                    log.next = None;
+                    added.extend(try!(add(conn, log)));
+                }
+                Err(err) => {
+                    // Probably an orphan from somewhere else, which pull replication will sort out
+                    warn!("Couldn't fetch missing predecessor: {}", err);
+                    failed.insert(id);
+                }
+            }
+        }
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use nodes;
+    use postgres::GenericConnection;
+    use super::{add, missing_predecessors};
+    use testing;
+    use uuid::Uuid;
+
+    fn in_table(conn: &GenericConnection, table: &str, id: &Uuid) -> bool {
+        !conn.query(&format!("SELECT 1 from {} WHERE id=$1", table), &[id]).unwrap().is_empty()
+    }
+
+    #[test]
+    fn forks_are_not_missing_and_take_their_descendants_with_them() {
+        let conn = match testing::connection() {
+            Some(conn) => conn,
+            None => return,
+        };
+        let trans = conn.transaction().unwrap();
+        let owner = Uuid::new_v4();
+        let first = testing::new_log(owner, None, None);
+        let second = testing::new_log(owner, None, Some(&first));
+        assert!(nodes::insert_log(&trans, &first).unwrap());
+        assert!(nodes::insert_log(&trans, &second).unwrap());
+
+        // A rival to second, whose descendants turn up before it does
+        let fork = testing::new_log(owner, None, Some(&first));
+        let after_fork = testing::new_log(owner, None, Some(&fork));
+        let later = testing::new_log(owner, None, Some(&after_fork));
+        assert!(add(&trans, later.clone()).unwrap().is_empty());
+        assert!(add(&trans, after_fork.clone()).unwrap().is_empty());
+        assert_eq!(missing_predecessors(&trans).unwrap(), vec![fork.id]);
+
+        assert!(add(&trans, fork.clone()).unwrap().is_empty());
+        for log in &[&fork, &after_fork, &later] {
+            assert!(in_table(&trans, "forks", &log.id));
+            assert!(!in_table(&trans, "orphans", &log.id));
+            assert!(!in_table(&trans, "log", &log.id));
+        }
+        assert!(missing_predecessors(&trans).unwrap().is_empty());
+
+        // More of the fork arriving later goes straight to quarantine, and sending it again is a no-op
+        let even_later = testing::new_log(owner, None, Some(&later));
+        assert!(add(&trans, even_later.clone()).unwrap().is_empty());
+        assert!(in_table(&trans, "forks", &even_later.id));
+        assert!(add(&trans, fork).unwrap().is_empty());
+        assert!(missing_predecessors(&trans).unwrap().is_empty());
+    }
+}
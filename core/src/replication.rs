@@ -0,0 +1,444 @@
+use cluster;
+use hyper;
+use membership;
+use postgres::GenericConnection;
+use potboiler_common::metrics::Counter;
+use potboiler_common::string_error::StringError;
+use potboiler_common::trace::{self, Kind, Span};
+use potboiler_common::types::Log;
+use r2d2;
+use r2d2_postgres;
+use runtime;
+use serde_json;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use store::LogStore;
+use store::pg::PostgresStore;
+use url::form_urlencoded;
+use uuid::Uuid;
+
+pub type PostgresPool = r2d2::Pool<r2d2_postgres::PostgresConnectionManager>;
+
+const MAX_BATCH: usize = 100;
+// How many unacknowledged entries from before we started are loaded at a time
+const MAX_BACKLOG: usize = 1000;
+const RETRY_BASE_SECS: u64 = 1;
+const RETRY_MAX_SECS: u64 = 60;
+
+const PUSHES: Counter = Counter {
+    name: "potboiler_replication_pushes_total",
+    help: "Batches of entries pushed to a peer, by peer and result",
+};
+
+const PUSHED: Counter = Counter {
+    name: "potboiler_replication_pushed_entries_total",
+    help: "Entries a peer has acknowledged, by peer",
+};
+
+// An entry waiting to be pushed to a peer, already serialised
+#[derive(Clone)]
+pub struct Pending {
+    id: Uuid,
+    owner: Uuid,
+    prev: Option<Uuid>,
+    stream: Option<String>,
+    trace: Option<String>,
+    json: serde_json::Value,
+}
+
+impl Pending {
+    pub fn from_log(log: &Log) -> Pending {
+        Pending {
+            id: log.id,
+            owner: log.owner,
+            prev: log.prev,
+            stream: log.stream.clone(),
+            trace: log.trace.clone(),
+            json: serde_json::to_value(log),
+        }
+    }
+}
+
+// An owner's chain in a stream
+type Chain = (Uuid, Option<String>);
+
+// Everything after the last acknowledged entry of each chain we've queued entries of for the
+// peer, in chain order, along with those chains. Ones it's never acked go from the start. Stops
+// at limit entries, in which case the last value is false and the rest is for another call, once
+// these have been acked.
+fn backlog(conn: &GenericConnection,
+           store: &LogStore,
+           peer: &String,
+           limit: usize)
+           -> Result<(Vec<Pending>, HashSet<Chain>, bool), StringError> {
+    let mut pending = Vec::new();
+    let mut chains = HashSet::new();
+    for row in &try!(conn.query("SELECT owner, stream, acked from replication_acks WHERE peer=$1", &[peer])) {
+        let owner: Uuid = row.get("owner");
+        let stream: Option<String> = row.get("stream");
+        let acked: Option<Uuid> = row.get("acked");
+        chains.insert((owner, stream.clone()));
+        if pending.len() >= limit {
+            return Ok((pending, chains, false));
+        }
+        let after = match acked {
+            Some(acked) => acked,
+            None => {
+                let first = match try!(store.first(&owner, &stream)) {
+                    Some(first) => first,
+                    None => continue,
+                };
+                let log = try!(try!(store.get(&first))
+                    .ok_or(StringError::from(format!("Missing log {} in chain", first))));
+                pending.push(Pending::from_log(&log));
+                first
+            }
+        };
+        let logs = try!(store.range(&after, limit - pending.len()));
+        pending.extend(logs.iter().map(Pending::from_log));
+        if pending.len() >= limit {
+            return Ok((pending, chains, false));
+        }
+    }
+    Ok((pending, chains, true))
+}
+
+// Records where the peer's got up to in each chain, as just before the first of its entries
+// we're queueing (nothing, if that's the chain's start), unless it's already got a position for
+// it. Done before they're queued, so that if we stop before sending them, the backlog picks them
+// up again on restart.
+fn seed_acks(conn: &GenericConnection,
+             peer: &String,
+             chains: &HashMap<Chain, Option<Uuid>>)
+             -> Result<(), StringError> {
+    for (&(ref owner, ref stream), acked) in chains {
+        try!(conn.execute("INSERT INTO replication_acks (peer, owner, stream, acked) SELECT $1, $2, $3, $4 \
+                           WHERE NOT EXISTS (SELECT 1 from replication_acks WHERE peer=$1 and owner=$2 and \
+                           stream is not distinct from $3)",
+                          &[peer, owner, stream, acked]));
+    }
+    Ok(())
+}
+
+fn record_acks(conn: &GenericConnection, peer: &String, batch: &[Pending]) -> Result<(), StringError> {
+    let mut latest: HashMap<(Uuid, Option<String>), Uuid> = HashMap::new();
+    for pending in batch {
+        latest.insert((pending.owner, pending.stream.clone()), pending.id);
+    }
+    let trans = try!(conn.transaction());
+    for ((owner, stream), acked) in latest {
+        try!(trans.execute("DELETE from replication_acks WHERE peer=$1 and owner=$2 and stream is not \
+                            distinct from $3",
+                           &[peer, &owner, &stream]));
+        try!(trans.execute("INSERT INTO replication_acks (peer, owner, stream, acked) VALUES ($1, $2, $3, \
+                            $4)",
+                           &[peer, &owner, &stream, &acked]));
+    }
+    try!(trans.commit());
+    Ok(())
+}
+
+fn post_batch(client: &hyper::client::Client, peer: &String, batch: &[Pending]) -> Result<(), StringError> {
+    let body = serde_json::Value::Array(batch.iter().map(|pending| pending.json.clone()).collect());
+    let me: String = form_urlencoded::byte_serialize(membership::advertise_url().as_bytes()).collect();
+    let notify_url = format!("{}/cluster/log/other?from={}", peer, me);
+    debug!("Sending {} entries to {}", batch.len(), notify_url);
+    let res = try!(cluster::post(client, &notify_url)
+        .body(&try!(serde_json::to_string(&body)))
+        .send()
+        .map_err(|err| StringError::from(format!("Failed to send to {}: {:?}", peer, err))));
+    if res.status != hyper::status::StatusCode::Ok {
+        return Err(StringError::from(format!("Bad status from {}: {:?}", peer, res.status)));
+    }
+    Ok(())
+}
+
+// The request isn't traced itself, as the batch may have entries from any number of traces, but
+// each of those gets a span for it
+fn send_batch(client: &hyper::client::Client, peer: &String, batch: &[Pending]) -> Result<(), StringError> {
+    let mut traces = HashSet::new();
+    let spans: Vec<Span> = batch.iter()
+        .filter(|pending| traces.insert(pending.trace.clone()))
+        .filter_map(|pending| trace::for_entry("replicate to peer", Kind::Producer, &pending.trace))
+        .collect();
+    let result = post_batch(client, peer, batch);
+    for mut span in spans {
+        span.attribute("peer", peer);
+        span.attribute("entries", batch.len());
+        if result.is_err() {
+            span.failed();
+        }
+        span.finish();
+    }
+    result
+}
+
+fn retry_delay(failures: u32) -> Duration {
+    let factor = 1u64 << cmp::min(failures, 16);
+    Duration::from_secs(cmp::min(RETRY_BASE_SECS * factor, RETRY_MAX_SECS))
+}
+
+// What's waiting to go to one peer. Pushes are jobs on the runtime, one at a time per peer so
+// entries go in order, with the next one scheduled as each finishes (straight away, or after a
+// backoff if it failed).
+struct Outbox {
+    queue: VecDeque<Pending>,
+    // There may be unacknowledged entries (from before we started, or queued since) still to load
+    // from the log. They're loaded a page at a time, whenever the queue runs out, and until
+    // they've all been, new entries are left for that rather than queued.
+    backlog: bool,
+    // Chains the peer has a row in replication_acks for
+    seeded: HashSet<Chain>,
+    failures: u32,
+    // The token of the push that's due, if any. A push that finds a different one has been
+    // superseded (e.g. a retry brought forward by a flush), and does nothing.
+    scheduled: Option<u64>,
+    next_token: u64,
+    // A push is sending right now
+    running: bool,
+    stopped: bool,
+    // Told once the queue's been sent (or a push fails) while flushing
+    flush: Option<Sender<()>>,
+}
+
+#[derive(Clone)]
+pub struct Replicator {
+    peer: String,
+    pool: PostgresPool,
+    outbox: Arc<Mutex<Outbox>>,
+}
+
+fn flushed(peer: &String, queue: &VecDeque<Pending>, flush: Option<Sender<()>>) {
+    if !queue.is_empty() {
+        warn!("Stopping replication to {} with {} entries unsent, which will go on restart",
+              peer,
+              queue.len());
+    }
+    if let Some(done) = flush {
+        // Whoever asked may have given up waiting
+        let _ = done.send(());
+    }
+}
+
+impl Replicator {
+    pub fn new(pool: PostgresPool, peer: String) -> Replicator {
+        Replicator {
+            peer: peer,
+            pool: pool,
+            outbox: Arc::new(Mutex::new(Outbox {
+                queue: VecDeque::new(),
+                backlog: true,
+                seeded: HashSet::new(),
+                failures: 0,
+                scheduled: None,
+                next_token: 0,
+                running: false,
+                stopped: false,
+                flush: None,
+            })),
+        }
+    }
+
+    // Loads the backlog and starts pushing
+    pub fn start(&self) {
+        self.push_soon();
+    }
+
+    // Schedules a push now, superseding any that's waiting to retry
+    fn push_soon(&self) {
+        let token = {
+            let mut outbox = self.outbox.lock().unwrap();
+            outbox.next_token += 1;
+            outbox.scheduled = Some(outbox.next_token);
+            outbox.next_token
+        };
+        let replicator = self.clone();
+        runtime::spawn(move || replicator.push(token));
+    }
+
+    pub fn enqueue(&self, entries: Arc<Vec<Pending>>) {
+        let mut unseeded: HashMap<Chain, Option<Uuid>> = HashMap::new();
+        {
+            let outbox = self.outbox.lock().unwrap();
+            for pending in entries.iter() {
+                let chain = (pending.owner, pending.stream.clone());
+                if !outbox.seeded.contains(&chain) {
+                    unseeded.entry(chain).or_insert(pending.prev);
+                }
+            }
+        }
+        // Chains we couldn't record, so the backlog won't find their entries
+        let mut unrecorded: HashSet<Chain> = HashSet::new();
+        if !unseeded.is_empty() {
+            let seeded = self.pool
+                .get()
+                .map_err(|err| StringError::from(format!("{:?}", err)))
+                .and_then(|conn| seed_acks(&*conn, &self.peer, &unseeded));
+            match seeded {
+                Ok(_) => {
+                    let mut outbox = self.outbox.lock().unwrap();
+                    outbox.seeded.extend(unseeded.into_iter().map(|(chain, _)| chain));
+                }
+                Err(err) => {
+                    // They'll still be sent, unless we stop first
+                    warn!("Couldn't record new chains for {}: {}", self.peer, err);
+                    unrecorded.extend(unseeded.into_iter().map(|(chain, _)| chain));
+                }
+            }
+        }
+        let idle = {
+            let mut outbox = self.outbox.lock().unwrap();
+            if outbox.stopped {
+                return;
+            }
+            let backlog = outbox.backlog;
+            outbox.queue.extend(entries.iter()
+                .filter(|pending| {
+                    !backlog || unrecorded.contains(&(pending.owner, pending.stream.clone()))
+                })
+                .map(|x| x.clone()));
+            outbox.scheduled.is_none()
+        };
+        if idle {
+            self.push_soon();
+        }
+    }
+
+    // Stops pushing, leaving anything unsent for the next start to pick up
+    pub fn quit(&self) {
+        self.outbox.lock().unwrap().stopped = true;
+        info!("Stopped replication to {}", self.peer);
+    }
+
+    // Has one more go at sending everything queued, then says so on done and stops
+    pub fn flush(&self, done: Sender<()>) {
+        let push_now = {
+            let mut outbox = self.outbox.lock().unwrap();
+            if outbox.stopped || (outbox.scheduled.is_none() && outbox.queue.is_empty() && !outbox.backlog) {
+                let _ = done.send(());
+                return;
+            }
+            outbox.flush = Some(done);
+            // A running push carries on to the rest of the queue by itself
+            !outbox.running
+        };
+        if push_now {
+            self.push_soon();
+        }
+    }
+
+    fn push(&self, token: u64) {
+        let loaded: Result<Vec<Pending>, Duration> = {
+            let mut outbox = self.outbox.lock().unwrap();
+            if outbox.scheduled != Some(token) || outbox.stopped {
+                return;
+            }
+            let mut retry = None;
+            if outbox.queue.is_empty() && outbox.backlog {
+                match self.load_backlog() {
+                    Ok((backlog, chains, done)) => {
+                        outbox.seeded.extend(chains);
+                        outbox.backlog = !done;
+                        outbox.queue.extend(backlog);
+                    }
+                    Err(err) => {
+                        outbox.failures += 1;
+                        if let Some(done) = outbox.flush.take() {
+                            warn!("Couldn't load unacknowledged entries for {} while flushing: {}",
+                                  self.peer,
+                                  err);
+                            outbox.stopped = true;
+                            outbox.scheduled = None;
+                            flushed(&self.peer, &outbox.queue, Some(done));
+                            return;
+                        }
+                        let delay = retry_delay(outbox.failures);
+                        warn!("Couldn't load unacknowledged entries for {} (retrying in {}s): {}",
+                              self.peer,
+                              delay.as_secs(),
+                              err);
+                        retry = Some(delay);
+                    }
+                }
+            }
+            match retry {
+                Some(delay) => Err(delay),
+                None => {
+                    if outbox.queue.is_empty() {
+                        outbox.scheduled = None;
+                        if let Some(done) = outbox.flush.take() {
+                            outbox.stopped = true;
+                            flushed(&self.peer, &outbox.queue, Some(done));
+                        }
+                        return;
+                    }
+                    outbox.running = true;
+                    Ok(outbox.queue.iter().take(MAX_BATCH).map(|x| x.clone()).collect())
+                }
+            }
+        };
+        let batch = match loaded {
+            Ok(batch) => batch,
+            Err(delay) => {
+                let replicator = self.clone();
+                runtime::spawn_after(delay, move || replicator.push(token));
+                return;
+            }
+        };
+        let result = send_batch(cluster::peer_client(), &self.peer, &batch).and_then(|_| {
+            let conn = try!(self.pool.get().map_err(|err| StringError::from(format!("{:?}", err))));
+            record_acks(&*conn, &self.peer, &batch)
+        });
+        let delay = {
+            let mut outbox = self.outbox.lock().unwrap();
+            outbox.running = false;
+            match result {
+                Ok(_) => {
+                    PUSHES.inc(&[("peer", self.peer.as_str()), ("result", "ok")]);
+                    PUSHED.add(&[("peer", self.peer.as_str())], batch.len() as f64);
+                    outbox.failures = 0;
+                    outbox.queue.drain(..batch.len());
+                    Duration::from_secs(0)
+                }
+                Err(err) => {
+                    PUSHES.inc(&[("peer", self.peer.as_str()), ("result", "error")]);
+                    outbox.failures += 1;
+                    if let Some(done) = outbox.flush.take() {
+                        warn!("Failed to replicate {} entries to {} while flushing: {}",
+                              batch.len(),
+                              self.peer,
+                              err);
+                        outbox.stopped = true;
+                        outbox.scheduled = None;
+                        flushed(&self.peer, &outbox.queue, Some(done));
+                        return;
+                    }
+                    let delay = retry_delay(outbox.failures);
+                    warn!("Failed to replicate {} entries to {} ({} failures in a row, retrying in {}s): {}",
+                          batch.len(),
+                          self.peer,
+                          outbox.failures,
+                          delay.as_secs(),
+                          err);
+                    delay
+                }
+            }
+        };
+        let replicator = self.clone();
+        runtime::spawn_after(delay, move || replicator.push(token));
+    }
+
+    fn load_backlog(&self) -> Result<(Vec<Pending>, HashSet<Chain>, bool), StringError> {
+        let conn = try!(self.pool
+            .get()
+            .map_err(|err| StringError::from(format!("Couldn't get a connection: {:?}", err))));
+        backlog(&*conn, &PostgresStore::new(self.pool.clone()), &self.peer, MAX_BACKLOG)
+    }
+}
+
+pub fn pending_from_logs(logs: &[Log]) -> Arc<Vec<Pending>> {
+    Arc::new(logs.iter().map(Pending::from_log).collect())
+}
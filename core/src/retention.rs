@@ -0,0 +1,271 @@
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use iron::prelude::{IronResult, Request, Response};
+use iron::status;
+use logs;
+use persistent;
+use postgres::GenericConnection;
+use potboiler_common::config;
+use potboiler_common::db;
+use potboiler_common::{iron_storage_error, iron_str_error};
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use r2d2;
+use r2d2_postgres;
+use runtime::Stop;
+use serde_json;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use store::pg;
+use time;
+use uuid::Uuid;
+
+pub type PostgresPool = r2d2::Pool<r2d2_postgres::PostgresConnectionManager>;
+
+// A retention policy applies to every chain matching its stream and owner, with a missing
+// stream or owner matching anything. The most specific matching policy wins.
+#[derive(Debug)]
+pub struct Policy {
+    pub stream: Option<String>,
+    pub owner: Option<Uuid>,
+    pub days: i32,
+}
+
+impl Policy {
+    fn matches(&self, owner: &Uuid, stream: &Option<String>) -> bool {
+        let owner_ok = self.owner.as_ref().map(|x| x == owner).unwrap_or(true);
+        let stream_ok = self.stream.as_ref().map(|x| Some(x) == stream.as_ref()).unwrap_or(true);
+        owner_ok && stream_ok
+    }
+
+    fn specificity(&self) -> u8 {
+        (if self.stream.is_some() { 2 } else { 0 }) + (if self.owner.is_some() { 1 } else { 0 })
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut map = serde_json::Map::new();
+        map.insert(String::from("stream"), serde_json::to_value(&self.stream));
+        map.insert(String::from("owner"),
+                   serde_json::to_value(&self.owner.map(|owner| owner.to_string())));
+        map.insert(String::from("days"), serde_json::to_value(&self.days));
+        serde_json::Value::Object(map)
+    }
+}
+
+const ARCHIVE_INTERVAL_SECS: u64 = 3600;
+
+fn archive_path() -> PathBuf {
+    PathBuf::from(config::current().archive_path)
+}
+
+fn get_policies(conn: &GenericConnection) -> Result<Vec<Policy>, StringError> {
+    let mut policies = Vec::new();
+    for row in &try!(conn.query("SELECT stream, owner, days from retention", &[])) {
+        policies.push(Policy {
+            stream: row.get("stream"),
+            owner: row.get("owner"),
+            days: row.get("days"),
+        });
+    }
+    Ok(policies)
+}
+
+fn policy_from_body(req: &mut Request, need_days: bool) -> IronResult<Policy> {
+    let json = try!(logs::json_from_body(req));
+    let owner = match json.find("owner").and_then(|owner| owner.as_str()) {
+        Some(owner) => Some(try!(Uuid::parse_str(owner).map_err(iron_str_error))),
+        None => None,
+    };
+    let days = match json.find("days").and_then(|days| days.as_i64()) {
+        Some(days) if days > 0 => days as i32,
+        _ if !need_days => 0,
+        _ => {
+            return Err(iron_str_error(StringError::from("days must be a positive integer")));
+        }
+    };
+    Ok(Policy {
+        stream: json.find("stream").and_then(|stream| stream.as_str()).map(String::from),
+        owner: owner,
+        days: days,
+    })
+}
+
+pub fn list_policies(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let policies = try!(get_policies(&*conn).map_err(iron_storage_error));
+    let json: Vec<serde_json::Value> = policies.iter().map(|policy| policy.to_json()).collect();
+    Ok(Response::with((status::Ok, serde_json::to_string(&json).unwrap())))
+}
+
+pub fn set_policy(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let policy = try!(policy_from_body(req, true));
+    let trans = try!(conn.transaction().map_err(iron_str_error));
+    try!(trans.execute("DELETE from retention WHERE stream is not distinct from $1 and owner is not distinct \
+                        from $2",
+                 &[&policy.stream, &policy.owner])
+        .map_err(iron_str_error));
+    try!(trans.execute("INSERT INTO retention (stream, owner, days) VALUES ($1, $2, $3)",
+                 &[&policy.stream, &policy.owner, &policy.days])
+        .map_err(iron_str_error));
+    try!(trans.commit().map_err(iron_str_error));
+    Ok(Response::with(status::NoContent))
+}
+
+pub fn remove_policy(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let policy = try!(policy_from_body(req, false));
+    let removed = try!(conn.execute("DELETE from retention WHERE stream is not distinct from $1 and owner is \
+                                     not distinct from $2",
+                                    &[&policy.stream, &policy.owner])
+        .map_err(iron_str_error));
+    if removed == 0 {
+        Ok(Response::with((status::NotFound, "No such policy")))
+    } else {
+        Ok(Response::with(status::NoContent))
+    }
+}
+
+pub fn list_archives(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let rows = try!(conn.query("SELECT owner, stream, boundary, boundary_prev, file, entries from archives",
+               &[])
+        .map_err(iron_str_error));
+    let mut archives = Vec::new();
+    for row in &rows {
+        let mut map = serde_json::Map::new();
+        let owner: Uuid = row.get("owner");
+        let boundary: Uuid = row.get("boundary");
+        let boundary_prev: Uuid = row.get("boundary_prev");
+        let stream: Option<String> = row.get("stream");
+        let file: String = row.get("file");
+        let entries: i64 = row.get("entries");
+        map.insert(String::from("owner"), serde_json::to_value(&owner.to_string()));
+        map.insert(String::from("stream"), serde_json::to_value(&stream));
+        map.insert(String::from("boundary"), serde_json::to_value(&boundary.to_string()));
+        map.insert(String::from("boundary_prev"),
+                   serde_json::to_value(&boundary_prev.to_string()));
+        map.insert(String::from("file"), serde_json::to_value(&file));
+        map.insert(String::from("entries"), serde_json::to_value(&entries));
+        archives.push(serde_json::Value::Object(map));
+    }
+    Ok(Response::with((status::Ok, serde_json::to_string(&archives).unwrap())))
+}
+
+fn write_archive(path: &PathBuf, logs: &Vec<Log>) -> Result<(), StringError> {
+    let file = try!(File::create(path));
+    let mut encoder = GzEncoder::new(file, Compression::Default);
+    for log in logs {
+        try!(encoder.write_all(try!(serde_json::to_string(log)).as_bytes()));
+        try!(encoder.write_all(b"\n"));
+    }
+    try!(encoder.finish());
+    Ok(())
+}
+
+// Archives everything older than the cutoff at the start of one chain. The head is always
+// kept, so appends still have something to link to. The first kept entry becomes the new
+// start of the chain, and its old prev is recorded in the archives table.
+fn archive_chain(conn: &GenericConnection,
+                 owner: &Uuid,
+                 stream: &Option<String>,
+                 days: i32)
+                 -> Result<usize, StringError> {
+    let cutoff = time::get_time() - time::Duration::days(days as i64);
+    let first = try!(conn.query("SELECT id from log WHERE prev is null and owner=$1 and stream is not \
+                                 distinct from $2 limit 1",
+                                &[owner, stream]));
+    if first.is_empty() {
+        return Ok(0);
+    }
+    let mut current: Uuid = first.get(0).get("id");
+    let mut expired = Vec::new();
+    loop {
+        let log = try!(try!(pg::read_log(conn, &current))
+            .ok_or(StringError::from(format!("Missing log {} in chain", current))));
+        let next = match log.next {
+            Some(val) => val,
+            None => break,
+        };
+        if log.when.time.as_timespec() >= cutoff {
+            break;
+        }
+        expired.push(log);
+        current = next;
+    }
+    if expired.is_empty() {
+        return Ok(0);
+    }
+    // '@' can't be in a stream's name, so the default stream can't clash with a real one
+    let stream_name = stream.clone().unwrap_or("@default".to_string());
+    let mut path = archive_path();
+    try!(fs::create_dir_all(&path));
+    path.push(format!("{}-{}-{}.ndjson.gz", owner, stream_name, expired[0].id));
+    try!(write_archive(&path, &expired));
+
+    let trans = try!(conn.transaction());
+    for log in &expired {
+        try!(trans.execute("DELETE from log WHERE id=$1", &[&log.id]));
+    }
+    let boundary_prev = expired[expired.len() - 1].id;
+    try!(trans.execute("UPDATE log set prev = null WHERE id=$1", &[&current]));
+    try!(trans.execute("INSERT INTO archives (owner, stream, boundary, boundary_prev, file, entries) VALUES \
+                        ($1, $2, $3, $4, $5, $6)",
+                       &[owner,
+                         stream,
+                         &current,
+                         &boundary_prev,
+                         &path.to_string_lossy().into_owned(),
+                         &(expired.len() as i64)]));
+    try!(trans.commit());
+    info!("Archived {} entries from {} ({:?}) to {:?}",
+          expired.len(),
+          owner,
+          stream,
+          path);
+    Ok(expired.len())
+}
+
+fn archive_once(conn: &GenericConnection) -> Result<(), StringError> {
+    let policies = try!(get_policies(conn));
+    if policies.is_empty() {
+        return Ok(());
+    }
+    let chains = try!(conn.query("SELECT owner, stream from log WHERE prev is null", &[]));
+    for row in &chains {
+        let owner: Uuid = row.get("owner");
+        let stream: Option<String> = row.get("stream");
+        let policy = policies.iter()
+            .filter(|policy| policy.matches(&owner, &stream))
+            .max_by_key(|policy| policy.specificity());
+        if let Some(policy) = policy {
+            // One bad chain (e.g. a file we can't write) shouldn't hold up the rest, and it'll get
+            // another go next time
+            if let Err(err) = archive_chain(conn, &owner, &stream, policy.days) {
+                warn!("Couldn't archive {} ({:?}), skipping it: {}", owner, stream, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn archive_loop(pool: PostgresPool, stop: Stop) {
+    let sleep_time = Duration::from_secs(ARCHIVE_INTERVAL_SECS);
+    loop {
+        match pool.get() {
+            Ok(conn) => {
+                if let Err(err) = archive_once(&*conn) {
+                    warn!("Got an error while archiving: {}", err);
+                }
+            }
+            Err(err) => {
+                warn!("Couldn't get a connection for archiving: {:?}", err);
+            }
+        }
+        if !stop.wait(sleep_time) {
+            return;
+        }
+    }
+}
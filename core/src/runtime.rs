@@ -0,0 +1,247 @@
+use potboiler_common::config;
+use potboiler_common::metrics::{self, Gauge};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError, channel, sync_channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Core's background work runs as jobs on fixed pools of threads, rather than on a thread each, so
+// the number of threads doesn't grow with the number of subscribers, peers or entries. This isn't
+// an async runtime: each job still blocks its thread while it waits on the network, as iron and
+// hyper 0.9 don't do async, and moving to something that does is a bigger change than this.
+//
+// Replication pushes, peer checks and joins go to the workers (worker_threads, with at most
+// worker_queue_size jobs waiting), and notifications to their own pool (notify_threads and
+// notify_queue_size), so slow subscribers can't hold up replication. Past a pool's queue size,
+// spawning blocks until there's room, which slows appends down rather than letting the backlog
+// grow without limit. Jobs that run later (retries and the next check of a peer) wait on a single
+// timer thread, and then go to the workers.
+
+trait Run: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Run for F {
+    fn run(self: Box<F>) {
+        (*self)()
+    }
+}
+
+type Job = Box<Run>;
+
+struct Delayed {
+    when: Instant,
+    job: Job,
+}
+
+// BinaryHeap gives the greatest first, so the earliest has to be the greatest
+impl Ord for Delayed {
+    fn cmp(&self, other: &Delayed) -> Ordering {
+        other.when.cmp(&self.when)
+    }
+}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Delayed) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Delayed) -> bool {
+        self.when == other.when
+    }
+}
+
+impl Eq for Delayed {}
+
+struct Pool {
+    name: &'static str,
+    jobs: Mutex<SyncSender<Job>>,
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+}
+
+const QUEUED: Gauge = Gauge {
+    name: "potboiler_core_jobs_queued",
+    help: "Background jobs waiting for a thread, by pool",
+};
+
+const BUSY: Gauge = Gauge {
+    name: "potboiler_core_workers_busy",
+    help: "Threads running a background job, by pool",
+};
+
+lazy_static! {
+    static ref WORKERS: Arc<Pool> = {
+        let config = config::current();
+        start_pool("worker", config.worker_threads, config.worker_queue_size)
+    };
+    static ref NOTIFIERS: Arc<Pool> = {
+        let config = config::current();
+        start_pool("notify", config.notify_threads, config.notify_queue_size)
+    };
+    static ref TIMER: Mutex<Sender<Delayed>> = start_timer();
+}
+
+// The pool the current thread belongs to, if any
+thread_local!(static CURRENT_POOL: Cell<Option<&'static str>> = Cell::new(None));
+
+fn start_pool(name: &'static str, threads: usize, queue_size: usize) -> Arc<Pool> {
+    let (jobs, recv) = sync_channel(queue_size);
+    let pool = Arc::new(Pool {
+        name: name,
+        jobs: Mutex::new(jobs),
+        queued: AtomicUsize::new(0),
+        busy: AtomicUsize::new(0),
+    });
+    let recv = Arc::new(Mutex::new(recv));
+    for number in 0..threads {
+        let recv = recv.clone();
+        let pool = pool.clone();
+        thread::Builder::new()
+            .name(format!("{}-{}", name, number))
+            .spawn(move || work(&pool, recv))
+            .expect("pool thread starts");
+    }
+    let collected = pool.clone();
+    metrics::add_collector(move || {
+        QUEUED.set(&[("pool", collected.name)],
+                   collected.queued.load(atomic::Ordering::SeqCst) as f64);
+        BUSY.set(&[("pool", collected.name)],
+                 collected.busy.load(atomic::Ordering::SeqCst) as f64);
+    });
+    info!("Started {} {} threads, with room for {} queued jobs", threads, name, queue_size);
+    pool
+}
+
+fn start_timer() -> Mutex<Sender<Delayed>> {
+    let (timer, delayed) = channel();
+    thread::Builder::new()
+        .name(String::from("timer"))
+        .spawn(move || timer_loop(delayed))
+        .expect("timer thread starts");
+    Mutex::new(timer)
+}
+
+fn work(pool: &Pool, recv: Arc<Mutex<Receiver<Job>>>) {
+    CURRENT_POOL.with(|current| current.set(Some(pool.name)));
+    loop {
+        let job = match recv.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        pool.queued.fetch_sub(1, atomic::Ordering::SeqCst);
+        run(pool, job);
+    }
+}
+
+fn run(pool: &Pool, job: Job) {
+    pool.busy.fetch_add(1, atomic::Ordering::SeqCst);
+    // So one bad job doesn't take a thread down with it
+    if panic::catch_unwind(AssertUnwindSafe(move || job.run())).is_err() {
+        warn!("A background job panicked");
+    }
+    pool.busy.fetch_sub(1, atomic::Ordering::SeqCst);
+}
+
+fn timer_loop(delayed: Receiver<Delayed>) {
+    let mut waiting = BinaryHeap::new();
+    loop {
+        let now = Instant::now();
+        while waiting.peek().map(|next: &Delayed| next.when <= now).unwrap_or(false) {
+            let due = waiting.pop().unwrap();
+            queue(&WORKERS, due.job);
+        }
+        let received = match waiting.peek() {
+            Some(next) => delayed.recv_timeout(next.when - now),
+            None => delayed.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(job) => waiting.push(job),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+// Queues the job for the pool's next free thread, waiting for room if the queue's full. From one
+// of the pool's own jobs, a full queue means running it straight away instead, as waiting could
+// leave every thread in the pool stuck waiting for each other.
+fn queue(pool: &Pool, job: Job) {
+    let jobs = pool.jobs.lock().unwrap().clone();
+    pool.queued.fetch_add(1, atomic::Ordering::SeqCst);
+    if CURRENT_POOL.with(|current| current.get()) == Some(pool.name) {
+        match jobs.try_send(job) {
+            Ok(_) => {}
+            Err(TrySendError::Full(job)) => {
+                pool.queued.fetch_sub(1, atomic::Ordering::SeqCst);
+                run(pool, job);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!("The {} pool has gone, so dropping a job", pool.name)
+            }
+        }
+    } else if jobs.send(job).is_err() {
+        warn!("The {} pool has gone, so dropping a job", pool.name);
+    }
+}
+
+pub fn spawn<F: FnOnce() + Send + 'static>(job: F) {
+    queue(&WORKERS, Box::new(job));
+}
+
+// For sending notifications, which can take as long as a subscriber does to answer
+pub fn spawn_notification<F: FnOnce() + Send + 'static>(job: F) {
+    queue(&NOTIFIERS, Box::new(job));
+}
+
+// For threads that run for as long as the core does (gossip and archiving), so they can be told to
+// finish. Waiting on one wakes up as soon as it's stopped, rather than after the whole round.
+#[derive(Clone)]
+pub struct Stop {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Stop {
+    pub fn new() -> Stop {
+        Stop { stopped: Arc::new((Mutex::new(false), Condvar::new())) }
+    }
+
+    pub fn stop(&self) {
+        let &(ref stopped, ref changed) = &*self.stopped;
+        *stopped.lock().unwrap() = true;
+        changed.notify_all();
+    }
+
+    // Sleeps for the given time, returning false if we've been (or get) stopped
+    pub fn wait(&self, duration: Duration) -> bool {
+        let &(ref stopped, ref changed) = &*self.stopped;
+        let deadline = Instant::now() + duration;
+        let mut is_stopped = stopped.lock().unwrap();
+        while !*is_stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            is_stopped = changed.wait_timeout(is_stopped, deadline - now).unwrap().0;
+        }
+        false
+    }
+}
+
+// Queues the job for the workers once the delay's up
+pub fn spawn_after<F: FnOnce() + Send + 'static>(delay: Duration, job: F) {
+    let delayed = Delayed {
+        when: Instant::now() + delay,
+        job: Box::new(job),
+    };
+    if TIMER.lock().unwrap().send(delayed).is_err() {
+        warn!("Timer has gone, so dropping a job");
+    }
+}
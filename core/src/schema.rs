@@ -70,6 +70,247 @@ impl PostgresMigration for Nodes {
     }
 }
 
+struct Streams;
+migration!(Streams, 201611061512, "add named streams to log and notifications");
+
+impl PostgresMigration for Streams {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("ALTER TABLE log ADD COLUMN stream VARCHAR(1024)", &[])
+            .unwrap();
+        transaction.execute("ALTER TABLE notifications ADD COLUMN stream VARCHAR(1024)", &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("ALTER TABLE notifications DROP COLUMN stream", &[]).unwrap();
+        let _ = transaction.execute("ALTER TABLE log DROP COLUMN stream", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct NotificationFilters;
+migration!(NotificationFilters, 201611131040, "add content filters to notifications");
+
+impl PostgresMigration for NotificationFilters {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("ALTER TABLE notifications ADD COLUMN filter JSONB", &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("ALTER TABLE notifications DROP COLUMN filter", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct Snapshots;
+migration!(Snapshots, 201611201930, "add snapshots and their acknowledgements");
+
+impl PostgresMigration for Snapshots {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("CREATE TABLE snapshots (id UUID PRIMARY KEY, stream VARCHAR(1024), heads JSONB \
+                             NOT NULL, data JSONB NOT NULL, hlc_tstamp BYTEA NOT NULL);",
+                     &[])
+            .unwrap();
+        transaction.execute("CREATE TABLE snapshot_acks (snapshot UUID REFERENCES snapshots(id) ON DELETE \
+                             CASCADE, url VARCHAR(2083), PRIMARY KEY(snapshot, url));",
+                     &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("DROP TABLE snapshot_acks", &[]).unwrap();
+        let _ = transaction.execute("DROP TABLE snapshots", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct Retention;
+migration!(Retention, 201611271415, "add retention policies and archive boundaries");
+
+impl PostgresMigration for Retention {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("CREATE TABLE retention (stream VARCHAR(1024), owner UUID, days INTEGER NOT \
+                             NULL);",
+                     &[])
+            .unwrap();
+        transaction.execute("CREATE TABLE archives (owner UUID NOT NULL, stream VARCHAR(1024), boundary UUID \
+                             NOT NULL, boundary_prev UUID NOT NULL, file VARCHAR(4096) NOT NULL, entries \
+                             BIGINT NOT NULL);",
+                     &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("DROP TABLE archives", &[]).unwrap();
+        let _ = transaction.execute("DROP TABLE retention", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct Members;
+migration!(Members, 201612041105, "add gossip membership");
+
+impl PostgresMigration for Members {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("CREATE TABLE members (id UUID PRIMARY KEY, url VARCHAR(2083) NOT NULL, \
+                             incarnation BIGINT NOT NULL, state VARCHAR(8) NOT NULL);",
+                     &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("DROP TABLE members", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct MemberTombstones;
+migration!(MemberTombstones, 201612111620, "add member removal timestamps");
+
+impl PostgresMigration for MemberTombstones {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("ALTER TABLE members ADD COLUMN removed_at BYTEA", &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("ALTER TABLE members DROP COLUMN removed_at", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct ReplicationAcks;
+migration!(ReplicationAcks, 201612181050, "add acknowledged replication positions");
+
+impl PostgresMigration for ReplicationAcks {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("CREATE TABLE replication_acks (peer VARCHAR(2083) NOT NULL, owner UUID NOT \
+                             NULL, stream VARCHAR(1024), acked UUID NOT NULL);",
+                     &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("DROP TABLE replication_acks", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct Orphans;
+migration!(Orphans, 201612251340, "add orphans for entries that arrive before their predecessor");
+
+impl PostgresMigration for Orphans {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("CREATE TABLE orphans (id UUID PRIMARY KEY, owner UUID NOT NULL, prev UUID NOT \
+                             NULL, body JSONB NOT NULL);",
+                     &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("DROP TABLE orphans", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct Forks;
+migration!(Forks, 201701021115, "add quarantined forks");
+
+impl PostgresMigration for Forks {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("CREATE TABLE forks (id UUID PRIMARY KEY, owner UUID NOT NULL, stream \
+                             VARCHAR(1024), prev UUID, existing UUID NOT NULL, body JSONB NOT NULL, detected \
+                             BIGINT NOT NULL);",
+                     &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("DROP TABLE forks", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct NotificationTokens;
+migration!(NotificationTokens, 201701091430, "add bearer tokens for notifications");
+
+impl PostgresMigration for NotificationTokens {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("ALTER TABLE notifications ADD COLUMN token VARCHAR(1024)", &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("ALTER TABLE notifications DROP COLUMN token", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct Traces;
+migration!(Traces, 201701161015, "add trace context to log");
+
+impl PostgresMigration for Traces {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("ALTER TABLE log ADD COLUMN trace VARCHAR(256)", &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("ALTER TABLE log DROP COLUMN trace", &[]).unwrap();
+        return Ok(());
+    }
+}
+
+struct UnackedChains;
+migration!(UnackedChains, 201701231000, "allow replication acks for chains not acked yet");
+
+impl PostgresMigration for UnackedChains {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        transaction.execute("ALTER TABLE replication_acks ALTER COLUMN acked DROP NOT NULL", &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("DELETE from replication_acks WHERE acked is null", &[]).unwrap();
+        let _ = transaction.execute("ALTER TABLE replication_acks ALTER COLUMN acked SET NOT NULL", &[])
+            .unwrap();
+        return Ok(());
+    }
+}
+
+struct UniqueChainStarts;
+migration!(UniqueChainStarts, 201701301000, "only one start to each chain");
+
+impl PostgresMigration for UniqueChainStarts {
+    fn up(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        // Stream names can't be empty, so that stands in for the default stream, which would
+        // otherwise never clash, as nulls are all distinct
+        transaction.execute("CREATE UNIQUE INDEX log_chain_start ON log (owner, COALESCE(stream, '')) \
+                             WHERE prev IS NULL",
+                     &[])
+            .unwrap();
+        return Ok(());
+    }
+
+    fn down(&self, transaction: &postgres::Transaction) -> Result<(), postgres::error::Error> {
+        let _ = transaction.execute("DROP INDEX log_chain_start", &[]).unwrap();
+        return Ok(());
+    }
+}
+
 fn migrate(connection: &postgres::Connection) -> Migrator<PostgresAdapter> {
     let adapter = PostgresAdapter::new(connection);
     let _ = adapter.setup_schema().unwrap();
@@ -79,6 +320,19 @@ fn migrate(connection: &postgres::Connection) -> Migrator<PostgresAdapter> {
     migrator.register(Box::new(Notifications));
     migrator.register(Box::new(Timestamp));
     migrator.register(Box::new(Nodes));
+    migrator.register(Box::new(Streams));
+    migrator.register(Box::new(NotificationFilters));
+    migrator.register(Box::new(Snapshots));
+    migrator.register(Box::new(Retention));
+    migrator.register(Box::new(Members));
+    migrator.register(Box::new(MemberTombstones));
+    migrator.register(Box::new(ReplicationAcks));
+    migrator.register(Box::new(Orphans));
+    migrator.register(Box::new(Forks));
+    migrator.register(Box::new(NotificationTokens));
+    migrator.register(Box::new(Traces));
+    migrator.register(Box::new(UnackedChains));
+    migrator.register(Box::new(UniqueChainStarts));
     return migrator;
 }
 
@@ -0,0 +1,32 @@
+use hybrid_clocks::{Timestamp, WallT};
+use uuid::Uuid;
+
+enum_str!(MemberState {
+    Alive("alive"),
+    Suspect("suspect"),
+    Dead("dead"),
+    Left("left"),
+    Removed("removed"),
+});
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Member {
+    pub id: Uuid,
+    pub url: String,
+    pub incarnation: u64,
+    pub state: MemberState,
+    // Set when the member was removed from the cluster, which is permanent
+    #[serde(default)]
+    pub removed_at: Option<Timestamp<WallT>>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Gossip {
+    pub from: Member,
+    pub members: Vec<Member>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingRequest {
+    pub target: String
+}
@@ -0,0 +1,252 @@
+use api;
+use cluster;
+use hybrid_clocks;
+use hyper;
+use iron::prelude::{IronError, IronResult, Request, Response};
+use iron::status;
+use logs;
+use persistent;
+use postgres;
+use postgres::GenericConnection;
+use postgres::error::SqlState;
+use postgres::rows::Row;
+use potboiler_common::{clock, db, get_raw_timestamp, get_req_key, iron_storage_error, iron_str_error,
+                       url_from_body};
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Snapshot;
+use serde_json::{self, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read};
+use std::iter::FromIterator;
+use uuid::Uuid;
+
+fn snapshot_from_row(row: &Row) -> Result<Snapshot, StringError> {
+    let hlc_tstamp: Vec<u8> = row.get("hlc_tstamp");
+    let when = try!(hybrid_clocks::Timestamp::read_bytes(Cursor::new(hlc_tstamp))
+        .map_err(|err| StringError::from(format!("Bad snapshot timestamp: {:?}", err))));
+    let heads: Value = row.get("heads");
+    Ok(Snapshot {
+        id: row.get("id"),
+        stream: row.get("stream"),
+        heads: try!(serde_json::from_value(heads)),
+        when: when,
+        data: row.get("data"),
+    })
+}
+
+fn stream_snapshots(conn: &GenericConnection, stream: &Option<String>) -> Result<Vec<Snapshot>, StringError> {
+    let results = try!(conn.query("SELECT id, stream, heads, data, hlc_tstamp from snapshots WHERE stream is \
+                                   not distinct from $1",
+                                  &[stream]));
+    let mut snapshots = Vec::new();
+    for row in &results {
+        snapshots.push(try!(snapshot_from_row(&row)));
+    }
+    // Newest first
+    snapshots.sort_by(|a, b| b.when.cmp(&a.when));
+    Ok(snapshots)
+}
+
+// Returns false if we already had it
+pub fn insert_snapshot(conn: &GenericConnection,
+                       snapshot: &Snapshot)
+                       -> Result<bool, postgres::error::Error> {
+    let raw_timestamp = get_raw_timestamp(&snapshot.when);
+    match conn.execute("INSERT INTO snapshots (id, stream, heads, data, hlc_tstamp) VALUES ($1, $2, $3, $4, \
+                        $5)",
+                       &[&snapshot.id,
+                         &snapshot.stream,
+                         &serde_json::to_value(&snapshot.heads),
+                         &snapshot.data,
+                         &raw_timestamp]) {
+        Ok(_) => Ok(true),
+        Err(postgres::error::Error::Db(ref dberr)) if dberr.code == SqlState::UniqueViolation => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn current_heads(conn: &GenericConnection,
+                 stream: &Option<String>)
+                 -> Result<HashMap<String, Uuid>, postgres::error::Error> {
+    let results = try!(conn.query("SELECT id, owner from log WHERE next is null and stream is not distinct \
+                                   from $1",
+                                  &[stream]));
+    let mut heads = HashMap::new();
+    for row in &results {
+        let owner: Uuid = row.get("owner");
+        heads.insert(owner.to_string(), row.get("id"));
+    }
+    Ok(heads)
+}
+
+pub fn create_snapshot(mut req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let json = try!(logs::json_from_body(req));
+    let data = try!(json.find("data")
+        .ok_or(IronError::new(StringError::from("No data"), (status::BadRequest, "No data"))))
+        .clone();
+    let stream = json.find("stream").and_then(|stream| stream.as_str()).map(String::from);
+    let heads: HashMap<String, Uuid> = match json.find("heads") {
+        Some(heads) => try!(serde_json::from_value(heads.clone()).map_err(iron_str_error)),
+        None => try!(current_heads(&*conn, &stream).map_err(iron_storage_error)),
+    };
+    for (owner, id) in &heads {
+        let owner_id = try!(Uuid::parse_str(owner).map_err(iron_str_error));
+        let existing = try!(conn.query("SELECT 1 from log WHERE id=$1 and owner=$2 and stream is not \
+                                        distinct from $3",
+                                       &[id, &owner_id, &stream])
+            .map_err(iron_str_error));
+        if existing.is_empty() {
+            return Ok(Response::with((status::BadRequest,
+                                      format!("No log {} for owner {} in this stream", id, owner))));
+        }
+    }
+    let snapshot = Snapshot {
+        id: Uuid::new_v4(),
+        stream: stream,
+        heads: heads,
+        when: clock::get_timestamp(&mut req),
+        data: data,
+    };
+    try!(insert_snapshot(&*conn, &snapshot).map_err(iron_storage_error));
+    Ok(Response::with((status::Created, snapshot.id.hyphenated().to_string())))
+}
+
+pub fn list_snapshots(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let rows = try!(conn.query("SELECT id, stream, heads, data, hlc_tstamp from snapshots", &[])
+        .map_err(iron_str_error));
+    let mut snapshots = Vec::new();
+    for row in &rows {
+        snapshots.push(try!(snapshot_from_row(&row).map_err(iron_storage_error)));
+    }
+    Ok(Response::with((status::Ok, serde_json::to_string(&snapshots).unwrap())))
+}
+
+fn get_snapshot_id(req: &Request) -> IronResult<Uuid> {
+    let raw_id = try!(get_req_key(req, "snapshot_id")
+        .ok_or(iron_str_error(StringError::from("No snapshot id"))));
+    Uuid::parse_str(&raw_id).map_err(iron_str_error)
+}
+
+pub fn get_snapshot(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let id = try!(get_snapshot_id(req));
+    let results = try!(conn.query("SELECT id, stream, heads, data, hlc_tstamp from snapshots WHERE id=$1",
+               &[&id])
+        .map_err(iron_str_error));
+    if results.is_empty() {
+        Ok(Response::with((status::NotFound, format!("No snapshot {}", id))))
+    } else {
+        let snapshot = try!(snapshot_from_row(&results.get(0)).map_err(iron_storage_error));
+        Ok(Response::with((status::Ok, serde_json::to_string(&snapshot).unwrap())))
+    }
+}
+
+pub fn ack_snapshot(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let id = try!(get_snapshot_id(req));
+    let url = try!(url_from_body(req));
+    match conn.execute("INSERT INTO snapshot_acks (snapshot, url) VALUES ($1, $2)",
+                       &[&id, &url]) {
+        Ok(_) => Ok(Response::with(status::NoContent)),
+        Err(postgres::error::Error::Db(ref dberr)) if dberr.code == SqlState::UniqueViolation => {
+            Ok(Response::with(status::NoContent))
+        }
+        Err(postgres::error::Error::Db(ref dberr)) if dberr.code == SqlState::ForeignKeyViolation => {
+            Ok(Response::with((status::NotFound, format!("No snapshot {}", id))))
+        }
+        Err(err) => Err(iron_str_error(err)),
+    }
+}
+
+// Drops everything before each head of the snapshot. The head itself is kept, so the chain
+// still has a first entry and new appends still have something to link to.
+fn prune_to_snapshot(conn: &GenericConnection, snapshot: &Snapshot) -> Result<u64, postgres::error::Error> {
+    let mut removed = 0;
+    for id in snapshot.heads.values() {
+        removed += try!(conn.execute("WITH RECURSIVE older(id) AS (SELECT prev from log WHERE id=$1 and \
+                                      prev is not null UNION SELECT log.prev from log JOIN older ON \
+                                      log.id = older.id WHERE log.prev is not null) DELETE FROM log \
+                                      WHERE id IN (SELECT id from older)",
+                                     &[id]));
+        try!(conn.execute("UPDATE log set prev = null WHERE id=$1", &[id]));
+    }
+    Ok(removed)
+}
+
+pub fn compact(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let notifiers = api::get_core(req).notifiers();
+    let trans = try!(conn.transaction().map_err(iron_str_error));
+    let stream_rows = try!(trans.query("SELECT DISTINCT stream from snapshots", &[]).map_err(iron_str_error));
+    let mut removed = 0;
+    for row in &stream_rows {
+        let stream: Option<String> = row.get("stream");
+        let subscribers: HashSet<String> = HashSet::from_iter(notifiers.iter()
+            .filter(|notifier| notifier.stream.is_none() || notifier.stream == stream)
+            .map(|notifier| notifier.url.clone()));
+        if subscribers.is_empty() {
+            // No-one's said what they can do without, so we can't know what's safe to drop
+            info!("Not compacting {:?}, as it has no subscribers", stream);
+            continue;
+        }
+        let snapshots = try!(stream_snapshots(&trans, &stream).map_err(iron_storage_error));
+        let mut acks = Vec::new();
+        for snapshot in &snapshots {
+            let ack_rows = try!(trans.query("SELECT url from snapshot_acks WHERE snapshot=$1",
+                       &[&snapshot.id])
+                .map_err(iron_str_error));
+            let acked: HashSet<String> = HashSet::from_iter(ack_rows.iter()
+                .map(|x| x.get::<&str, String>("url")));
+            acks.push(acked);
+        }
+        // The newest snapshot each subscriber has acked (snapshots are newest first). Everyone has
+        // caught up to the oldest of those, so that's as far as we can go.
+        let newest_acked: Vec<Option<usize>> = subscribers.iter()
+            .map(|url| acks.iter().position(|acked| acked.contains(url)))
+            .collect();
+        if newest_acked.iter().any(|index| index.is_none()) {
+            continue;
+        }
+        if let Some(oldest) = newest_acked.into_iter().filter_map(|index| index).max() {
+            let snapshot = &snapshots[oldest];
+            info!("Compacting {:?} to snapshot {}", stream, snapshot.id);
+            removed += try!(prune_to_snapshot(&trans, snapshot).map_err(iron_str_error));
+        }
+    }
+    try!(trans.commit().map_err(iron_str_error));
+    let mut result = serde_json::Map::new();
+    result.insert(String::from("removed"), serde_json::to_value(&removed));
+    Ok(Response::with((status::Ok, serde_json::to_string(&result).unwrap())))
+}
+
+// Used when bootstrapping from a peer: grab their newest snapshot for the stream, so local
+// applications can start from it rather than needing the pruned history. Only returns it if it's
+// new to us.
+pub fn fetch_latest(client: &hyper::client::Client,
+                    host_url: &String,
+                    conn: &GenericConnection,
+                    stream: &Option<String>)
+                    -> Result<Option<Snapshot>, StringError> {
+    let snapshots_url = format!("{}/cluster/snapshots", host_url);
+    let mut res = try!(cluster::get(client, &snapshots_url)
+        .send()
+        .map_err(|err| StringError::from(format!("Failed to get snapshots: {:?}", err))));
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body));
+    let mut remote: Vec<Snapshot> = try!(serde_json::from_str(&body));
+    remote.retain(|snapshot| &snapshot.stream == stream);
+    remote.sort_by(|a, b| b.when.cmp(&a.when));
+    match remote.into_iter().next() {
+        Some(snapshot) => {
+            if try!(insert_snapshot(conn, &snapshot)) {
+                info!("Bootstrapping {:?} from snapshot {} on {}", stream, snapshot.id, host_url);
+                Ok(Some(snapshot))
+            } else {
+                Ok(None)
+            }
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,119 @@
+use hybrid_clocks::{Timestamp, WallT};
+use iron::prelude::{IronResult, Request, Response};
+use iron::status;
+use nodes;
+use postgres::GenericConnection;
+use potboiler_common::iron_storage_error;
+use potboiler_common::string_error::StringError;
+use serde_json;
+use std::collections::HashMap;
+use std::io::Cursor;
+use uuid::Uuid;
+
+struct LocalHead {
+    id: Uuid,
+    when: Timestamp<WallT>,
+}
+
+fn local_heads(conn: &GenericConnection) -> Result<HashMap<(Option<String>, Uuid), LocalHead>, StringError> {
+    let mut heads = HashMap::new();
+    for row in &try!(conn.query("SELECT id, owner, stream, hlc_tstamp from log WHERE next is null", &[])) {
+        let hlc_tstamp: Vec<u8> = row.get("hlc_tstamp");
+        let when = try!(Timestamp::read_bytes(Cursor::new(hlc_tstamp))
+            .map_err(|err| StringError::from(format!("Bad log timestamp: {:?}", err))));
+        let stream: Option<String> = row.get("stream");
+        let owner: Uuid = row.get("owner");
+        heads.insert((stream, owner),
+                     LocalHead {
+                         id: row.get("id"),
+                         when: when,
+                     });
+    }
+    Ok(heads)
+}
+
+// How far behind us the peer is in a chain, as how many of our entries come after its head, and
+// whether it's ahead of us instead (we don't have its head, at least until the next sync). If it
+// is, we can't tell by how much, as we don't have what comes after our head to count.
+fn chain_lag(conn: &GenericConnection,
+             peer_head: &Uuid,
+             local_head: Option<&LocalHead>)
+             -> Result<(i64, bool), StringError> {
+    let local_head = match local_head {
+        Some(val) => val,
+        None => return Ok((0, true)),
+    };
+    if &local_head.id == peer_head {
+        return Ok((0, false));
+    }
+    let existing = try!(conn.query("SELECT 1 from log WHERE id=$1", &[peer_head]));
+    if existing.is_empty() {
+        return Ok((0, true));
+    }
+    // The chain ends at our head, so this counts up to and including it
+    let count = try!(conn.query("WITH RECURSIVE later(id) AS (SELECT next from log WHERE id=$1 and next is \
+                                 not null UNION SELECT log.next from log JOIN later ON log.id = later.id \
+                                 WHERE log.next is not null) SELECT count(*) from later",
+                                &[peer_head]));
+    Ok((count.get(0).get(0), false))
+}
+
+fn replication_status(conn: &GenericConnection,
+                      peers: &HashMap<String, nodes::PeerHealth>)
+                      -> Result<Vec<serde_json::Value>, StringError> {
+    let locals = try!(local_heads(conn));
+    let mut chains: Vec<(Option<String>, Uuid)> = locals.keys().map(|x| x.clone()).collect();
+    for health in peers.values() {
+        for (stream, heads) in &health.advertised {
+            for owner in heads.keys() {
+                let chain = (stream.clone(), owner.clone());
+                if !chains.contains(&chain) {
+                    chains.push(chain);
+                }
+            }
+        }
+    }
+    chains.sort_by(|a, b| (&a.0, a.1.as_bytes()).cmp(&(&b.0, b.1.as_bytes())));
+    let mut result = Vec::new();
+    for (stream, owner) in chains {
+        let mut map = serde_json::Map::new();
+        map.insert(String::from("owner"), serde_json::to_value(&owner));
+        map.insert(String::from("stream"), serde_json::to_value(&stream));
+        let local = locals.get(&(stream.clone(), owner));
+        match local {
+            Some(local) => {
+                map.insert(String::from("head"), serde_json::to_value(&local.id));
+                map.insert(String::from("when"), serde_json::to_value(&local.when));
+            }
+            None => {
+                map.insert(String::from("head"), serde_json::Value::Null);
+                map.insert(String::from("when"), serde_json::Value::Null);
+            }
+        }
+        let mut peer_map = serde_json::Map::new();
+        for (url, health) in peers {
+            let head = match health.advertised.get(&stream).and_then(|heads| heads.get(&owner)) {
+                Some(val) => val,
+                None => continue,
+            };
+            let mut entry = serde_json::Map::new();
+            entry.insert(String::from("head"), serde_json::to_value(head));
+            let (lag, behind) = try!(chain_lag(conn, head, local));
+            entry.insert(String::from("lag"), serde_json::to_value(&lag));
+            entry.insert(String::from("behind"), serde_json::to_value(&behind));
+            entry.insert(String::from("last_sync"),
+                         serde_json::to_value(&health.last_sync.map(|when| when.sec)));
+            peer_map.insert(url.clone(), serde_json::Value::Object(entry));
+        }
+        map.insert(String::from("peers"), serde_json::Value::Object(peer_map));
+        result.push(serde_json::Value::Object(map));
+    }
+    Ok(result)
+}
+
+pub fn replication(req: &mut Request) -> IronResult<Response> {
+    let conn = get_pg_connection!(&req);
+    let peers = nodes::all_peer_health(&nodes::get_nodelist(req));
+    let result = try!(replication_status(&*conn, &peers).map_err(iron_storage_error));
+    Ok(Response::with((status::Ok, serde_json::to_string(&result).unwrap())))
+}
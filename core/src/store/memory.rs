@@ -0,0 +1,236 @@
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use store::{LogStore, NotifierRecord};
+use uuid::Uuid;
+
+// Keeps everything in memory, so it's gone on restart. Meant for tests and trying things out.
+
+type Chain = (Uuid, Option<String>);
+
+// What linking an entry did, so a batch that fails part way through can be taken back out
+enum Change {
+    Added(Uuid),
+    // Set the next of an entry that didn't have one
+    Linked(Uuid),
+    Head(Chain, Option<Uuid>),
+    First(Chain, Option<Uuid>),
+}
+
+struct Contents {
+    logs: HashMap<Uuid, Log>,
+    // The ends of each chain, so finding them doesn't mean going through every entry
+    heads: HashMap<Chain, Uuid>,
+    firsts: HashMap<Chain, Uuid>,
+    nodes: Vec<String>,
+    notifiers: Vec<NotifierRecord>,
+}
+
+fn set_end(ends: &mut HashMap<Chain, Uuid>, chain: Chain, id: Option<Uuid>) {
+    match id {
+        Some(id) => ends.insert(chain, id),
+        None => ends.remove(&chain),
+    };
+}
+
+fn chain_ends(ends: &HashMap<Chain, Uuid>, stream: &Option<String>) -> HashMap<Uuid, Uuid> {
+    ends.iter()
+        .filter(|&(&(_, ref chain_stream), _)| chain_stream == stream)
+        .map(|(&(owner, _), id)| (owner, *id))
+        .collect()
+}
+
+impl Contents {
+    // Same rules as the Postgres link_log
+    fn link_log(&mut self, log: &Log, changes: &mut Vec<Change>) -> Result<Option<Uuid>, StringError> {
+        if self.logs.contains_key(&log.id) {
+            return Err(StringError::from(format!("Already have {}", log.id)));
+        }
+        let chain = (log.owner, log.stream.clone());
+        let existing = match log.prev {
+            Some(prev) => {
+                match self.logs.get_mut(&prev) {
+                    Some(prev_log) => {
+                        if prev_log.owner == log.owner && prev_log.next.is_none() {
+                            prev_log.next = Some(log.id);
+                            changes.push(Change::Linked(prev));
+                        }
+                        prev_log.next
+                    }
+                    None => None,
+                }
+            }
+            None => self.firsts.get(&chain).cloned(),
+        };
+        if let Some(existing) = existing {
+            if existing != log.id {
+                return Ok(Some(existing));
+            }
+        }
+        let mut stored = log.clone();
+        stored.next = None;
+        self.logs.insert(log.id, stored);
+        changes.push(Change::Added(log.id));
+        let head = self.heads.get(&chain).cloned();
+        if head.is_none() || head == log.prev {
+            self.heads.insert(chain.clone(), log.id);
+            changes.push(Change::Head(chain.clone(), head));
+        }
+        if log.prev.is_none() {
+            let first = self.firsts.insert(chain.clone(), log.id);
+            changes.push(Change::First(chain, first));
+        }
+        Ok(None)
+    }
+
+    fn undo(&mut self, changes: Vec<Change>) {
+        for change in changes.into_iter().rev() {
+            match change {
+                Change::Added(id) => {
+                    self.logs.remove(&id);
+                }
+                Change::Linked(id) => {
+                    if let Some(log) = self.logs.get_mut(&id) {
+                        log.next = None;
+                    }
+                }
+                Change::Head(chain, id) => set_end(&mut self.heads, chain, id),
+                Change::First(chain, id) => set_end(&mut self.firsts, chain, id),
+            }
+        }
+    }
+}
+
+pub struct MemoryStore {
+    contents: Mutex<Contents>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            contents: Mutex::new(Contents {
+                logs: HashMap::new(),
+                heads: HashMap::new(),
+                firsts: HashMap::new(),
+                nodes: Vec::new(),
+                notifiers: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl LogStore for MemoryStore {
+    fn insert(&self, log: &Log) -> Result<Option<Uuid>, StringError> {
+        // Nothing's changed unless it goes in, so there's nothing to undo
+        self.contents.lock().unwrap().link_log(log, &mut Vec::new())
+    }
+
+    fn insert_batch(&self, logs: &[Log]) -> Result<Option<Uuid>, StringError> {
+        let mut contents = self.contents.lock().unwrap();
+        // Keep track of what goes in, so a failure part way through leaves nothing behind
+        let mut changes = Vec::new();
+        for log in logs {
+            match contents.link_log(log, &mut changes) {
+                Ok(None) => {}
+                Ok(Some(existing)) => {
+                    contents.undo(changes);
+                    return Ok(Some(existing));
+                }
+                Err(err) => {
+                    contents.undo(changes);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn get(&self, id: &Uuid) -> Result<Option<Log>, StringError> {
+        Ok(self.contents.lock().unwrap().logs.get(id).cloned())
+    }
+
+    fn head(&self, owner: &Uuid, stream: &Option<String>) -> Result<Option<Uuid>, StringError> {
+        Ok(self.contents.lock().unwrap().heads.get(&(*owner, stream.clone())).cloned())
+    }
+
+    fn first(&self, owner: &Uuid, stream: &Option<String>) -> Result<Option<Uuid>, StringError> {
+        Ok(self.contents.lock().unwrap().firsts.get(&(*owner, stream.clone())).cloned())
+    }
+
+    fn heads(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, StringError> {
+        Ok(chain_ends(&self.contents.lock().unwrap().heads, stream))
+    }
+
+    fn firsts(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, StringError> {
+        Ok(chain_ends(&self.contents.lock().unwrap().firsts, stream))
+    }
+
+    fn streams(&self) -> Result<Vec<String>, StringError> {
+        let contents = self.contents.lock().unwrap();
+        let mut streams: Vec<String> = contents.logs.values().filter_map(|log| log.stream.clone()).collect();
+        streams.sort();
+        streams.dedup();
+        Ok(streams)
+    }
+
+    fn range(&self, after: &Uuid, limit: usize) -> Result<Vec<Log>, StringError> {
+        let contents = self.contents.lock().unwrap();
+        let mut logs = Vec::new();
+        let mut current = contents.logs.get(after).and_then(|log| log.next);
+        while let Some(id) = current {
+            if logs.len() >= limit {
+                break;
+            }
+            let log = try!(contents.logs
+                .get(&id)
+                .ok_or(StringError::from(format!("Missing log {} in chain", id))));
+            current = log.next;
+            logs.push(log.clone());
+        }
+        Ok(logs)
+    }
+
+    fn nodes(&self) -> Result<Vec<String>, StringError> {
+        Ok(self.contents.lock().unwrap().nodes.clone())
+    }
+
+    fn add_node(&self, url: &str) -> Result<bool, StringError> {
+        let mut contents = self.contents.lock().unwrap();
+        if contents.nodes.iter().any(|node| node == url) {
+            return Ok(false);
+        }
+        contents.nodes.push(String::from(url));
+        Ok(true)
+    }
+
+    fn remove_node(&self, url: &str) -> Result<bool, StringError> {
+        let mut contents = self.contents.lock().unwrap();
+        let before = contents.nodes.len();
+        contents.nodes.retain(|node| node != url);
+        Ok(contents.nodes.len() != before)
+    }
+
+    fn notifiers(&self) -> Result<Vec<NotifierRecord>, StringError> {
+        Ok(self.contents.lock().unwrap().notifiers.clone())
+    }
+
+    fn add_notifier(&self, notifier: &NotifierRecord) -> Result<bool, StringError> {
+        let mut contents = self.contents.lock().unwrap();
+        for existing in contents.notifiers.iter_mut() {
+            if existing.url == notifier.url {
+                *existing = notifier.clone();
+                return Ok(false);
+            }
+        }
+        contents.notifiers.push(notifier.clone());
+        Ok(true)
+    }
+
+    fn remove_notifier(&self, url: &str) -> Result<bool, StringError> {
+        let mut contents = self.contents.lock().unwrap();
+        let before = contents.notifiers.len();
+        contents.notifiers.retain(|notifier| notifier.url != url);
+        Ok(contents.notifiers.len() != before)
+    }
+}
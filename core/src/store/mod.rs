@@ -0,0 +1,207 @@
+use potboiler_common::config::Config;
+use potboiler_common::db;
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use serde_json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub mod memory;
+pub mod pg;
+pub mod sqlite;
+
+// Where core keeps the log, the nodes it's been told about, and who wants notifications.
+// Postgres is the full backend; the SQLite and in-memory ones are for single nodes (tests and
+// development), as clustering, snapshots, retention, export/import and fork quarantine still
+// go straight to Postgres.
+pub trait LogStore: Send + Sync {
+    // Links the entry in after its prev and stores it, unless another entry already has that
+    // place in the chain, in which case that entry's id is returned and nothing is stored
+    fn insert(&self, log: &Log) -> Result<Option<Uuid>, StringError>;
+    // As insert, but for a run of entries that all go in or none do
+    fn insert_batch(&self, logs: &[Log]) -> Result<Option<Uuid>, StringError>;
+    fn get(&self, id: &Uuid) -> Result<Option<Log>, StringError>;
+    fn head(&self, owner: &Uuid, stream: &Option<String>) -> Result<Option<Uuid>, StringError>;
+    fn first(&self, owner: &Uuid, stream: &Option<String>) -> Result<Option<Uuid>, StringError>;
+    // Owner -> latest entry, for every chain in the stream
+    fn heads(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, StringError>;
+    // Owner -> earliest entry, for every chain in the stream
+    fn firsts(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, StringError>;
+    fn streams(&self) -> Result<Vec<String>, StringError>;
+    // Up to limit entries following on from the given one in its chain, in order
+    fn range(&self, after: &Uuid, limit: usize) -> Result<Vec<Log>, StringError>;
+
+    fn nodes(&self) -> Result<Vec<String>, StringError>;
+    // Returns false if we already had it
+    fn add_node(&self, url: &str) -> Result<bool, StringError>;
+    // Returns false if we didn't have it
+    fn remove_node(&self, url: &str) -> Result<bool, StringError>;
+
+    fn notifiers(&self) -> Result<Vec<NotifierRecord>, StringError>;
+    // Replaces the one registered with the same URL, if any, in which case it returns false
+    fn add_notifier(&self, notifier: &NotifierRecord) -> Result<bool, StringError>;
+    // Returns false if the URL wasn't registered
+    fn remove_notifier(&self, url: &str) -> Result<bool, StringError>;
+}
+
+#[derive(Clone, Debug)]
+pub struct NotifierRecord {
+    pub url: String,
+    pub stream: Option<String>,
+    pub filter: Option<serde_json::Value>,
+    pub token: Option<String>,
+}
+
+pub type SharedStore = Arc<LogStore>;
+
+pub enum Backend {
+    Postgres(db::PostgresPool),
+    Sqlite(String),
+    Memory,
+}
+
+// Picks the backend from the storage setting: "postgres" (the default, using database_url),
+// "sqlite" (using the file at sqlite_path) or "memory"
+pub fn backend_from_config(config: &Config) -> Result<Backend, StringError> {
+    match config.storage.as_str() {
+        "postgres" => {
+            let db_url = try!(config.database_url
+                .as_ref()
+                .ok_or(StringError::from("Needed database_url (DATABASE_URL)")));
+            Ok(Backend::Postgres(db::get_pool(db_url, config.db_pool_size)))
+        }
+        "sqlite" => Ok(Backend::Sqlite(config.sqlite_path.clone())),
+        "memory" => Ok(Backend::Memory),
+        other => {
+            let msg = format!("Unknown STORAGE '{}', expected postgres, sqlite or memory", other);
+            Err(StringError::from(msg))
+        }
+    }
+}
+
+pub fn open(backend: &Backend) -> Result<SharedStore, StringError> {
+    match *backend {
+        Backend::Postgres(ref pool) => Ok(Arc::new(pg::PostgresStore::new(pool.clone()))),
+        Backend::Sqlite(ref path) => {
+            let store = try!(sqlite::SqliteStore::open(path)
+                .map_err(|err| StringError::from(format!("Can't open SQLite store at {}: {}", path, err))));
+            Ok(Arc::new(store))
+        }
+        Backend::Memory => {
+            warn!("Using in-memory storage, so the log will be lost on restart");
+            Ok(Arc::new(memory::MemoryStore::new()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use potboiler_common::clock;
+    use potboiler_common::types::Log;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use super::{NotifierRecord, SharedStore};
+    use super::memory::MemoryStore;
+    use super::sqlite::SqliteStore;
+    use uuid::Uuid;
+
+    fn new_log(owner: Uuid, stream: &str, prev: Option<&Log>) -> Log {
+        Log {
+            id: Uuid::new_v4(),
+            owner: owner,
+            prev: prev.map(|log| log.id),
+            next: None,
+            when: clock::get_timestamp_from_state(&clock::init_clock().clock_state),
+            data: Value::String(String::from("data")),
+            stream: Some(String::from(stream)),
+            trace: None,
+        }
+    }
+
+    fn ids(logs: &[Log]) -> Vec<Uuid> {
+        logs.iter().map(|log| log.id).collect()
+    }
+
+    // What every backend has to do the same way
+    fn conforms(store: SharedStore) {
+        let owner = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let stream = Some(String::from("orders"));
+
+        let first = new_log(owner, "orders", None);
+        let second = new_log(owner, "orders", Some(&first));
+        assert_eq!(store.insert(&first).unwrap(), None);
+        assert_eq!(store.insert(&second).unwrap(), None);
+        assert_eq!(store.get(&first.id).unwrap().unwrap().next, Some(second.id));
+
+        // Another entry after the same one, or another start to the chain, conflicts
+        let rival = new_log(owner, "orders", Some(&first));
+        assert_eq!(store.insert(&rival).unwrap(), Some(second.id));
+        assert_eq!(store.insert(&new_log(owner, "orders", None)).unwrap(), Some(first.id));
+        assert!(store.get(&rival.id).unwrap().is_none());
+
+        // A batch with a conflict in it leaves nothing behind
+        let third = new_log(owner, "orders", Some(&second));
+        let fourth = new_log(owner, "orders", Some(&third));
+        let clash = new_log(owner, "orders", Some(&first));
+        assert_eq!(store.insert_batch(&[third.clone(), fourth.clone(), clash]).unwrap(),
+                   Some(second.id));
+        assert!(store.get(&third.id).unwrap().is_none());
+        assert_eq!(store.get(&second.id).unwrap().unwrap().next, None);
+        assert_eq!(store.head(&owner, &stream).unwrap(), Some(second.id));
+        assert_eq!(store.insert_batch(&[third.clone(), fourth.clone()]).unwrap(), None);
+
+        let range = store.range(&first.id, 10).unwrap();
+        assert_eq!(ids(&range), vec![second.id, third.id, fourth.id]);
+        assert_eq!(ids(&store.range(&first.id, 2).unwrap()), vec![second.id, third.id]);
+        assert!(store.range(&fourth.id, 10).unwrap().is_empty());
+        assert!(store.range(&Uuid::new_v4(), 10).unwrap().is_empty());
+
+        let elsewhere = new_log(other, "orders", None);
+        assert_eq!(store.insert(&elsewhere).unwrap(), None);
+        assert_eq!(store.insert(&new_log(owner, "invoices", None)).unwrap(), None);
+        let mut heads = HashMap::new();
+        heads.insert(owner, fourth.id);
+        heads.insert(other, elsewhere.id);
+        assert_eq!(store.heads(&stream).unwrap(), heads);
+        let mut firsts = HashMap::new();
+        firsts.insert(owner, first.id);
+        firsts.insert(other, elsewhere.id);
+        assert_eq!(store.firsts(&stream).unwrap(), firsts);
+        assert_eq!(store.head(&owner, &stream).unwrap(), Some(fourth.id));
+        assert_eq!(store.first(&owner, &stream).unwrap(), Some(first.id));
+        assert_eq!(store.head(&Uuid::new_v4(), &stream).unwrap(), None);
+        assert_eq!(store.streams().unwrap(),
+                   vec![String::from("invoices"), String::from("orders")]);
+
+        // Registering a URL again replaces what it was registered with
+        let mut notifier = NotifierRecord {
+            url: String::from("http://localhost:8001/kv/event"),
+            stream: None,
+            filter: None,
+            token: Some(String::from("old")),
+        };
+        assert!(store.add_notifier(&notifier).unwrap());
+        notifier.stream = Some(String::from("orders"));
+        notifier.token = Some(String::from("new"));
+        assert!(!store.add_notifier(&notifier).unwrap());
+        let notifiers = store.notifiers().unwrap();
+        assert_eq!(notifiers.len(), 1);
+        assert_eq!(notifiers[0].stream, notifier.stream);
+        assert_eq!(notifiers[0].token, notifier.token);
+        assert!(store.remove_notifier(&notifier.url).unwrap());
+        assert!(store.notifiers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_store_conforms() {
+        conforms(Arc::new(MemoryStore::new()));
+    }
+
+    #[test]
+    fn sqlite_store_conforms() {
+        conforms(Arc::new(SqliteStore::open(":memory:").unwrap()));
+    }
+}
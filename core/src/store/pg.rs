@@ -0,0 +1,344 @@
+use hybrid_clocks;
+use postgres;
+use postgres::GenericConnection;
+use postgres::error::SqlState;
+use postgres::rows::{Row, RowIndex};
+use postgres::types::FromSql;
+use potboiler_common::db;
+use potboiler_common::get_raw_timestamp;
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use std::collections::HashMap;
+use std::io::Cursor;
+use store::{LogStore, NotifierRecord};
+use uuid::Uuid;
+
+pub fn get_head(conn: &GenericConnection,
+                owner: &Uuid,
+                stream: &Option<String>)
+                -> Result<Option<Uuid>, postgres::error::Error> {
+    let results = try!(conn.query("SELECT id from log WHERE next is null and owner = $1 and stream is not \
+                                   distinct from $2 LIMIT 1",
+                                  &[owner, stream]));
+    if results.is_empty() {
+        Ok(None)
+    } else {
+        let id: Uuid = results.get(0).get("id");
+        Ok(Some(id))
+    }
+}
+
+pub fn get_first(conn: &GenericConnection,
+                 owner: &Uuid,
+                 stream: &Option<String>)
+                 -> Result<Option<Uuid>, postgres::error::Error> {
+    let results = try!(conn.query("SELECT id from log WHERE prev is null and owner = $1 and stream is not \
+                                   distinct from $2 LIMIT 1",
+                                  &[owner, stream]));
+    if results.is_empty() {
+        Ok(None)
+    } else {
+        let id: Uuid = results.get(0).get("id");
+        Ok(Some(id))
+    }
+}
+
+fn get_with_null<I, T>(row: &Row, index: I) -> Option<T>
+    where I: RowIndex,
+          T: FromSql
+{
+    match row.get_opt(index) {
+        Some(val) => {
+            match val {
+                Ok(val) => Some(val),
+                Err(_) => None,
+            }
+        }
+        None => None,
+    }
+}
+
+pub fn log_from_row(row: &Row) -> Result<Log, StringError> {
+    let hlc_tstamp: Vec<u8> = row.get("hlc_tstamp");
+    let when = try!(hybrid_clocks::Timestamp::read_bytes(Cursor::new(hlc_tstamp))
+        .map_err(|err| StringError::from(format!("Bad log timestamp: {:?}", err))));
+    Ok(Log {
+        id: row.get("id"),
+        owner: row.get("owner"),
+        prev: get_with_null(row, "prev"),
+        next: get_with_null(row, "next"),
+        data: row.get("data"),
+        when: when,
+        stream: get_with_null(row, "stream"),
+        trace: get_with_null(row, "trace"),
+    })
+}
+
+pub fn read_log(conn: &GenericConnection, id: &Uuid) -> Result<Option<Log>, StringError> {
+    let results = try!(conn.query("SELECT id, owner, next, prev, data, hlc_tstamp, stream, trace from log \
+                                   where id=$1",
+                                  &[id]));
+    if results.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(try!(log_from_row(&results.get(0)))))
+    }
+}
+
+// Returns the entry that's already in the place this log wants, if any
+pub fn conflicting_entry(conn: &GenericConnection, log: &Log) -> Result<Option<Uuid>, StringError> {
+    let existing = match log.prev {
+        Some(prev) => {
+            let rows = try!(conn.query("SELECT next from log WHERE id=$1", &[&prev]));
+            if rows.is_empty() {
+                None
+            } else {
+                rows.get(0).get("next")
+            }
+        }
+        None => try!(get_first(conn, &log.owner, &log.stream)),
+    };
+    Ok(existing.and_then(|id| if id == log.id { None } else { Some(id) }))
+}
+
+// Links the log in after its prev and inserts it, unless another entry is already there, in
+// which case that's returned instead. The next update only applies to an unclaimed prev, so
+// concurrent appends can't both succeed, and for the start of a chain, where there's no prev to
+// update, the unique index on chain starts does the same. It's all done in a transaction (a
+// savepoint, if there's one already), so a clash doesn't leave the update behind, or abort the
+// caller's transaction.
+pub fn link_log(conn: &GenericConnection, log: &Log) -> Result<Option<Uuid>, StringError> {
+    let trans = try!(conn.transaction());
+    if log.prev.is_some() {
+        let updated = try!(trans.execute("UPDATE log set next = $1 where owner = $2 and id = $3 and (next is \
+                                          null or next = $1)",
+                                         &[&log.id, &log.owner, &log.prev]));
+        if updated == 0 {
+            if let Some(existing) = try!(conflicting_entry(&trans, log)) {
+                return Ok(Some(existing));
+            }
+        }
+    } else if let Some(existing) = try!(conflicting_entry(&trans, log)) {
+        return Ok(Some(existing));
+    }
+    let raw_timestamp = get_raw_timestamp(&log.when);
+    let inserted = trans.execute("INSERT INTO log (id, owner, data, prev, hlc_tstamp, stream, trace) VALUES \
+                                  ($1, $2, $3, $4, $5, $6, $7)",
+                                 &[&log.id,
+                                   &log.owner,
+                                   &log.data,
+                                   &log.prev,
+                                   &raw_timestamp,
+                                   &log.stream,
+                                   &log.trace]);
+    match inserted {
+        Ok(_) => {
+            try!(trans.commit());
+            Ok(None)
+        }
+        Err(postgres::error::Error::Db(ref dberr)) if dberr.code == SqlState::UniqueViolation => {
+            // Another entry started the chain first
+            drop(trans);
+            match try!(conflicting_entry(conn, log)) {
+                Some(existing) => Ok(Some(existing)),
+                None => Err(StringError::from(format!("Log {} is already stored", log.id))),
+            }
+        }
+        Err(err) => Err(StringError::from(err)),
+    }
+}
+
+fn chain_ends(conn: &GenericConnection,
+              column: &str,
+              stream: &Option<String>)
+              -> Result<HashMap<Uuid, Uuid>, StringError> {
+    let rows = try!(conn.query(&format!("SELECT id, owner from log WHERE {} is null and stream is not \
+                                         distinct from $1",
+                                        column),
+                               &[stream]));
+    Ok(rows.iter().map(|row| (row.get("owner"), row.get("id"))).collect())
+}
+
+// Inserts, treating a clash with an existing row as "already there" rather than an error
+fn insert_unique(conn: &GenericConnection,
+                 query: &str,
+                 params: &[&postgres::types::ToSql])
+                 -> Result<bool, StringError> {
+    match conn.execute(query, params) {
+        Ok(_) => Ok(true),
+        Err(postgres::error::Error::Db(ref dberr)) if dberr.code == SqlState::UniqueViolation => Ok(false),
+        Err(err) => Err(StringError::from(err)),
+    }
+}
+
+pub struct PostgresStore {
+    pool: db::PostgresPool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: db::PostgresPool) -> PostgresStore {
+        PostgresStore { pool: pool }
+    }
+
+    fn conn(&self) -> Result<db::PostgresConnection, StringError> {
+        self.pool
+            .get()
+            .map_err(|err| StringError::from(format!("Couldn't get a connection to pg: {:?}", err)))
+    }
+}
+
+impl LogStore for PostgresStore {
+    // link_log is a transaction of its own
+    fn insert(&self, log: &Log) -> Result<Option<Uuid>, StringError> {
+        link_log(&*try!(self.conn()), log)
+    }
+
+    fn insert_batch(&self, logs: &[Log]) -> Result<Option<Uuid>, StringError> {
+        let conn = try!(self.conn());
+        let trans = try!(conn.transaction());
+        for log in logs {
+            if let Some(existing) = try!(link_log(&trans, log)) {
+                // Dropping the transaction rolls back the rest of the batch
+                return Ok(Some(existing));
+            }
+        }
+        try!(trans.commit());
+        Ok(None)
+    }
+
+    fn get(&self, id: &Uuid) -> Result<Option<Log>, StringError> {
+        read_log(&*try!(self.conn()), id)
+    }
+
+    fn head(&self, owner: &Uuid, stream: &Option<String>) -> Result<Option<Uuid>, StringError> {
+        Ok(try!(get_head(&*try!(self.conn()), owner, stream)))
+    }
+
+    fn first(&self, owner: &Uuid, stream: &Option<String>) -> Result<Option<Uuid>, StringError> {
+        Ok(try!(get_first(&*try!(self.conn()), owner, stream)))
+    }
+
+    fn heads(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, StringError> {
+        chain_ends(&*try!(self.conn()), "next", stream)
+    }
+
+    fn firsts(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, StringError> {
+        chain_ends(&*try!(self.conn()), "prev", stream)
+    }
+
+    fn streams(&self) -> Result<Vec<String>, StringError> {
+        let conn = try!(self.conn());
+        let rows = try!(conn.query("SELECT DISTINCT stream from log WHERE stream is not null", &[]));
+        Ok(rows.iter().map(|row| row.get("stream")).collect())
+    }
+
+    fn range(&self, after: &Uuid, limit: usize) -> Result<Vec<Log>, StringError> {
+        let conn = try!(self.conn());
+        let mut logs = Vec::new();
+        let mut current = match try!(read_log(&*conn, after)) {
+            Some(log) => log.next,
+            None => return Ok(logs),
+        };
+        while let Some(id) = current {
+            if logs.len() >= limit {
+                break;
+            }
+            let log = try!(try!(read_log(&*conn, &id))
+                .ok_or(StringError::from(format!("Missing log {} in chain", id))));
+            current = log.next;
+            logs.push(log);
+        }
+        Ok(logs)
+    }
+
+    fn nodes(&self) -> Result<Vec<String>, StringError> {
+        let conn = try!(self.conn());
+        let rows = try!(conn.query("select url from nodes", &[]));
+        Ok(rows.iter().map(|row| row.get("url")).collect())
+    }
+
+    fn add_node(&self, url: &str) -> Result<bool, StringError> {
+        insert_unique(&*try!(self.conn()), "INSERT INTO nodes (url) VALUES ($1)", &[&url])
+    }
+
+    fn remove_node(&self, url: &str) -> Result<bool, StringError> {
+        let conn = try!(self.conn());
+        Ok(try!(conn.execute("DELETE from nodes where url = $1", &[&url])) > 0)
+    }
+
+    fn notifiers(&self) -> Result<Vec<NotifierRecord>, StringError> {
+        let conn = try!(self.conn());
+        let rows = try!(conn.query("select url, stream, filter, token from notifications", &[]));
+        Ok(rows.iter()
+            .map(|row| {
+                NotifierRecord {
+                    url: row.get("url"),
+                    stream: row.get("stream"),
+                    filter: row.get("filter"),
+                    token: row.get("token"),
+                }
+            })
+            .collect())
+    }
+
+    fn add_notifier(&self, notifier: &NotifierRecord) -> Result<bool, StringError> {
+        let conn = try!(self.conn());
+        let params: &[&postgres::types::ToSql] =
+            &[&notifier.url, &notifier.stream, &notifier.filter, &notifier.token];
+        let updated = try!(conn.execute("UPDATE notifications SET stream = $2, filter = $3, token = $4 WHERE \
+                                         url = $1",
+                                        params));
+        if updated > 0 {
+            return Ok(false);
+        }
+        // Still an upsert, in case it's registered again in the meantime
+        try!(conn.execute("INSERT INTO notifications (url, stream, filter, token) VALUES ($1, $2, $3, $4) ON \
+                           CONFLICT (url) DO UPDATE SET stream = $2, filter = $3, token = $4",
+                          params));
+        Ok(true)
+    }
+
+    fn remove_notifier(&self, url: &str) -> Result<bool, StringError> {
+        let conn = try!(self.conn());
+        Ok(try!(conn.execute("DELETE from notifications where url = $1", &[&url])) > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{link_log, read_log};
+    use testing;
+    use uuid::Uuid;
+
+    #[test]
+    fn chains_only_start_once() {
+        let conn = match testing::connection() {
+            Some(conn) => conn,
+            None => return,
+        };
+        let trans = conn.transaction().unwrap();
+        let owner = Uuid::new_v4();
+        let first = testing::new_log(owner, None, None);
+        assert_eq!(link_log(&trans, &first).unwrap(), None);
+        let rival = testing::new_log(owner, None, None);
+        assert_eq!(link_log(&trans, &rival).unwrap(), Some(first.id));
+
+        // Even without the check link_log does first, as for an append racing with it
+        {
+            let savepoint = trans.transaction().unwrap();
+            assert!(savepoint.execute("INSERT INTO log (id, owner, data, prev, hlc_tstamp) SELECT $1, owner, \
+                                       data, NULL, hlc_tstamp from log WHERE id=$2",
+                                      &[&rival.id, &first.id])
+                .is_err());
+        }
+        // In another stream, it's a chain of its own
+        let other = testing::new_log(owner, Some("other"), None);
+        assert_eq!(link_log(&trans, &other).unwrap(), None);
+
+        // The clash didn't spoil the transaction
+        let second = testing::new_log(owner, None, Some(&first));
+        assert_eq!(link_log(&trans, &second).unwrap(), None);
+        assert_eq!(read_log(&trans, &first.id).unwrap().unwrap().next, Some(second.id));
+        assert!(read_log(&trans, &rival.id).unwrap().is_none());
+    }
+}
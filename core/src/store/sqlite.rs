@@ -0,0 +1,319 @@
+use hybrid_clocks::Timestamp;
+use potboiler_common::get_raw_timestamp;
+use potboiler_common::string_error::StringError;
+use potboiler_common::types::Log;
+use rusqlite::{self, Connection, Row};
+use rusqlite::types::ToSql;
+use serde_json;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+use store::{LogStore, NotifierRecord};
+use uuid::Uuid;
+
+// An embedded backend, keeping everything in one SQLite file. Ids are stored as text and the
+// data as JSON text, as SQLite has no types for either.
+
+const SCHEMA: &'static str = "CREATE TABLE IF NOT EXISTS log (id TEXT PRIMARY KEY, owner TEXT NOT NULL, \
+                              prev TEXT, next TEXT, data TEXT NOT NULL, hlc_tstamp BLOB NOT NULL, stream \
+                              TEXT, trace TEXT);
+                              CREATE INDEX IF NOT EXISTS log_owner ON log (owner, stream);
+                              CREATE TABLE IF NOT EXISTS nodes (url TEXT PRIMARY KEY);
+                              CREATE TABLE IF NOT EXISTS notifications (url TEXT PRIMARY KEY, stream TEXT, \
+                              filter TEXT, token TEXT);";
+
+const LOG_COLUMNS: &'static str = "id, owner, prev, next, data, hlc_tstamp, stream, trace";
+
+fn sql_error(err: rusqlite::Error) -> StringError {
+    StringError::from(format!("SQLite error: {:?}", err))
+}
+
+fn uuid_text(id: &Uuid) -> String {
+    id.hyphenated().to_string()
+}
+
+fn optional_uuid(raw: Option<String>) -> Result<Option<Uuid>, StringError> {
+    match raw {
+        Some(val) => Ok(Some(try!(Uuid::parse_str(&val)))),
+        None => Ok(None),
+    }
+}
+
+// A log row as SQLite gives it to us, before parsing
+struct RawLog {
+    id: String,
+    owner: String,
+    prev: Option<String>,
+    next: Option<String>,
+    data: String,
+    hlc_tstamp: Vec<u8>,
+    stream: Option<String>,
+    trace: Option<String>,
+}
+
+fn raw_log(row: &Row) -> RawLog {
+    RawLog {
+        id: row.get(0),
+        owner: row.get(1),
+        prev: row.get(2),
+        next: row.get(3),
+        data: row.get(4),
+        hlc_tstamp: row.get(5),
+        stream: row.get(6),
+        trace: row.get(7),
+    }
+}
+
+impl RawLog {
+    fn into_log(self) -> Result<Log, StringError> {
+        let when = try!(Timestamp::read_bytes(Cursor::new(self.hlc_tstamp))
+            .map_err(|err| StringError::from(format!("Bad log timestamp: {:?}", err))));
+        Ok(Log {
+            id: try!(Uuid::parse_str(&self.id)),
+            owner: try!(Uuid::parse_str(&self.owner)),
+            prev: try!(optional_uuid(self.prev)),
+            next: try!(optional_uuid(self.next)),
+            data: try!(serde_json::from_str(&self.data)),
+            when: when,
+            stream: self.stream,
+            trace: self.trace,
+        })
+    }
+}
+
+fn query_strings(conn: &Connection, query: &str, params: &[&ToSql]) -> Result<Vec<String>, StringError> {
+    let mut stmt = try!(conn.prepare(query).map_err(sql_error));
+    let rows = try!(stmt.query_map(params, |row| row.get(0)).map_err(sql_error));
+    let mut values = Vec::new();
+    for row in rows {
+        values.push(try!(row.map_err(sql_error)));
+    }
+    Ok(values)
+}
+
+fn read_log(conn: &Connection, id: &Uuid) -> Result<Option<Log>, StringError> {
+    let mut stmt = try!(conn.prepare(&format!("SELECT {} from log WHERE id = ?1", LOG_COLUMNS))
+        .map_err(sql_error));
+    let mut rows = try!(stmt.query_map(&[&uuid_text(id)], raw_log).map_err(sql_error));
+    match rows.next() {
+        Some(row) => Ok(Some(try!(try!(row.map_err(sql_error)).into_log()))),
+        None => Ok(None),
+    }
+}
+
+// The start (column "prev") or end (column "next") of an owner's chain
+fn chain_end(conn: &Connection,
+             column: &str,
+             owner: &Uuid,
+             stream: &Option<String>)
+             -> Result<Option<Uuid>, StringError> {
+    let ids = try!(query_strings(conn,
+                                 &format!("SELECT id from log WHERE {} IS NULL AND owner = ?1 AND stream IS \
+                                           ?2 LIMIT 1",
+                                          column),
+                                 &[&uuid_text(owner), stream]));
+    optional_uuid(ids.into_iter().next())
+}
+
+fn chain_ends(conn: &Connection,
+              column: &str,
+              stream: &Option<String>)
+              -> Result<HashMap<Uuid, Uuid>, StringError> {
+    let mut stmt = try!(conn.prepare(&format!("SELECT owner, id from log WHERE {} IS NULL AND stream IS ?1",
+                                              column))
+        .map_err(sql_error));
+    let rows = try!(stmt.query_map(&[stream], |row| (row.get::<i32, String>(0), row.get::<i32, String>(1)))
+        .map_err(sql_error));
+    let mut ends = HashMap::new();
+    for row in rows {
+        let (owner, id) = try!(row.map_err(sql_error));
+        ends.insert(try!(Uuid::parse_str(&owner)), try!(Uuid::parse_str(&id)));
+    }
+    Ok(ends)
+}
+
+fn conflicting_entry(conn: &Connection, log: &Log) -> Result<Option<Uuid>, StringError> {
+    let existing = match log.prev {
+        Some(prev) => try!(read_log(conn, &prev)).and_then(|prev_log| prev_log.next),
+        None => try!(chain_end(conn, "prev", &log.owner, &log.stream)),
+    };
+    Ok(existing.and_then(|id| if id == log.id { None } else { Some(id) }))
+}
+
+// Same rules as the Postgres link_log
+fn link_log(conn: &Connection, log: &Log) -> Result<Option<Uuid>, StringError> {
+    if let Some(prev) = log.prev {
+        let updated = try!(conn.execute("UPDATE log SET next = ?1 WHERE owner = ?2 AND id = ?3 AND (next IS \
+                                         NULL OR next = ?1)",
+                                        &[&uuid_text(&log.id), &uuid_text(&log.owner), &uuid_text(&prev)])
+            .map_err(sql_error));
+        if updated == 0 {
+            if let Some(existing) = try!(conflicting_entry(conn, log)) {
+                return Ok(Some(existing));
+            }
+        }
+    } else if let Some(existing) = try!(conflicting_entry(conn, log)) {
+        return Ok(Some(existing));
+    }
+    try!(conn.execute("INSERT INTO log (id, owner, prev, data, hlc_tstamp, stream, trace) VALUES (?1, ?2, \
+                       ?3, ?4, ?5, ?6, ?7)",
+                      &[&uuid_text(&log.id),
+                        &uuid_text(&log.owner),
+                        &log.prev.as_ref().map(uuid_text),
+                        &try!(serde_json::to_string(&log.data)),
+                        &get_raw_timestamp(&log.when),
+                        &log.stream,
+                        &log.trace])
+        .map_err(sql_error));
+    Ok(None)
+}
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<SqliteStore, StringError> {
+        let conn = try!(Connection::open(path).map_err(sql_error));
+        try!(conn.execute_batch(SCHEMA).map_err(sql_error));
+        // Files from before log entries had traces. Fails if the column's already there, which is fine.
+        let _ = conn.execute("ALTER TABLE log ADD COLUMN trace TEXT", &[]);
+        info!("Using SQLite storage at {}", path);
+        Ok(SqliteStore { conn: Mutex::new(conn) })
+    }
+}
+
+impl LogStore for SqliteStore {
+    fn insert(&self, log: &Log) -> Result<Option<Uuid>, StringError> {
+        link_log(&self.conn.lock().unwrap(), log)
+    }
+
+    fn insert_batch(&self, logs: &[Log]) -> Result<Option<Uuid>, StringError> {
+        let mut conn = self.conn.lock().unwrap();
+        let trans = try!(conn.transaction().map_err(sql_error));
+        for log in logs {
+            if let Some(existing) = try!(link_log(&trans, log)) {
+                // Dropping the transaction rolls back the rest of the batch
+                return Ok(Some(existing));
+            }
+        }
+        try!(trans.commit().map_err(sql_error));
+        Ok(None)
+    }
+
+    fn get(&self, id: &Uuid) -> Result<Option<Log>, StringError> {
+        read_log(&self.conn.lock().unwrap(), id)
+    }
+
+    fn head(&self, owner: &Uuid, stream: &Option<String>) -> Result<Option<Uuid>, StringError> {
+        chain_end(&self.conn.lock().unwrap(), "next", owner, stream)
+    }
+
+    fn first(&self, owner: &Uuid, stream: &Option<String>) -> Result<Option<Uuid>, StringError> {
+        chain_end(&self.conn.lock().unwrap(), "prev", owner, stream)
+    }
+
+    fn heads(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, StringError> {
+        chain_ends(&self.conn.lock().unwrap(), "next", stream)
+    }
+
+    fn firsts(&self, stream: &Option<String>) -> Result<HashMap<Uuid, Uuid>, StringError> {
+        chain_ends(&self.conn.lock().unwrap(), "prev", stream)
+    }
+
+    fn streams(&self) -> Result<Vec<String>, StringError> {
+        query_strings(&self.conn.lock().unwrap(),
+                      "SELECT DISTINCT stream from log WHERE stream IS NOT NULL ORDER BY stream",
+                      &[])
+    }
+
+    fn range(&self, after: &Uuid, limit: usize) -> Result<Vec<Log>, StringError> {
+        let conn = self.conn.lock().unwrap();
+        let mut logs = Vec::new();
+        let mut current = match try!(read_log(&conn, after)) {
+            Some(log) => log.next,
+            None => return Ok(logs),
+        };
+        while let Some(id) = current {
+            if logs.len() >= limit {
+                break;
+            }
+            let log = try!(try!(read_log(&conn, &id))
+                .ok_or(StringError::from(format!("Missing log {} in chain", id))));
+            current = log.next;
+            logs.push(log);
+        }
+        Ok(logs)
+    }
+
+    fn nodes(&self) -> Result<Vec<String>, StringError> {
+        query_strings(&self.conn.lock().unwrap(), "SELECT url from nodes", &[])
+    }
+
+    fn add_node(&self, url: &str) -> Result<bool, StringError> {
+        let conn = self.conn.lock().unwrap();
+        let added = try!(conn.execute("INSERT OR IGNORE INTO nodes (url) VALUES (?1)", &[&url])
+            .map_err(sql_error));
+        Ok(added > 0)
+    }
+
+    fn remove_node(&self, url: &str) -> Result<bool, StringError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = try!(conn.execute("DELETE from nodes WHERE url = ?1", &[&url]).map_err(sql_error));
+        Ok(removed > 0)
+    }
+
+    fn notifiers(&self) -> Result<Vec<NotifierRecord>, StringError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT url, stream, filter, token from notifications")
+            .map_err(sql_error));
+        let rows = try!(stmt.query_map(&[], |row| {
+                (row.get::<i32, String>(0),
+                 row.get::<i32, Option<String>>(1),
+                 row.get::<i32, Option<String>>(2),
+                 row.get::<i32, Option<String>>(3))
+            })
+            .map_err(sql_error));
+        let mut notifiers = Vec::new();
+        for row in rows {
+            let (url, stream, filter, token) = try!(row.map_err(sql_error));
+            let filter = match filter {
+                Some(raw) => Some(try!(serde_json::from_str(&raw))),
+                None => None,
+            };
+            notifiers.push(NotifierRecord {
+                url: url,
+                stream: stream,
+                filter: filter,
+                token: token,
+            });
+        }
+        Ok(notifiers)
+    }
+
+    fn add_notifier(&self, notifier: &NotifierRecord) -> Result<bool, StringError> {
+        let filter = match notifier.filter {
+            Some(ref filter) => Some(try!(serde_json::to_string(filter))),
+            None => None,
+        };
+        let conn = self.conn.lock().unwrap();
+        let updated = try!(conn.execute("UPDATE notifications SET stream = ?2, filter = ?3, token = ?4 WHERE \
+                                         url = ?1",
+                                        &[&notifier.url, &notifier.stream, &filter, &notifier.token])
+            .map_err(sql_error));
+        if updated > 0 {
+            return Ok(false);
+        }
+        try!(conn.execute("INSERT INTO notifications (url, stream, filter, token) VALUES (?1, ?2, ?3, ?4)",
+                          &[&notifier.url, &notifier.stream, &filter, &notifier.token])
+            .map_err(sql_error));
+        Ok(true)
+    }
+
+    fn remove_notifier(&self, url: &str) -> Result<bool, StringError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = try!(conn.execute("DELETE from notifications WHERE url = ?1", &[&url])
+            .map_err(sql_error));
+        Ok(removed > 0)
+    }
+}
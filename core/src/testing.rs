@@ -0,0 +1,37 @@
+use postgres::{Connection, SslMode};
+use potboiler_common::clock;
+use potboiler_common::types::Log;
+use schema;
+use serde_json::Value;
+use std::env;
+use uuid::Uuid;
+
+// Helpers for the tests. The ones that need Postgres only run with TEST_DATABASE_URL set (to a
+// database they can migrate), and pass without doing anything otherwise. They work inside a
+// transaction that's never committed, so they leave nothing behind.
+
+pub fn connection() -> Option<Connection> {
+    let url = match env::var("TEST_DATABASE_URL") {
+        Ok(ref url) if !url.is_empty() => url.clone(),
+        _ => {
+            println!("No TEST_DATABASE_URL, so skipping a Postgres test");
+            return None;
+        }
+    };
+    let conn = Connection::connect(url.as_str(), SslMode::None).expect("Can connect to TEST_DATABASE_URL");
+    schema::up(&conn).expect("Can migrate TEST_DATABASE_URL");
+    Some(conn)
+}
+
+pub fn new_log(owner: Uuid, stream: Option<&str>, prev: Option<&Log>) -> Log {
+    Log {
+        id: Uuid::new_v4(),
+        owner: owner,
+        prev: prev.map(|log| log.id),
+        next: None,
+        when: clock::get_timestamp_from_state(&clock::init_clock().clock_state),
+        data: Value::String(String::from("data")),
+        stream: stream.map(String::from),
+        trace: None,
+    }
+}
@@ -0,0 +1 @@
+include!(concat!(env!("OUT_DIR"), "/serde_types.rs"));
@@ -38,18 +38,29 @@ class LocallyBuilt:
         return ret
 
 class Core(LocallyBuilt):
-    def __init__(self, name, index, postgres):
+    def __init__(self, name, index, postgres, seed, cluster_token):
         self.base_port = 8000 + index*100
         self.name = name
         self.postgres = postgres
+        self.seed = seed
+        self.cluster_token = cluster_token
     def service(self):
         ret = self.build_context("core")
-        ret["environment"] = {"DATABASE_URL": self.postgres.db_url()}
+        ret["environment"] = {
+            "DATABASE_URL": self.postgres.db_url(),
+            "ADVERTISE_URL": self.advertise_url()
+        }
+        if self.seed != None:
+            ret["environment"]["SEEDS"] = self.seed.advertise_url()
+        if self.cluster_token != None:
+            ret["environment"]["CLUSTER_TOKEN"] = self.cluster_token
         ret["ports"] = ["%d:8000"%self.base_port]
         ret["links"] = ["%s:postgres"%self.postgres.name]
         return ret
     def log_url(self):
         return "http://core:8000/log"
+    def advertise_url(self):
+        return "http://%s:8000" % self.name
 
 class KV(LocallyBuilt):
     def __init__(self, name, index, postgres, core):
@@ -115,20 +126,24 @@ class Correspondence:
         ret["environment"] = {"SERVER_URL": self.kv.base_url()}
         ret["ports"] = ["%d:5000"%self.base_port]
         ret["links"] = ["%s:kv"%self.kv.name]
-        ret["command"] = "bash -c \"./wait-for-port.sh kv 8001 && flask run --host=0.0.0.0\""
+        ret["command"] = "bash -c \"./wait-for-ready.sh kv 8001 && flask run --host=0.0.0.0\""
         return ret
 
 parser = argparse.ArgumentParser()
 parser.add_argument('--component', action='append', default=[], dest="components", choices=["kv", "pigtail", "correspondence"])
+parser.add_argument('--cluster-token', default=None, help="Shared token for node-to-node requests")
 parser.add_argument('count', type=int)
 args = parser.parse_args()
 
 services = []
+first_core = None
 for index in range(args.count):
     postgres = Postgres("postgres-core%d"%index)
     services.append(postgres)
-    core = Core("core%d"%index, index, postgres)
+    core = Core("core%d"%index, index, postgres, first_core, args.cluster_token)
     services.append(core)
+    if first_core == None:
+        first_core = core
 
     if args.components == [] or "kv" in args.components or "correspondence" in args.components:
         postgres = Postgres("postgres-kv%d"%index)
@@ -12,60 +12,106 @@ extern crate lazy_static;
 #[macro_use]
 extern crate potboiler_common;
 extern crate serde_json;
-extern crate hyper;
 extern crate hybrid_clocks;
+extern crate potboiler_client;
 #[macro_use]
 extern crate mime;
 mod tables;
 
+use iron::Protocol;
 use iron::prelude::*;
 use iron::status;
 use logger::Logger;
 use persistent::Read as PRead;
 use persistent::State;
-use potboiler_common::{db, iron_str_error, server_id};
+use potboiler_client::{ClientConfig, CoreClient};
+use potboiler_common::{auth, db, error, health, iron_str_error, metrics, server_id, shutdown,
+                        trace};
+use potboiler_common::config::Config;
+use potboiler_common::error::ServiceError;
+use potboiler_common::metrics::Counter;
 use potboiler_common::string_error::StringError;
 use potboiler_common::types::{CRDT, LWW, Log};
 use r2d2_postgres::PostgresConnectionManager;
 use router::Router;
-use std::env;
 use std::io::Read;
 use std::ops::Deref;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub type PostgresConnection = r2d2::PooledConnection<PostgresConnectionManager>;
 
 lazy_static! {
-    static ref SERVER_URL: String = env::var("SERVER_URL").expect("Needed SERVER_URL");
+    static ref CONFIG: Config = Config::load(Config::defaults(8001), &["database_url", "core_url"]);
+    static ref CORE: CoreClient = CoreClient::new(CONFIG.core_url.as_ref().expect("Needed core_url"),
+                                                  ClientConfig {
+                                                      timeout: CONFIG.timeout(),
+                                                      token: auth::core_token(),
+                                                      ..ClientConfig::default()
+                                                  });
 }
 
+static STREAM: &'static str = "kv";
+const REGISTER_RETRY_SECS: u64 = 5;
+
+const EVENTS: Counter = Counter {
+    name: "potboiler_kv_events_total",
+    help: "Events from core applied to tables, by CRDT type",
+};
+
 include!(concat!(env!("OUT_DIR"), "/serde_types.rs"));
 
-fn get_key(_: &mut Request) -> IronResult<Response> {
-    Ok(Response::with((status::Ok, "get_key")))
+fn get_key(req: &mut Request) -> IronResult<Response> {
+    let table = potboiler_common::get_req_key(req, "table").ok_or(raw_string_iron_error("No table key"))?;
+    let key = potboiler_common::get_req_key(req, "key").ok_or(raw_string_iron_error("No key"))?;
+    let crdt = match tables::get_tables(req).get(&table) {
+        Some(&val) => val,
+        None => return Err(ServiceError::NotFound(format!("No table {}", table)).into()),
+    };
+    let conn = get_pg_connection!(&req);
+    let value = match crdt {
+        CRDT::LWW => {
+            let results = conn.query(&format!("select value from {} where key=$1", table), &[&key])
+                .map_err(iron_str_error)?;
+            if results.is_empty() {
+                return Err(ServiceError::NotFound(format!("No key {} in {}", key, table)).into());
+            }
+            let value: serde_json::Value = results.get(0).get("value");
+            value
+        }
+        CRDT::ORSET => {
+            if get_crdt(&conn, &table, &key)?.is_none() {
+                return Err(ServiceError::NotFound(format!("No key {} in {}", key, table)).into());
+            }
+            let results = conn.query(&format!("select item from {}_items where collection=$1", table),
+                       &[&key])
+                .map_err(iron_str_error)?;
+            let items: Vec<String> = results.iter().map(|row| row.get("item")).collect();
+            serde_json::to_value(&items)
+        }
+        CRDT::GSET => return string_iron_error("No G-Set support yet"),
+    };
+    Ok(Response::with((status::Ok,
+                       mime!(Application / Json),
+                       serde_json::to_string(&value).map_err(iron_str_error)?)))
+}
+
+fn json_from_body(req: &mut Request) -> Result<serde_json::Value, ServiceError> {
+    let mut body = String::new();
+    req.body.read_to_string(&mut body)?;
+    Ok(serde_json::de::from_str(&body)?)
 }
 
 fn update_key(req: &mut Request) -> IronResult<Response> {
-    let body_string = {
-        let mut body = String::new();
-        req.body.read_to_string(&mut body).expect("could read from body");
-        body
-    };
-    let mut json: serde_json::Value = match serde_json::de::from_str(&body_string) {
-        Ok(val) => val,
-        Err(err) => return Err(IronError::new(err, (status::BadRequest, "Bad JSON"))),
+    let table = potboiler_common::get_req_key(req, "table").ok_or(raw_string_iron_error("No table key"))?;
+    let key = potboiler_common::get_req_key(req, "key").ok_or(raw_string_iron_error("No key"))?;
+    let mut map = match json_from_body(req)? {
+        serde_json::Value::Object(map) => map,
+        _ => return Err(ServiceError::BadRequest(String::from("Change must be a JSON object")).into()),
     };
-    let map = json.as_object_mut().unwrap();
-    map.insert("table".to_string(),
-               serde_json::to_value(&potboiler_common::get_req_key(req, "table").unwrap()));
-    map.insert("key".to_string(),
-               serde_json::to_value(&potboiler_common::get_req_key(req, "key").unwrap()));
-
-    let client = hyper::client::Client::new();
-    let res = client.post(SERVER_URL.deref())
-        .body(&serde_json::ser::to_string(map).unwrap())
-        .send()
-        .expect("sender ok");
-    assert_eq!(res.status, hyper::status::StatusCode::Created);
+    map.insert("table".to_string(), serde_json::to_value(&table));
+    map.insert("key".to_string(), serde_json::to_value(&key));
+    CORE.append(Some(STREAM), &serde_json::Value::Object(map)).map_err(ServiceError::from)?;
     Ok(Response::with((status::Ok, "update_key")))
 }
 
@@ -100,7 +146,7 @@ fn make_table(conn: &PostgresConnection, table_name: &str, kind: &CRDT) -> IronR
 }
 
 fn raw_string_iron_error(error: &str) -> IronError {
-    IronError::new(StringError(error.to_string()), (status::BadRequest, error))
+    ServiceError::BadRequest(error.to_string()).into()
 }
 
 fn string_iron_error(error: &str) -> IronResult<Response> {
@@ -125,19 +171,31 @@ fn get_crdt(conn: &PostgresConnection,
 }
 
 fn new_event(req: &mut Request) -> IronResult<Response> {
-    let body_string = {
-        let mut body = String::new();
-        req.body.read_to_string(&mut body).expect("could read from body");
-        body
-    };
-    let json: serde_json::Value = match serde_json::de::from_str(&body_string) {
-        Ok(val) => val,
-        Err(err) => return Err(IronError::new(err, (status::BadRequest, "Bad JSON"))),
-    };
+    let json = json_from_body(req)?;
+    if json.find("snapshot").is_some() {
+        // Core sends the snapshot it bootstrapped from, but we don't make any
+        info!("Ignoring snapshot");
+        return Ok(Response::with(status::NoContent));
+    }
     info!("body: {:?}", json);
-    let log = try!(serde_json::from_value::<Log>(json).map_err(iron_str_error));
+    if let serde_json::Value::Array(items) = json {
+        for item in items {
+            let log = serde_json::from_value::<Log>(item).map_err(ServiceError::from)?;
+            let log_id = log.id;
+            let res = try!(apply_event(req, log));
+            if res.status != Some(status::NoContent) {
+                warn!("Event {} in batch gave {:?}", log_id, res.status);
+            }
+        }
+        return Ok(Response::with(status::NoContent));
+    }
+    let log = serde_json::from_value::<Log>(json).map_err(ServiceError::from)?;
+    apply_event(req, log)
+}
+
+fn apply_event(req: &mut Request, log: Log) -> IronResult<Response> {
     info!("log: {:?}", log);
-    let change: Change = serde_json::from_value(log.data).unwrap();
+    let change: Change = serde_json::from_value(log.data).map_err(ServiceError::from)?;
     info!("change: {:?}", change);
     let tables = tables::get_tables(req);
     let table_type = match tables.get(&change.table) {
@@ -173,22 +231,22 @@ fn new_event(req: &mut Request) -> IronResult<Response> {
                             conn.execute(&format!("INSERT INTO {} (key, value, crdt) VALUES ($1, $2, $3)",
                                                   &change.table),
                                          &[&change.key, &change.change, &serde_json::to_value(&lww)])
-                                .expect("insert worked");
-                            if &change.table == tables::CONFIG_TABLE {
-                                let crdt = crdt_to_use.unwrap();
+                                .map_err(ServiceError::from)?;
+                            if let Some(crdt) = crdt_to_use {
                                 make_table(&conn, &change.key, &crdt)?;
                                 tables::add_table(req, &change.key, &crdt);
                             }
                         }
                         Some(raw_crdt) => {
-                            let mut lww: LWW = serde_json::from_value(raw_crdt).expect("bad raw crdt");
+                            let mut lww: LWW = serde_json::from_value(raw_crdt)
+                                .map_err(|err| ServiceError::Storage(format!("Bad stored CRDT: {}", err)))?;
                             if lww.when < log.when {
                                 lww.when = log.when;
                                 lww.data = change.change.clone();
                                 conn.execute(&format!("UPDATE {} set value=$2, crdt=$3 where key=$1",
                                                       &change.table),
                                              &[&change.key, &change.change, &serde_json::to_value(&lww)])
-                                    .expect("update worked");
+                                    .map_err(ServiceError::from)?;
                             } else {
                                 info!("Earlier event, skipping");
                             }
@@ -214,7 +272,7 @@ fn new_event(req: &mut Request) -> IronResult<Response> {
                      false)
                 }
             };
-            let trans = conn.transaction().unwrap();
+            let trans = conn.transaction().map_err(ServiceError::from)?;
             match change.op {
                 Operation::Add => {
                     if !crdt.removes.contains_key(&op.key) && !crdt.adds.contains_key(&op.key) {
@@ -262,6 +320,7 @@ fn new_event(req: &mut Request) -> IronResult<Response> {
             return string_iron_error("Only support LWW and OR-Set so far");
         }
     }
+    EVENTS.inc(&[("crdt", format!("{:?}", table_type).as_str())]);
     Ok(Response::with(status::NoContent))
 }
 
@@ -288,22 +347,36 @@ fn list_keys(req: &mut Request) -> IronResult<Response> {
                        serde_json::to_string(&key_names).map_err(iron_str_error)?)))
 }
 
+// Keeps trying to register with core in the background, so we can come up (and say we're not
+// ready yet) while core is still starting
+fn register_with_core(url: String) {
+    health::pending("registered", "Not registered with core yet");
+    thread::spawn(move || {
+        let event_token = auth::event_token();
+        loop {
+            match CORE.register(&url, Some(STREAM), event_token.as_ref().map(|token| token.as_str())) {
+                Ok(_) => {
+                    info!("Registered with core as {}", url);
+                    health::passed("registered");
+                    return;
+                }
+                Err(err) => {
+                    warn!("Couldn't register with core, retrying in {}s: {}", REGISTER_RETRY_SECS, err);
+                    health::pending("registered", &format!("Couldn't register with core: {}", err));
+                    thread::sleep(Duration::from_secs(REGISTER_RETRY_SECS));
+                }
+            }
+        }
+    });
+}
+
 fn main() {
+    let signals = shutdown::listen();
+    let config = CONFIG.deref();
     log4rs::init_file("log.yaml", Default::default()).expect("log config ok");
-    let client = hyper::client::Client::new();
-
-    let mut map: serde_json::Map<String, String> = serde_json::Map::new();
-    let host: &str = &env::var("HOST").unwrap_or("localhost".to_string());
-    map.insert("url".to_string(),
-               format!("http://{}:8001/kv/event", host).to_string());
-    let res = client.post(&format!("{}/register", SERVER_URL.deref()))
-        .body(&serde_json::ser::to_string(&map).unwrap())
-        .send()
-        .expect("Register ok");
-    assert_eq!(res.status, hyper::status::StatusCode::NoContent);
-
-    let db_url: &str = &env::var("DATABASE_URL").expect("Needed DATABASE_URL");
-    let pool = db::get_pool(db_url);
+    trace::init("potboiler-kv", config.trace_collector_url.clone());
+    let db_url = config.database_url.as_ref().expect("Needed database_url");
+    let pool = db::get_pool(db_url, config.db_pool_size);
     let conn = pool.get().unwrap();
     match make_table(&conn, tables::CONFIG_TABLE, &CRDT::LWW) {
         Ok(_) => {}
@@ -312,20 +385,55 @@ fn main() {
             return;
         }
     }
+    health::passed("migrations");
     let (logger_before, logger_after) = Logger::new(None);
+    let (metrics_before, metrics_after) = metrics::middleware();
     let mut router = Router::new();
-    router.get("/kv", list_tables);
-    router.get("/kv/:table", list_keys);
-    router.get("/kv/:table/:key", get_key);
-    router.post("/kv/:table/:key", update_key);
-    router.post("/kv/event", new_event);
+    router.get("/metrics", metrics::serve);
+    router.get("/health", health::live);
+    router.get("/ready", health::ready);
+    router.get("/kv", auth::with_scope("kv:read", list_tables));
+    router.get("/kv/:table", auth::with_scope("kv:table:{table}:read", list_keys));
+    router.get("/kv/:table/:key", auth::with_scope("kv:table:{table}:read", get_key));
+    router.post("/kv/:table/:key", auth::with_scope("kv:table:{table}:write", update_key));
+    router.post("/kv/event", auth::with_scope("kv:event", new_event));
     let mut chain = Chain::new(router);
+    chain.link_before(shutdown::Drain);
+    chain.link_before(metrics_before);
+    chain.link_before(trace::Tracing);
     chain.link_before(logger_before);
+    chain.link_before(auth::init_auth());
+    chain.link_after(error::JsonErrors);
     chain.link_after(logger_after);
-    chain.link_before(PRead::<server_id::ServerId>::one(server_id::setup()));
+    chain.link_before(PRead::<server_id::ServerId>::one(server_id::setup(&config.id_path)));
     chain.link(PRead::<db::PostgresDB>::both(pool));
     let tables = tables::init_tables(&conn);
     chain.link(State::<tables::Tables>::both(tables));
+    chain.link_after(metrics_after);
+    chain.link_after(trace::Tracing);
+    chain.link_after(shutdown::Drain);
+    let event_url = format!("{}/kv/event", config.advertise_url);
+    register_with_core(event_url.clone());
+    signals.on_shutdown(config.shutdown_timeout(), move |deadline| {
+        // So core stops sending us events we won't be around to apply, but only in the time we've
+        // got left. The timeout's for reading and writing separately, so they get half each.
+        let now = Instant::now();
+        if now >= deadline {
+            warn!("No time left to deregister from core");
+            return;
+        }
+        let core = CoreClient::new(CONFIG.core_url.as_ref().expect("Needed core_url"),
+                                   ClientConfig {
+                                       timeout: (deadline - now) / 2,
+                                       retries: 0,
+                                       token: auth::core_token(),
+                                       ..ClientConfig::default()
+                                   });
+        match core.deregister(&event_url) {
+            Ok(_) => info!("Deregistered from core"),
+            Err(err) => warn!("Couldn't deregister from core: {}", err),
+        }
+    });
     info!("Potboiler-kv booted");
-    Iron::new(chain).http("0.0.0.0:8001").unwrap();
+    Iron::new(chain).listen_with(config.listen.as_str(), config.http_threads, Protocol::Http, None).unwrap();
 }
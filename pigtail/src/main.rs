@@ -13,26 +13,33 @@ extern crate potboiler_common;
 extern crate logger;
 extern crate serde_json;
 extern crate serde;
-extern crate hyper;
+extern crate potboiler_client;
 extern crate router;
 extern crate uuid;
 extern crate hybrid_clocks;
 extern crate time;
 
 use hybrid_clocks::{Timestamp, WallT};
+use iron::Protocol;
 use iron::modifiers::Redirect;
 use iron::prelude::{Chain, Iron, IronError, IronResult, Request, Response};
 use iron::status;
 use logger::Logger;
 use persistent::Read as PRead;
 use postgres::error::SqlState;
-use potboiler_common::{clock, db, get_raw_timestamp, iron_str_error};
+use potboiler_client::{ClientConfig, CoreClient};
+use potboiler_common::{auth, clock, db, error, get_raw_timestamp, health, iron_str_error, metrics,
+                        shutdown, trace};
+use potboiler_common::config::Config;
+use potboiler_common::error::ServiceError;
+use potboiler_common::metrics::Gauge;
 use potboiler_common::string_error::StringError;
 use potboiler_common::types::Log;
 use serde_json::{Map, Value};
-use std::env;
 use std::io::Cursor;
 use std::ops::Deref;
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
 use time::Duration;
 use types::QueueOperation;
 use uuid::Uuid;
@@ -42,10 +49,23 @@ mod types;
 pub type PostgresConnection = r2d2::PooledConnection<r2d2_postgres::PostgresConnectionManager>;
 
 lazy_static! {
-    static ref SERVER_URL: String = env::var("SERVER_URL").expect("Needed SERVER_URL");
-    static ref HOST: String = env::var("HOST").expect("Needed HOST");
+    static ref CONFIG: Config = Config::load(Config::defaults(8000), &["database_url", "core_url"]);
+    static ref CORE: CoreClient = CoreClient::new(CONFIG.core_url.as_ref().expect("Needed core_url"),
+                                                  ClientConfig {
+                                                      timeout: CONFIG.timeout(),
+                                                      token: auth::core_token(),
+                                                      ..ClientConfig::default()
+                                                  });
 }
 
+static STREAM: &'static str = "pigtail";
+const REGISTER_RETRY_SECS: u64 = 5;
+
+const QUEUE_DEPTH: Gauge = Gauge {
+    name: "potboiler_pigtail_queue_items",
+    help: "Items in each queue, by queue and state",
+};
+
 fn string_from_body<T: std::io::Read>(body: &mut T) -> IronResult<String> {
     let mut result = String::new();
     try!(body.read_to_string(&mut result).map_err(iron_str_error));
@@ -54,22 +74,21 @@ fn string_from_body<T: std::io::Read>(body: &mut T) -> IronResult<String> {
 
 fn json_from_body(req: &mut Request) -> IronResult<Value> {
     let body_string = try!(string_from_body(&mut req.body));
-    let json: Value = match serde_json::de::from_str(&body_string) {
-        Ok(val) => val,
-        Err(err) => return Err(IronError::new(err, (status::BadRequest, "Bad JSON"))),
-    };
+    let json: Value = try!(serde_json::de::from_str(&body_string).map_err(ServiceError::from));
     return Ok(json);
 }
 
+// The body of a request that adds to a queue item, with the bits from the URL added in
+fn object_from_body(req: &mut Request) -> IronResult<Map<String, Value>> {
+    match try!(json_from_body(req)) {
+        Value::Object(map) => Ok(map),
+        _ => Err(IronError::from(ServiceError::BadRequest(String::from("Body must be a JSON object")))),
+    }
+}
+
 fn add_queue_operation(op: QueueOperation) -> IronResult<String> {
-    let client = hyper::client::Client::new();
-    let mut res = client.post(SERVER_URL.deref())
-        .body(&serde_json::ser::to_string(&op).unwrap())
-        .send()
-        .expect("sender ok");
-    assert_eq!(res.status, hyper::status::StatusCode::Created);
-    let data = try!(string_from_body(&mut res));
-    Ok(data)
+    let id = try!(CORE.append(Some(STREAM), &serde_json::to_value(&op)).map_err(ServiceError::from));
+    Ok(id.hyphenated().to_string())
 }
 
 fn create_queue(req: &mut Request) -> IronResult<Response> {
@@ -78,7 +97,7 @@ fn create_queue(req: &mut Request) -> IronResult<Response> {
     let name = op.name.clone();
     match add_queue_operation(QueueOperation::Create(op)) {
         Ok(_) => {
-            let new_url = format!("http://{}:8000/queue/{}", HOST.deref(), &name);
+            let new_url = format!("{}/queue/{}", CONFIG.advertise_url, &name);
             Ok(Response::with((status::Created,
                                Redirect(iron::Url::parse(&new_url).expect("URL parsed ok")))))
         }
@@ -133,7 +152,27 @@ fn parse_progress<F>(req: &mut Request,
 
 fn new_event(req: &mut Request) -> IronResult<Response> {
     let json = try!(json_from_body(req));
+    if json.find("snapshot").is_some() {
+        // Core sends the snapshot it bootstrapped from, but we don't make any
+        info!("Ignoring snapshot");
+        return Ok(Response::with(status::NoContent));
+    }
+    if let Value::Array(items) = json {
+        for item in items {
+            let log = try!(serde_json::from_value::<Log>(item).map_err(iron_str_error));
+            let log_id = log.id;
+            let res = try!(apply_event(req, log));
+            if res.status != Some(status::NoContent) {
+                warn!("Event {} in batch gave {:?}", log_id, res.status);
+            }
+        }
+        return Ok(Response::with(status::NoContent));
+    }
     let log = try!(serde_json::from_value::<Log>(json).map_err(iron_str_error));
+    apply_event(req, log)
+}
+
+fn apply_event(req: &mut Request, log: Log) -> IronResult<Response> {
     info!("log: {:?}", log);
     let log_when = log.when.clone();
     clock::observe_timestamp(&clock::get_clock(req), log_when);
@@ -158,10 +197,12 @@ fn new_event(req: &mut Request) -> IronResult<Response> {
                     if let postgres::error::Error::Db(dberr) = err {
                         match dberr.code {
                             SqlState::UniqueViolation => {}
-                            _ => return Err(iron_str_error(dberr)),
+                            _ => {
+                                return Err(ServiceError::Storage(format!("Database error: {}", dberr)).into())
+                            }
                         };
                     } else {
-                        return Err(iron_str_error(err));
+                        return Err(ServiceError::from(err).into());
                     }
                 }
             };
@@ -170,15 +211,15 @@ fn new_event(req: &mut Request) -> IronResult<Response> {
         QueueOperation::Add(add) => {
             info!("add: {:?}", add);
             let raw_timestamp = get_raw_timestamp(&log.when);
-            conn.execute(&format!("INSERT INTO {} (id, task_name, state, info, hlc_tstamp) VALUES($1, $2, \
-                                   $3, $4, $5)",
-                                  add.queue_name),
-                         &[&log.id,
-                           &add.task_name,
-                           &String::from("pending"),
-                           &serde_json::to_value(&add.info),
-                           &raw_timestamp])
-                .unwrap();
+            try!(conn.execute(&format!("INSERT INTO {} (id, task_name, state, info, hlc_tstamp) \
+                                        VALUES($1, $2, $3, $4, $5)",
+                                       add.queue_name),
+                              &[&log.id,
+                                &add.task_name,
+                                &String::from("pending"),
+                                &serde_json::to_value(&add.info),
+                                &raw_timestamp])
+                .map_err(ServiceError::from));
         }
         QueueOperation::Progress(progress) => {
             info!("progress: {:?}", progress);
@@ -291,17 +332,14 @@ fn get_queue_item(req: &mut Request) -> IronResult<Response> {
 }
 
 fn add_queue_item(req: &mut Request) -> IronResult<Response> {
-    let mut json = try!(json_from_body(req));
+    let mut map = try!(object_from_body(req));
     let queue_name = try!(get_queue_name(req));
-    {
-        let map = json.as_object_mut().unwrap();
-        map.insert("queue_name".to_string(), serde_json::to_value(&queue_name));
-    }
-    let op = try!(serde_json::from_value::<types::QueueAdd>(json).map_err(iron_str_error));
+    map.insert("queue_name".to_string(), serde_json::to_value(&queue_name));
+    let op = try!(serde_json::from_value::<types::QueueAdd>(Value::Object(map)).map_err(iron_str_error));
     match add_queue_operation(QueueOperation::Add(op)) {
         Ok(val) => {
-            let new_url = format!("http://{}:8000/queue/{}/{}",
-                                  HOST.deref(),
+            let new_url = format!("{}/queue/{}/{}",
+                                  CONFIG.advertise_url,
                                   &queue_name,
                                   &val);
             Ok(Response::with((status::Created,
@@ -312,15 +350,13 @@ fn add_queue_item(req: &mut Request) -> IronResult<Response> {
 }
 
 fn build_queue_progress(req: &mut Request) -> IronResult<types::QueueProgress> {
-    let mut json = try!(json_from_body(req));
-    {
-        let queue_name = try!(get_queue_name(req));
-        let id = try!(get_item_id(req));
-        let map = json.as_object_mut().unwrap();
-        map.insert("queue_name".to_string(), serde_json::to_value(&queue_name));
-        map.insert("id".to_string(), serde_json::to_value(&id));
-    }
-    return Ok(try!(serde_json::from_value::<types::QueueProgress>(json).map_err(iron_str_error)));
+    let mut map = try!(object_from_body(req));
+    let queue_name = try!(get_queue_name(req));
+    let id = try!(get_item_id(req));
+    map.insert("queue_name".to_string(), serde_json::to_value(&queue_name));
+    map.insert("id".to_string(), serde_json::to_value(&id));
+    return Ok(try!(serde_json::from_value::<types::QueueProgress>(Value::Object(map))
+        .map_err(iron_str_error)));
 }
 
 fn progress_queue_item(req: &mut Request) -> IronResult<Response> {
@@ -345,39 +381,125 @@ fn make_queue_table(conn: &PostgresConnection) {
         .expect("make queue table worked");
 }
 
-fn main() {
-    log4rs::init_file("log.yaml", Default::default()).expect("log config ok");
-    let client = hyper::client::Client::new();
+// Counts the items in each queue whenever the metrics are read
+fn watch_queue_depth(pool: db::PostgresPool) {
+    metrics::add_collector(move || {
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("Couldn't get a connection to count queue items: {:?}", err);
+                return;
+            }
+        };
+        let queues = match conn.query("SELECT key from queues", &[]) {
+            Ok(rows) => rows,
+            Err(err) => {
+                warn!("Couldn't list queues to count their items: {}", err);
+                return;
+            }
+        };
+        QUEUE_DEPTH.reset();
+        for row in &queues {
+            let queue: String = row.get("key");
+            match conn.query(&format!("SELECT state, count(*) from {} GROUP BY state", queue), &[]) {
+                Ok(counts) => {
+                    for count in &counts {
+                        let state: String = count.get(0);
+                        let items: i64 = count.get(1);
+                        let labels = [("queue", queue.as_str()), ("state", state.as_str())];
+                        QUEUE_DEPTH.set(&labels, items as f64);
+                    }
+                }
+                Err(err) => warn!("Couldn't count items in {}: {}", queue, err),
+            }
+        }
+    });
+}
 
-    let mut map: serde_json::Map<String, String> = serde_json::Map::new();
-    map.insert("url".to_string(),
-               format!("http://{}:8000/event", HOST.deref()).to_string());
-    let res = client.post(&format!("{}/register", SERVER_URL.deref()))
-        .body(&serde_json::ser::to_string(&map).unwrap())
-        .send()
-        .expect("Register ok");
-    assert_eq!(res.status, hyper::status::StatusCode::NoContent);
+// Keeps trying to register with core in the background, so we can come up (and say we're not
+// ready yet) while core is still starting
+fn register_with_core(url: String) {
+    health::pending("registered", "Not registered with core yet");
+    thread::spawn(move || {
+        let event_token = auth::event_token();
+        loop {
+            match CORE.register(&url, Some(STREAM), event_token.as_ref().map(|token| token.as_str())) {
+                Ok(_) => {
+                    info!("Registered with core as {}", url);
+                    health::passed("registered");
+                    return;
+                }
+                Err(err) => {
+                    warn!("Couldn't register with core, retrying in {}s: {}", REGISTER_RETRY_SECS, err);
+                    health::pending("registered", &format!("Couldn't register with core: {}", err));
+                    thread::sleep(StdDuration::from_secs(REGISTER_RETRY_SECS));
+                }
+            }
+        }
+    });
+}
 
-    let db_url: &str = &env::var("DATABASE_URL").expect("Needed DATABASE_URL");
-    let pool = db::get_pool(db_url);
+fn main() {
+    let signals = shutdown::listen();
+    let config = CONFIG.deref();
+    log4rs::init_file("log.yaml", Default::default()).expect("log config ok");
+    trace::init("potboiler-pigtail", config.trace_collector_url.clone());
+    let db_url = config.database_url.as_ref().expect("Needed database_url");
+    let pool = db::get_pool(db_url, config.db_pool_size);
     let conn = pool.get().unwrap();
     make_queue_table(&conn);
+    health::passed("migrations");
+    watch_queue_depth(pool.clone());
     let (logger_before, logger_after) = Logger::new(None);
+    let (metrics_before, metrics_after) = metrics::middleware();
     let mut router = router::Router::new();
-    router.post("/create", create_queue);
-    router.post("/event", new_event);
-    router.get("/queue/:queue_name", get_queue_items);
-    router.post("/queue/:queue_name", add_queue_item);
-    router.delete("/queue/:queue_name", delete_queue);
-    router.get("/queue/:queue_name/:id", get_queue_item);
-    router.put("/queue/:queue_name/:id", progress_queue_item);
-    router.delete("/queue/:queue_name/:id", finish_queue_item);
+    router.get("/metrics", metrics::serve);
+    router.get("/health", health::live);
+    router.get("/ready", health::ready);
+    router.post("/create", auth::with_scope("queue:admin", create_queue));
+    router.post("/event", auth::with_scope("queue:event", new_event));
+    router.get("/queue/:queue_name", auth::with_scope("queue:{queue_name}:read", get_queue_items));
+    router.post("/queue/:queue_name", auth::with_scope("queue:{queue_name}:write", add_queue_item));
+    router.delete("/queue/:queue_name", auth::with_scope("queue:{queue_name}:admin", delete_queue));
+    router.get("/queue/:queue_name/:id", auth::with_scope("queue:{queue_name}:read", get_queue_item));
+    router.put("/queue/:queue_name/:id", auth::with_scope("queue:{queue_name}:work", progress_queue_item));
+    router.delete("/queue/:queue_name/:id", auth::with_scope("queue:{queue_name}:work", finish_queue_item));
     let mut chain = Chain::new(router);
+    chain.link_before(shutdown::Drain);
+    chain.link_before(metrics_before);
+    chain.link_before(trace::Tracing);
     chain.link_before(logger_before);
+    chain.link_before(auth::init_auth());
+    chain.link_after(error::JsonErrors);
     chain.link_after(logger_after);
     chain.link(PRead::<db::PostgresDB>::both(pool));
     let clock_state = clock::init_clock();
     chain.link_before(clock_state);
+    chain.link_after(metrics_after);
+    chain.link_after(trace::Tracing);
+    chain.link_after(shutdown::Drain);
+    let event_url = format!("{}/event", config.advertise_url);
+    register_with_core(event_url.clone());
+    signals.on_shutdown(config.shutdown_timeout(), move |deadline| {
+        // So core stops sending us events we won't be around to apply, but only in the time we've
+        // got left. The timeout's for reading and writing separately, so they get half each.
+        let now = Instant::now();
+        if now >= deadline {
+            warn!("No time left to deregister from core");
+            return;
+        }
+        let core = CoreClient::new(CONFIG.core_url.as_ref().expect("Needed core_url"),
+                                   ClientConfig {
+                                       timeout: (deadline - now) / 2,
+                                       retries: 0,
+                                       token: auth::core_token(),
+                                       ..ClientConfig::default()
+                                   });
+        match core.deregister(&event_url) {
+            Ok(_) => info!("Deregistered from core"),
+            Err(err) => warn!("Couldn't deregister from core: {}", err),
+        }
+    });
     info!("Pigtail booted");
-    Iron::new(chain).http("0.0.0.0:8000").unwrap();
+    Iron::new(chain).listen_with(config.listen.as_str(), config.http_threads, Protocol::Http, None).unwrap();
 }
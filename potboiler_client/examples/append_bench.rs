@@ -0,0 +1,96 @@
+extern crate potboiler_client;
+extern crate serde_json;
+
+use potboiler_client::{ClientConfig, CoreClient};
+use serde_json::Value;
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Appends from a number of threads at once, and reports how many appends a second core managed
+// and how long they took. Each thread has a stream of its own, as appends to the same chain at
+// the same time conflict. Usage: append_bench <log url> [threads] [appends per thread]
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1000000.0
+}
+
+fn number_arg(args: &[String], index: usize, default: usize) -> usize {
+    match args.get(index) {
+        Some(raw) => {
+            match raw.parse() {
+                Ok(number) => number,
+                Err(_) => {
+                    println!("Expected a number, not '{}'", raw);
+                    process::exit(1);
+                }
+            }
+        }
+        None => default,
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let url = match args.get(0) {
+        Some(url) => url.clone(),
+        None => {
+            println!("Usage: append_bench <log url> [threads] [appends per thread]");
+            process::exit(1);
+        }
+    };
+    let threads = number_arg(&args, 1, 16);
+    let appends = number_arg(&args, 2, 500);
+    let config = ClientConfig { retries: 0, ..ClientConfig::default() };
+    let client = Arc::new(CoreClient::new(&url, config));
+
+    let started = Instant::now();
+    let mut workers = Vec::new();
+    for number in 0..threads {
+        let client = client.clone();
+        workers.push(thread::spawn(move || {
+            let stream = format!("bench-{}", number);
+            let mut latencies = Vec::with_capacity(appends);
+            let mut failures = 0;
+            for item in 0..appends {
+                let data = Value::String(format!("bench {}-{}", number, item));
+                let sent = Instant::now();
+                match client.append(Some(&stream), &data) {
+                    Ok(_) => latencies.push(millis(sent.elapsed())),
+                    Err(_) => failures += 1,
+                }
+            }
+            (latencies, failures)
+        }));
+    }
+    let mut latencies = Vec::new();
+    let mut failures = 0;
+    for worker in workers {
+        let (mut done, failed) = worker.join().unwrap();
+        latencies.append(&mut done);
+        failures += failed;
+    }
+    let elapsed = millis(started.elapsed()) / 1000.0;
+
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let percentile = |at: f64| {
+        if latencies.is_empty() {
+            0.0
+        } else {
+            latencies[((latencies.len() - 1) as f64 * at) as usize]
+        }
+    };
+    println!("{} appends from {} threads in {:.2}s ({} failed)",
+             latencies.len(),
+             threads,
+             elapsed,
+             failures);
+    println!("{:.1} appends/s", latencies.len() as f64 / elapsed);
+    println!("latency ms: p50 {:.1}, p90 {:.1}, p99 {:.1}, max {:.1}",
+             percentile(0.5),
+             percentile(0.9),
+             percentile(0.99),
+             percentile(1.0));
+}
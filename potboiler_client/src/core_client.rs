@@ -0,0 +1,149 @@
+use error::{ClientError, ClientResult};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use potboiler_common::types::Log;
+use serde_json::{self, Map, Value};
+use std::collections::HashMap;
+use transport::{ClientConfig, Transport};
+use uuid::Uuid;
+
+// How many entries to ask for at a time when reading a whole stream
+const PAGE_SIZE: usize = 1000;
+
+// Talks to core's /log API. The base URL is the log one, e.g. "http://core:8000/log", as in the
+// services' SERVER_URL.
+pub struct CoreClient {
+    transport: Transport,
+}
+
+fn stream_path(stream: Option<&str>) -> String {
+    match stream {
+        Some(name) => format!("/streams/{}", name),
+        None => String::new(),
+    }
+}
+
+fn parse_ends(body: &str) -> ClientResult<HashMap<Uuid, Uuid>> {
+    let raw: HashMap<String, String> = try!(serde_json::from_str(body));
+    let mut ends = HashMap::new();
+    for (owner, id) in raw {
+        ends.insert(try!(Uuid::parse_str(&owner)), try!(Uuid::parse_str(&id)));
+    }
+    Ok(ends)
+}
+
+impl CoreClient {
+    pub fn new(log_url: &str, config: ClientConfig) -> CoreClient {
+        CoreClient { transport: Transport::new(log_url, config) }
+    }
+
+    // Adds an entry to the end of our chain in the stream (or the default one, for None), and
+    // gives back its id
+    pub fn append(&self, stream: Option<&str>, data: &Value) -> ClientResult<Uuid> {
+        let reply = try!(self.transport.send_retrying_conflicts(Method::Post,
+                                                                &stream_path(stream),
+                                                                Some(try!(serde_json::to_string(data))),
+                                                                &[StatusCode::Created]));
+        Ok(try!(Uuid::parse_str(reply.body.trim())))
+    }
+
+    // All of the items go in, in order, or none do
+    pub fn append_batch(&self, stream: Option<&str>, items: &[Value]) -> ClientResult<Vec<Uuid>> {
+        let reply = try!(self.transport.send(Method::Post,
+                                             &format!("{}/batch", stream_path(stream)),
+                                             Some(try!(serde_json::to_string(&items))),
+                                             &[StatusCode::Created]));
+        let ids: Vec<String> = try!(serde_json::from_str(&reply.body));
+        let mut parsed = Vec::with_capacity(ids.len());
+        for id in ids {
+            parsed.push(try!(Uuid::parse_str(&id)));
+        }
+        Ok(parsed)
+    }
+
+    pub fn get(&self, id: &Uuid) -> ClientResult<Option<Log>> {
+        match self.transport.send(Method::Get, &format!("/{}", id), None, &[StatusCode::Ok]) {
+            Ok(reply) => Ok(Some(try!(serde_json::from_str(&reply.body)))),
+            Err(ref err) if err.is_not_found() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Owner -> latest entry, for every chain in the stream
+    pub fn heads(&self, stream: Option<&str>) -> ClientResult<HashMap<Uuid, Uuid>> {
+        let reply = try!(self.transport.send(Method::Get, &stream_path(stream), None, &[StatusCode::Ok]));
+        parse_ends(&reply.body)
+    }
+
+    // Owner -> earliest entry, for every chain in the stream
+    pub fn firsts(&self, stream: Option<&str>) -> ClientResult<HashMap<Uuid, Uuid>> {
+        let path = format!("{}/first", stream_path(stream));
+        let reply = try!(self.transport.send(Method::Get, &path, None, &[StatusCode::Ok]));
+        parse_ends(&reply.body)
+    }
+
+    pub fn streams(&self) -> ClientResult<Vec<String>> {
+        let reply = try!(self.transport.send(Method::Get, "/streams", None, &[StatusCode::Ok]));
+        Ok(try!(serde_json::from_str(&reply.body)))
+    }
+
+    // Up to limit entries after the given one in its chain
+    pub fn range(&self, after: &Uuid, limit: usize) -> ClientResult<Vec<Log>> {
+        let reply = try!(self.transport.send(Method::Get,
+                                             &format!("/{}/after?limit={}", after, limit),
+                                             None,
+                                             &[StatusCode::Ok]));
+        Ok(try!(serde_json::from_str(&reply.body)))
+    }
+
+    // Everything in the stream, one owner's chain after another
+    pub fn stream(&self, stream: Option<&str>) -> ClientResult<Vec<Log>> {
+        let mut logs = Vec::new();
+        for (owner, first) in try!(self.firsts(stream)) {
+            let first_log = try!(try!(self.get(&first))
+                .ok_or(ClientError::BadResponse(format!("First entry {} for {} has gone", first, owner))));
+            let mut last = first_log.id;
+            logs.push(first_log);
+            loop {
+                let page = try!(self.range(&last, PAGE_SIZE));
+                let done = page.len() < PAGE_SIZE;
+                if let Some(log) = page.last() {
+                    last = log.id;
+                }
+                logs.extend(page);
+                if done {
+                    break;
+                }
+            }
+        }
+        Ok(logs)
+    }
+
+    // Asks core to POST new entries in the stream (or all of them, for None) to url, sending
+    // token as a bearer token with each one if given
+    pub fn register(&self, url: &str, stream: Option<&str>, token: Option<&str>) -> ClientResult<()> {
+        let mut map = Map::new();
+        map.insert(String::from("url"), Value::String(String::from(url)));
+        if let Some(stream) = stream {
+            map.insert(String::from("stream"), Value::String(String::from(stream)));
+        }
+        if let Some(token) = token {
+            map.insert(String::from("token"), Value::String(String::from(token)));
+        }
+        try!(self.transport.send(Method::Post,
+                                 "/register",
+                                 Some(try!(serde_json::to_string(&map))),
+                                 &[StatusCode::NoContent]));
+        Ok(())
+    }
+
+    pub fn deregister(&self, url: &str) -> ClientResult<()> {
+        let mut map = Map::new();
+        map.insert(String::from("url"), Value::String(String::from(url)));
+        try!(self.transport.send(Method::Post,
+                                 "/deregister",
+                                 Some(try!(serde_json::to_string(&map))),
+                                 &[StatusCode::NoContent]));
+        Ok(())
+    }
+}
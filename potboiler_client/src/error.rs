@@ -0,0 +1,138 @@
+use hyper;
+use hyper::status::{StatusClass, StatusCode};
+use potboiler_common::error::ServiceError;
+use serde_json;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use uuid;
+
+#[derive(Debug)]
+pub enum ClientError {
+    // Couldn't get a response at all (connection refused, timed out, etc)
+    Transport(String),
+    // The service answered, but not with the status we wanted. Has the body it sent back.
+    Status(StatusCode, String),
+    // The service answered with something we couldn't make sense of
+    BadResponse(String),
+}
+
+impl ClientError {
+    pub fn status(&self) -> Option<StatusCode> {
+        match *self {
+            ClientError::Status(status, _) => Some(status),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(StatusCode::NotFound)
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        self.status() == Some(StatusCode::Conflict)
+    }
+
+    // Worth trying again: the service couldn't be reached, was having trouble, or (for core)
+    // another append got in first
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            ClientError::Transport(_) => true,
+            ClientError::Status(status, _) => {
+                status.class() == StatusClass::ServerError || status == StatusCode::Conflict
+            }
+            ClientError::BadResponse(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Transport(ref msg) => write!(f, "Couldn't reach service: {}", msg),
+            ClientError::Status(ref status, ref body) => write!(f, "Got {}: {}", status, body),
+            ClientError::BadResponse(ref msg) => write!(f, "Bad response: {}", msg),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn description(&self) -> &str {
+        match *self {
+            ClientError::Transport(ref msg) => msg.as_str(),
+            ClientError::Status(_, ref body) => body.as_str(),
+            ClientError::BadResponse(ref msg) => msg.as_str(),
+        }
+    }
+}
+
+impl From<hyper::Error> for ClientError {
+    fn from(err: hyper::Error) -> ClientError {
+        ClientError::Transport(format!("{:?}", err))
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Transport(format!("{:?}", err))
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(err: serde_json::Error) -> ClientError {
+        ClientError::BadResponse(format!("{:?}", err))
+    }
+}
+
+impl From<uuid::ParseError> for ClientError {
+    fn from(err: uuid::ParseError) -> ClientError {
+        ClientError::BadResponse(format!("{:?}", err))
+    }
+}
+
+// For services passing on a failure from another one: not found and conflicts are the caller's
+// problem, anything else is the other service's
+impl From<ClientError> for ServiceError {
+    fn from(err: ClientError) -> ServiceError {
+        let message = format!("{}", err);
+        match err.status() {
+            Some(StatusCode::NotFound) => ServiceError::NotFound(message),
+            Some(StatusCode::Conflict) => ServiceError::Conflict(message),
+            _ => ServiceError::Upstream(message),
+        }
+    }
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[cfg(test)]
+mod tests {
+    use hyper::status::StatusCode;
+    use potboiler_common::error::ServiceError;
+    use super::ClientError;
+
+    fn code(err: ClientError) -> &'static str {
+        ServiceError::from(err).code()
+    }
+
+    #[test]
+    fn passes_on_not_found_and_conflicts() {
+        assert_eq!(code(ClientError::Status(StatusCode::NotFound, String::new())), "not_found");
+        assert_eq!(code(ClientError::Status(StatusCode::Conflict, String::new())), "conflict");
+    }
+
+    #[test]
+    fn anything_else_is_the_other_services_failure() {
+        assert_eq!(code(ClientError::Status(StatusCode::BadRequest, String::new())), "upstream_failure");
+        assert_eq!(code(ClientError::Status(StatusCode::InternalServerError, String::new())),
+                   "upstream_failure");
+        assert_eq!(code(ClientError::Transport(String::from("connection refused"))), "upstream_failure");
+        assert_eq!(code(ClientError::BadResponse(String::from("not JSON"))), "upstream_failure");
+    }
+
+    #[test]
+    fn keeps_the_other_services_message() {
+        let err = ServiceError::from(ClientError::Status(StatusCode::Conflict, String::from("raced")));
+        assert_eq!(err.message(), "Got 409 Conflict: raced");
+    }
+}
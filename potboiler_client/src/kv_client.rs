@@ -0,0 +1,87 @@
+use error::ClientResult;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use potboiler_common::types::CRDT;
+use serde::Deserialize;
+use serde_json::{self, Map, Value};
+use transport::{ClientConfig, Transport};
+
+const CONFIG_TABLE: &'static str = "_config";
+
+// Talks to kv. The base URL is the /kv one, e.g. "http://kv:8001/kv". Changes go through core,
+// so they show up in reads once kv has been told about them, not straight away.
+pub struct KvClient {
+    transport: Transport,
+}
+
+fn change(op: &str, change: Value) -> Value {
+    let mut map = Map::new();
+    map.insert(String::from("op"), Value::String(String::from(op)));
+    map.insert(String::from("change"), change);
+    Value::Object(map)
+}
+
+fn set_item(key: &str, item: &str) -> Value {
+    let mut map = Map::new();
+    map.insert(String::from("key"), Value::String(String::from(key)));
+    map.insert(String::from("item"), Value::String(String::from(item)));
+    Value::Object(map)
+}
+
+impl KvClient {
+    pub fn new(base_url: &str, config: ClientConfig) -> KvClient {
+        KvClient { transport: Transport::new(base_url, config) }
+    }
+
+    fn update(&self, table: &str, key: &str, body: Value) -> ClientResult<()> {
+        try!(self.transport.send(Method::Post,
+                                 &format!("/{}/{}", table, key),
+                                 Some(try!(serde_json::to_string(&body))),
+                                 &[StatusCode::Ok]));
+        Ok(())
+    }
+
+    pub fn list_tables(&self) -> ClientResult<Vec<String>> {
+        let reply = try!(self.transport.send(Method::Get, "", None, &[StatusCode::Ok]));
+        Ok(try!(serde_json::from_str(&reply.body)))
+    }
+
+    pub fn list_keys(&self, table: &str) -> ClientResult<Vec<String>> {
+        let reply = try!(self.transport.send(Method::Get, &format!("/{}", table), None, &[StatusCode::Ok]));
+        Ok(try!(serde_json::from_str(&reply.body)))
+    }
+
+    pub fn create_table(&self, table: &str, crdt: CRDT) -> ClientResult<()> {
+        let mut config = Map::new();
+        config.insert(String::from("crdt"), serde_json::to_value(&crdt));
+        self.set(CONFIG_TABLE, table, &Value::Object(config))
+    }
+
+    // The current value of a key: the value itself for LWW tables, and the list of items for
+    // OR-Set ones (see get_set). None if there's no such table or key.
+    pub fn get<T: Deserialize>(&self, table: &str, key: &str) -> ClientResult<Option<T>> {
+        match self.transport.send(Method::Get, &format!("/{}/{}", table, key), None, &[StatusCode::Ok]) {
+            Ok(reply) => Ok(Some(try!(serde_json::from_str(&reply.body)))),
+            Err(ref err) if err.is_not_found() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn get_set(&self, table: &str, key: &str) -> ClientResult<Option<Vec<String>>> {
+        self.get(table, key)
+    }
+
+    // For LWW tables
+    pub fn set(&self, table: &str, key: &str, value: &Value) -> ClientResult<()> {
+        self.update(table, key, change("set", value.clone()))
+    }
+
+    // For OR-Set tables. item_key identifies this add, so a later remove can name it.
+    pub fn add(&self, table: &str, key: &str, item_key: &str, item: &str) -> ClientResult<()> {
+        self.update(table, key, change("add", set_item(item_key, item)))
+    }
+
+    pub fn remove(&self, table: &str, key: &str, item_key: &str, item: &str) -> ClientResult<()> {
+        self.update(table, key, change("remove", set_item(item_key, item)))
+    }
+}
@@ -0,0 +1,27 @@
+extern crate hyper;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate potboiler_common;
+extern crate serde;
+extern crate serde_json;
+extern crate uuid;
+
+// Typed clients for core, kv and pigtail, so consumers don't each need their own hyper calls,
+// JSON handling and URL building. Each takes a ClientConfig for timeouts, retries and
+// credentials.
+
+mod error;
+mod transport;
+mod types;
+mod core_client;
+mod kv_client;
+mod pigtail_client;
+
+pub use core_client::CoreClient;
+pub use error::{ClientError, ClientResult};
+pub use kv_client::KvClient;
+pub use pigtail_client::PigtailClient;
+pub use potboiler_common::types::{CRDT, Log};
+pub use transport::ClientConfig;
+pub use types::{QueueItem, QueueListItem, QueueState};
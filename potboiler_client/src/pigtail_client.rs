@@ -0,0 +1,124 @@
+use error::{ClientError, ClientResult};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use serde_json::{self, Map, Value};
+use std::collections::HashMap;
+use transport::{ClientConfig, Transport};
+use types::{QueueItem, QueueListItem, QueueState};
+use uuid::Uuid;
+
+// Talks to pigtail, e.g. "http://pigtail:8000". Like kv, changes go through core, so reads only
+// see them once pigtail has caught up.
+pub struct PigtailClient {
+    transport: Transport,
+}
+
+fn worker_body(worker_id: &Uuid) -> ClientResult<Option<String>> {
+    let mut map = Map::new();
+    map.insert(String::from("worker_id"), serde_json::to_value(worker_id));
+    Ok(Some(try!(serde_json::to_string(&map))))
+}
+
+impl PigtailClient {
+    pub fn new(base_url: &str, config: ClientConfig) -> PigtailClient {
+        PigtailClient { transport: Transport::new(base_url, config) }
+    }
+
+    // Items being worked on for longer than timeout_ms go back to pending
+    pub fn create_queue(&self, name: &str, timeout_ms: i64) -> ClientResult<()> {
+        let mut map = Map::new();
+        map.insert(String::from("name"), Value::String(String::from(name)));
+        map.insert(String::from("timeout_ms"), serde_json::to_value(&timeout_ms));
+        try!(self.transport.send(Method::Post,
+                                 "/create",
+                                 Some(try!(serde_json::to_string(&map))),
+                                 &[StatusCode::Created]));
+        Ok(())
+    }
+
+    pub fn delete_queue(&self, name: &str) -> ClientResult<()> {
+        try!(self.transport.send(Method::Delete, &format!("/queue/{}", name), None, &[StatusCode::Ok]));
+        Ok(())
+    }
+
+    // Adds a task to the queue, and gives back the new item's id
+    pub fn enqueue(&self, queue: &str, task_name: &str, info: &Value) -> ClientResult<Uuid> {
+        let mut map = Map::new();
+        map.insert(String::from("task_name"), Value::String(String::from(task_name)));
+        map.insert(String::from("info"), info.clone());
+        let reply = try!(self.transport.send(Method::Post,
+                                             &format!("/queue/{}", queue),
+                                             Some(try!(serde_json::to_string(&map))),
+                                             &[StatusCode::Created]));
+        // The redirect is to the new item, which ends with its id
+        let location = try!(reply.location
+            .ok_or(ClientError::BadResponse(String::from("No location for the new item"))));
+        let id = try!(location.rsplit('/')
+            .next()
+            .ok_or(ClientError::BadResponse(format!("No item id in {}", location))));
+        Ok(try!(Uuid::parse_str(id)))
+    }
+
+    // Everything in the queue that isn't done, by id
+    pub fn list(&self, queue: &str) -> ClientResult<HashMap<Uuid, QueueListItem>> {
+        let path = format!("/queue/{}", queue);
+        let reply = try!(self.transport.send(Method::Get, &path, None, &[StatusCode::Ok]));
+        let raw: HashMap<String, QueueListItem> = try!(serde_json::from_str(&reply.body));
+        let mut items = HashMap::new();
+        for (id, item) in raw {
+            items.insert(try!(Uuid::parse_str(&id)), item);
+        }
+        Ok(items)
+    }
+
+    pub fn get(&self, queue: &str, id: &Uuid) -> ClientResult<Option<QueueItem>> {
+        match self.transport.send(Method::Get, &format!("/queue/{}/{}", queue, id), None, &[StatusCode::Ok]) {
+            Ok(reply) => Ok(Some(try!(serde_json::from_str(&reply.body)))),
+            Err(ref err) if err.is_not_found() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Marks the item as being worked on by worker_id (which also keeps it from timing out)
+    pub fn progress(&self, queue: &str, id: &Uuid, worker_id: &Uuid) -> ClientResult<QueueItem> {
+        let reply = try!(self.transport.send(Method::Put,
+                                             &format!("/queue/{}/{}", queue, id),
+                                             try!(worker_body(worker_id)),
+                                             &[StatusCode::Ok]));
+        Ok(try!(serde_json::from_str(&reply.body)))
+    }
+
+    // Picks a pending item (of the given task, if there is one) and starts working on it. This
+    // is best-effort: two workers can claim the same item at once, and the later progress wins.
+    pub fn claim(&self,
+                 queue: &str,
+                 task_name: Option<&str>,
+                 worker_id: &Uuid)
+                 -> ClientResult<Option<(Uuid, QueueItem)>> {
+        for (id, item) in try!(self.list(queue)) {
+            if item.state != QueueState::Pending {
+                continue;
+            }
+            if let Some(task_name) = task_name {
+                if item.task_name != task_name {
+                    continue;
+                }
+            }
+            match self.progress(queue, &id, worker_id) {
+                Ok(item) => return Ok(Some((id, item))),
+                // Gone or taken since we listed it, so try the next one
+                Err(ref err) if err.is_not_found() || err.is_conflict() => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn done(&self, queue: &str, id: &Uuid, worker_id: &Uuid) -> ClientResult<()> {
+        try!(self.transport.send(Method::Delete,
+                                 &format!("/queue/{}/{}", queue, id),
+                                 try!(worker_body(worker_id)),
+                                 &[StatusCode::Ok]));
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+use serde_json;
+use uuid::Uuid;
+
+enum_str!(QueueState {
+    Pending("pending"),
+    Working("working"),
+    Done("done"),
+});
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueListItem {
+    pub task_name: String,
+    pub state: QueueState
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueItem {
+    pub task_name: String,
+    pub state: QueueState,
+    pub info: serde_json::Value,
+    pub worker: Option<Uuid>
+}
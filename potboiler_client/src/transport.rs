@@ -0,0 +1,208 @@
+use error::{ClientError, ClientResult};
+use hyper::client::{Client, RedirectPolicy};
+use hyper::header::{Authorization, Bearer, Location};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use potboiler_common::trace::{self, Kind, Span, TraceParent};
+use std::cmp;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    // For each attempt, reading and writing separately
+    pub timeout: Duration,
+    // How many more times to try after a retryable error (see ClientError::is_retryable)
+    pub retries: u32,
+    // Wait before the first retry, doubling for each one after that
+    pub retry_delay: Duration,
+    // Sent as "Authorization: Bearer <token>", for services with AUTH_CONFIG set
+    pub token: Option<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            token: None,
+        }
+    }
+}
+
+pub struct Reply {
+    pub status: StatusCode,
+    pub location: Option<String>,
+    pub body: String,
+}
+
+// Whether a failed request is worth sending again. Only conflicts are retried when asked to (as
+// they mean nothing was done), and otherwise only idempotent requests, for failures that might
+// go away.
+fn should_retry(method: &Method, err: &ClientError, retry_conflicts: bool) -> bool {
+    if err.is_conflict() {
+        return retry_conflicts;
+    }
+    let idempotent = match *method {
+        Method::Get | Method::Head | Method::Put | Method::Delete | Method::Options => true,
+        _ => false,
+    };
+    idempotent && err.is_retryable()
+}
+
+pub struct Transport {
+    base_url: String,
+    config: ClientConfig,
+    client: Client,
+}
+
+impl Transport {
+    pub fn new(base_url: &str, config: ClientConfig) -> Transport {
+        let mut client = Client::new();
+        client.set_read_timeout(Some(config.timeout));
+        client.set_write_timeout(Some(config.timeout));
+        // Appends answer with a redirect to the new entry, which we want to see, not follow
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+        Transport {
+            base_url: String::from(base_url.trim_right_matches('/')),
+            config: config,
+            client: client,
+        }
+    }
+
+    fn send_once(&self, method: &Method, path: &str, body: &Option<String>) -> ClientResult<Reply> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut builder = self.client.request(method.clone(), &url);
+        if let Some(ref body) = *body {
+            builder = builder.body(body.as_str());
+        }
+        if let Some(ref token) = self.config.token {
+            builder = builder.header(Authorization(Bearer { token: token.clone() }));
+        }
+        // Carrying on the trace of the request being handled, if there is one. Background requests
+        // (e.g. registering with core) aren't traced.
+        let span = trace::current()
+            .map(|parent| Span::start(format!("{} {}", method, path), Kind::Client, Some(&parent)));
+        if let Some(ref span) = span {
+            builder = builder.header(TraceParent(span.context().traceparent()));
+        }
+        let sent = builder.send();
+        if let Some(mut span) = span {
+            span.attribute("http.url", &url);
+            match sent {
+                Ok(ref res) => {
+                    span.attribute("http.status_code", res.status.to_u16());
+                    if res.status.is_server_error() {
+                        span.failed();
+                    }
+                }
+                Err(_) => span.failed(),
+            }
+            span.finish();
+        }
+        let mut res = try!(sent);
+        let mut text = String::new();
+        try!(res.read_to_string(&mut text));
+        Ok(Reply {
+            status: res.status,
+            location: res.headers.get::<Location>().map(|location| location.to_string()),
+            body: text,
+        })
+    }
+
+    // Sends the request, and fails unless the status is one of expected. GETs, PUTs and DELETEs
+    // are retried as configured when the service can't be reached or has trouble. POSTs aren't, as
+    // one that timed out may have gone through anyway, and sending it again would do it twice.
+    pub fn send(&self,
+                method: Method,
+                path: &str,
+                body: Option<String>,
+                expected: &[StatusCode])
+                -> ClientResult<Reply> {
+        self.send_with_retries(method, path, body, expected, false)
+    }
+
+    // As send, but also retries conflicts, which mean nothing was done (e.g. core appending when
+    // another append got in first, where trying again goes after that one)
+    pub fn send_retrying_conflicts(&self,
+                                   method: Method,
+                                   path: &str,
+                                   body: Option<String>,
+                                   expected: &[StatusCode])
+                                   -> ClientResult<Reply> {
+        self.send_with_retries(method, path, body, expected, true)
+    }
+
+    fn send_with_retries(&self,
+                         method: Method,
+                         path: &str,
+                         body: Option<String>,
+                         expected: &[StatusCode],
+                         retry_conflicts: bool)
+                         -> ClientResult<Reply> {
+        let mut attempt = 0;
+        loop {
+            let result = match self.send_once(&method, path, &body) {
+                Ok(ref reply) if !expected.contains(&reply.status) => {
+                    Err(ClientError::Status(reply.status, reply.body.clone()))
+                }
+                other => other,
+            };
+            match result {
+                Err(ref err) if attempt < self.config.retries &&
+                                should_retry(&method, err, retry_conflicts) => {
+                    let delay = self.config.retry_delay * (1 << cmp::min(attempt, 16));
+                    warn!("{} {}{} failed ({}), retrying in {:?}",
+                          method,
+                          self.base_url,
+                          path,
+                          err,
+                          delay);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::ClientError;
+    use hyper::method::Method;
+    use hyper::status::StatusCode;
+    use super::should_retry;
+
+    fn status(code: StatusCode) -> ClientError {
+        ClientError::Status(code, String::new())
+    }
+
+    #[test]
+    fn retries_idempotent_requests_that_might_work_next_time() {
+        let unreachable = ClientError::Transport(String::from("connection refused"));
+        for method in &[Method::Get, Method::Head, Method::Put, Method::Delete, Method::Options] {
+            assert!(should_retry(method, &unreachable, false));
+            assert!(should_retry(method, &status(StatusCode::ServiceUnavailable), false));
+            assert!(!should_retry(method, &status(StatusCode::BadRequest), false));
+            assert!(!should_retry(method, &status(StatusCode::NotFound), false));
+            assert!(!should_retry(method, &ClientError::BadResponse(String::from("not JSON")), false));
+        }
+    }
+
+    #[test]
+    fn never_retries_posts_that_may_have_gone_through() {
+        assert!(!should_retry(&Method::Post, &ClientError::Transport(String::from("timed out")), false));
+        assert!(!should_retry(&Method::Post, &status(StatusCode::InternalServerError), false));
+        assert!(!should_retry(&Method::Post, &status(StatusCode::InternalServerError), true));
+    }
+
+    #[test]
+    fn only_retries_conflicts_when_asked() {
+        assert!(should_retry(&Method::Post, &status(StatusCode::Conflict), true));
+        assert!(!should_retry(&Method::Post, &status(StatusCode::Conflict), false));
+        assert!(!should_retry(&Method::Put, &status(StatusCode::Conflict), false));
+    }
+}
@@ -0,0 +1,356 @@
+use config;
+use get_req_key;
+use iron::{BeforeMiddleware, Chain, Handler, IronError, IronResult, Request};
+use iron::headers::{Authorization, Bearer};
+use iron::status;
+use iron::typemap::Key;
+use openssl::crypto::hash::Type as HashType;
+use openssl::crypto::hmac::hmac;
+use rustc_serialize::base64::FromBase64;
+use serde_json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use string_error::StringError;
+
+// Clients authenticate with "Authorization: Bearer <token>", where the token is either one of
+// the API keys from the file named by auth_config, or an HS256 JWT signed with its jwt_secret.
+// The file looks like
+//   {"api_keys": {"<key>": ["log:read", "log:write"]}, "jwt_secret": "<secret>"}
+// and JWTs carry their scopes as a space-separated "scope" claim. Scopes are colon-separated,
+// and a "*" segment in a granted scope matches anything (e.g. "kv:table:*:write"). Without
+// auth_config everything is allowed, as before.
+
+pub struct AuthConfig {
+    api_keys: HashMap<String, Vec<String>>,
+    jwt_secret: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Access {
+    // Auth is switched off
+    Everything,
+    // No usable credentials
+    Anonymous,
+    Scopes(Vec<String>),
+}
+
+#[derive(Copy, Clone)]
+pub struct Auth;
+
+impl Key for Auth {
+    type Value = Access;
+}
+
+pub struct AuthMiddleware {
+    config: Option<Arc<AuthConfig>>,
+}
+
+fn read_config(path: &str) -> Result<AuthConfig, StringError> {
+    let mut contents = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut contents));
+    let json: serde_json::Value = try!(serde_json::from_str(&contents));
+    let mut api_keys = HashMap::new();
+    if let Some(keys) = json.find("api_keys") {
+        let keys = try!(keys.as_object().ok_or(StringError::from("api_keys needs to be an object")));
+        for (key, scopes) in keys {
+            let scopes = try!(scopes.as_array()
+                .ok_or(StringError::from(format!("Scopes for API key {} need to be a list", key))));
+            let mut names = Vec::new();
+            for scope in scopes {
+                names.push(String::from(try!(scope.as_str()
+                    .ok_or(StringError::from("Scopes need to be strings")))));
+            }
+            api_keys.insert(key.clone(), names);
+        }
+    }
+    let jwt_secret = json.find("jwt_secret").and_then(|x| x.as_str()).map(String::from);
+    Ok(AuthConfig {
+        api_keys: api_keys,
+        jwt_secret: jwt_secret,
+    })
+}
+
+pub fn init_auth() -> AuthMiddleware {
+    let config = match config::current().auth_config {
+        Some(ref path) => {
+            let config = read_config(path).expect(&format!("Couldn't load auth_config from {}", path));
+            info!("Loaded {} API keys from {}", config.api_keys.len(), path);
+            Some(Arc::new(config))
+        }
+        None => {
+            warn!("No auth_config set, so not checking client credentials");
+            None
+        }
+    };
+    AuthMiddleware { config: config }
+}
+
+// For comparing secrets without the time taken giving away how much of them matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn decode_part(part: &str) -> Result<Vec<u8>, StringError> {
+    part.from_base64().map_err(|err| StringError::from(format!("Bad base64 in JWT: {:?}", err)))
+}
+
+fn decode_json(part: &str) -> Result<serde_json::Value, StringError> {
+    let bytes = try!(decode_part(part));
+    let text = try!(String::from_utf8(bytes).map_err(|_| StringError::from("JWT isn't UTF-8")));
+    Ok(try!(serde_json::from_str(&text)))
+}
+
+fn jwt_scopes(token: &str, secret: &str) -> Result<Vec<String>, StringError> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(StringError::from("Not a JWT"));
+    }
+    let header = try!(decode_json(parts[0]));
+    if header.find("alg").and_then(|x| x.as_str()) != Some("HS256") {
+        return Err(StringError::from("Only HS256 JWTs are supported"));
+    }
+    let signed = format!("{}.{}", parts[0], parts[1]);
+    let expected = hmac(HashType::SHA256, secret.as_bytes(), signed.as_bytes());
+    if !constant_time_eq(&expected, &try!(decode_part(parts[2]))) {
+        return Err(StringError::from("Bad JWT signature"));
+    }
+    let claims = try!(decode_json(parts[1]));
+    if let Some(exp) = claims.find("exp").and_then(|x| x.as_u64()) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+        if now >= exp {
+            return Err(StringError::from("JWT has expired"));
+        }
+    }
+    Ok(match claims.find("scope").and_then(|x| x.as_str()) {
+        Some(scope) => scope.split_whitespace().map(String::from).collect(),
+        None => Vec::new(),
+    })
+}
+
+impl AuthConfig {
+    fn access(&self, token: &str) -> Access {
+        if let Some(scopes) = self.api_keys.get(token) {
+            return Access::Scopes(scopes.clone());
+        }
+        if let Some(ref secret) = self.jwt_secret {
+            match jwt_scopes(token, secret) {
+                Ok(scopes) => return Access::Scopes(scopes),
+                Err(err) => debug!("Rejected bearer token: {}", err),
+            }
+        }
+        Access::Anonymous
+    }
+}
+
+impl BeforeMiddleware for AuthMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let access = match self.config {
+            None => Access::Everything,
+            Some(ref config) => {
+                match req.headers.get::<Authorization<Bearer>>() {
+                    Some(&Authorization(Bearer { ref token })) => config.access(token),
+                    None => Access::Anonymous,
+                }
+            }
+        };
+        req.extensions.insert::<Auth>(access);
+        Ok(())
+    }
+}
+
+pub fn scope_matches(granted: &str, required: &str) -> bool {
+    if granted == "*" {
+        return true;
+    }
+    let granted: Vec<&str> = granted.split(':').collect();
+    let required: Vec<&str> = required.split(':').collect();
+    granted.len() == required.len() &&
+    granted.iter().zip(required.iter()).all(|(g, r)| *g == "*" || g == r)
+}
+
+pub fn has_scope(req: &Request, scope: &str) -> bool {
+    match req.extensions.get::<Auth>() {
+        Some(&Access::Everything) => true,
+        Some(&Access::Scopes(ref scopes)) => scopes.iter().any(|granted| scope_matches(granted, scope)),
+        Some(&Access::Anonymous) => false,
+        // No AuthMiddleware in the chain, so we don't know who it is
+        None => false,
+    }
+}
+
+pub fn require_scope(req: &Request, scope: &str) -> IronResult<()> {
+    if has_scope(req, scope) {
+        return Ok(());
+    }
+    let error = StringError::from(format!("Missing scope {}", scope));
+    match req.extensions.get::<Auth>() {
+        Some(&Access::Anonymous) => {
+            Err(IronError::new(error, (status::Unauthorized, "Need a valid API key or token")))
+        }
+        // A route was set up without init_auth, which is our mistake rather than the caller's
+        None => {
+            Err(IronError::new(StringError::from(format!("No AuthMiddleware to check scope {}", scope)),
+                               (status::InternalServerError, "Auth isn't set up for this route")))
+        }
+        _ => Err(IronError::new(error, (status::Forbidden, format!("Need the {} scope", scope)))),
+    }
+}
+
+// Fills in {name} parts of the scope from the route's parameters. None if a parameter has a ":"
+// in it, as that would add segments to the scope.
+fn fill_scope<F: Fn(&str) -> Option<String>>(template: &str, param: F) -> Option<String> {
+    let mut scope = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(val) => start + val,
+            None => break,
+        };
+        scope.push_str(&rest[..start]);
+        let value = param(&rest[start + 1..end]).unwrap_or(String::new());
+        if value.contains(':') {
+            return None;
+        }
+        scope.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    scope.push_str(rest);
+    Some(scope)
+}
+
+pub struct RequireScope {
+    template: String,
+}
+
+impl BeforeMiddleware for RequireScope {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match fill_scope(&self.template, |name| get_req_key(req, name)) {
+            Some(scope) => require_scope(req, &scope),
+            None => {
+                let msg = "Names in the path can't contain ':'";
+                Err(IronError::new(StringError::from(msg), (status::BadRequest, msg)))
+            }
+        }
+    }
+}
+
+// Wraps a handler so it needs the given scope, e.g. with_scope("queue:{queue_name}:work", handler)
+pub fn with_scope<H: Handler>(template: &str, handler: H) -> Chain {
+    let mut chain = Chain::new(handler);
+    chain.link_before(RequireScope { template: String::from(template) });
+    chain
+}
+
+// Credentials for services to send when calling core, from core_api_key
+pub fn core_token() -> Option<String> {
+    config::current().core_api_key
+}
+
+pub fn core_credentials() -> Option<Authorization<Bearer>> {
+    core_token().map(|token| Authorization(Bearer { token: token }))
+}
+
+// Token a service asks core to send with its notifications, from event_token. It needs to be
+// one of the service's own API keys, with the scope for its /event route.
+pub fn event_token() -> Option<String> {
+    config::current().event_token
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::crypto::hash::Type as HashType;
+    use openssl::crypto::hmac::hmac;
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+    use std::collections::HashMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use super::{fill_scope, jwt_scopes, scope_matches};
+
+    fn jwt(header: &str, claims: &str, secret: &str) -> String {
+        let signed = format!("{}.{}",
+                             header.as_bytes().to_base64(URL_SAFE),
+                             claims.as_bytes().to_base64(URL_SAFE));
+        let signature = hmac(HashType::SHA256, secret.as_bytes(), signed.as_bytes());
+        format!("{}.{}", signed, signature.to_base64(URL_SAFE))
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    const HS256: &'static str = r#"{"alg": "HS256", "typ": "JWT"}"#;
+
+    #[test]
+    fn reads_scopes_from_a_signed_jwt() {
+        let claims = format!(r#"{{"scope": "log:read log:write", "exp": {}}}"#, now() + 60);
+        assert_eq!(jwt_scopes(&jwt(HS256, &claims, "secret"), "secret").unwrap(),
+                   vec![String::from("log:read"), String::from("log:write")]);
+        assert!(jwt_scopes(&jwt(HS256, "{}", "secret"), "secret").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_a_jwt_signed_with_another_secret() {
+        let token = jwt(HS256, r#"{"scope": "log:read"}"#, "other");
+        assert!(jwt_scopes(&token, "secret").is_err());
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let token = jwt(r#"{"alg": "none"}"#, r#"{"scope": "log:read"}"#, "secret");
+        assert!(jwt_scopes(&token, "secret").is_err());
+        let token = jwt(r#"{"alg": "HS512"}"#, r#"{"scope": "log:read"}"#, "secret");
+        assert!(jwt_scopes(&token, "secret").is_err());
+    }
+
+    #[test]
+    fn rejects_expired_jwts() {
+        let claims = format!(r#"{{"scope": "log:read", "exp": {}}}"#, now() - 1);
+        assert!(jwt_scopes(&jwt(HS256, &claims, "secret"), "secret").is_err());
+    }
+
+    #[test]
+    fn rejects_things_that_arent_jwts() {
+        assert!(jwt_scopes("an-api-key", "secret").is_err());
+        assert!(jwt_scopes("a.b.c", "secret").is_err());
+    }
+
+    #[test]
+    fn matches_scopes_by_segment() {
+        assert!(scope_matches("log:read", "log:read"));
+        assert!(!scope_matches("log:read", "log:write"));
+        assert!(scope_matches("*", "kv:table:users:write"));
+        assert!(scope_matches("kv:table:*:write", "kv:table:users:write"));
+        assert!(!scope_matches("kv:table:*:write", "kv:table:users:read"));
+        assert!(scope_matches("queue:*:*", "queue:jobs:work"));
+    }
+
+    #[test]
+    fn wildcards_only_cover_one_segment() {
+        assert!(!scope_matches("kv:*", "kv:table:users:write"));
+        assert!(!scope_matches("kv:table:*:write", "kv:table:write"));
+        assert!(!scope_matches("log", "log:read"));
+    }
+
+    #[test]
+    fn fills_in_route_parameters() {
+        let mut params = HashMap::new();
+        params.insert("table", String::from("users"));
+        let param = |name: &str| params.get(name).cloned();
+        assert_eq!(fill_scope("kv:table:{table}:write", &param),
+                   Some(String::from("kv:table:users:write")));
+        assert_eq!(fill_scope("kv:table:{missing}:write", &param),
+                   Some(String::from("kv:table::write")));
+        assert_eq!(fill_scope("log:read", &param), Some(String::from("log:read")));
+    }
+
+    #[test]
+    fn refuses_parameters_that_would_add_segments() {
+        let param = |_: &str| Some(String::from("users:write"));
+        assert_eq!(fill_scope("kv:table:{table}:read", param), None);
+    }
+}
@@ -1,4 +1,6 @@
 use iron::typemap::Key;
+use health;
+use metrics::{self, Gauge};
 use r2d2;
 use r2d2_postgres::{PostgresConnectionManager, SslMode};
 
@@ -28,7 +30,33 @@ macro_rules! get_pg_connection {
     })
 }
 
-pub fn get_pool(uri: &str) -> PostgresPool {
+const POOL_CONNECTIONS: Gauge = Gauge {
+    name: "potboiler_db_pool_connections",
+    help: "Postgres connections in the pool, by whether they're in use",
+};
+
+const POOL_SIZE: Gauge = Gauge {
+    name: "potboiler_db_pool_size",
+    help: "Most Postgres connections the pool will open",
+};
+
+pub fn get_pool(uri: &str, size: u32) -> PostgresPool {
     let manager = PostgresConnectionManager::new(uri, SslMode::None).expect("Needed a working DATABASE_URL");
-    r2d2::Pool::new(r2d2::Config::default(), manager).unwrap()
+    let config = r2d2::Config::builder().pool_size(size).build();
+    let pool = r2d2::Pool::new(config, manager).unwrap();
+    let watched = pool.clone();
+    metrics::add_collector(move || {
+        let state = watched.state();
+        POOL_CONNECTIONS.set(&[("state", "idle")], state.idle_connections as f64);
+        POOL_CONNECTIONS.set(&[("state", "busy")],
+                             (state.connections - state.idle_connections) as f64);
+        POOL_SIZE.set(&[], watched.config().pool_size() as f64);
+    });
+    let probed = pool.clone();
+    health::add_probe("postgres", move || {
+        let conn = try!(probed.get().map_err(|err| format!("Can't get a connection: {:?}", err)));
+        try!(conn.execute("SELECT 1", &[]).map_err(|err| format!("Can't query: {}", err)));
+        Ok(())
+    });
+    pool
 }
@@ -0,0 +1,263 @@
+use iron::{AfterMiddleware, IronError, IronResult, Request, Response, Set};
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+use iron::response::ResponseBody;
+use iron::status::{self, Status};
+use postgres;
+use r2d2;
+use serde_json::{self, Map, Value};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use string_error::StringError;
+use uuid;
+
+// What went wrong handling a request, in a way that maps onto an HTTP status. Error responses
+// from every service have a body of {"error": {"code": "<code>", "message": "<message>"}}, where
+// the code is one of the ones below.
+#[derive(Debug)]
+pub enum ServiceError {
+    // The request was malformed or asked for something impossible
+    BadRequest(String),
+    NotFound(String),
+    // Something else got there first, e.g. a concurrent append
+    Conflict(String),
+    // Another service we rely on (e.g. core, for kv and pigtail) failed or couldn't be reached
+    Upstream(String),
+    // Our own database failed
+    Storage(String),
+}
+
+impl ServiceError {
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ServiceError::BadRequest(_) => "bad_request",
+            ServiceError::NotFound(_) => "not_found",
+            ServiceError::Conflict(_) => "conflict",
+            ServiceError::Upstream(_) => "upstream_failure",
+            ServiceError::Storage(_) => "storage_failure",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match *self {
+            ServiceError::BadRequest(_) => status::BadRequest,
+            ServiceError::NotFound(_) => status::NotFound,
+            ServiceError::Conflict(_) => status::Conflict,
+            ServiceError::Upstream(_) => status::BadGateway,
+            ServiceError::Storage(_) => status::InternalServerError,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match *self {
+            ServiceError::BadRequest(ref msg) |
+            ServiceError::NotFound(ref msg) |
+            ServiceError::Conflict(ref msg) |
+            ServiceError::Upstream(ref msg) |
+            ServiceError::Storage(ref msg) => msg.as_str(),
+        }
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl Error for ServiceError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
+
+fn code_for_status(status: Status) -> &'static str {
+    match status {
+        status::NotFound => "not_found",
+        status::Conflict => "conflict",
+        status::BadGateway | status::GatewayTimeout => "upstream_failure",
+        status::ServiceUnavailable => "unavailable",
+        status::Unauthorized => "unauthorized",
+        status::Forbidden => "forbidden",
+        _ => if status.is_server_error() { "storage_failure" } else { "bad_request" },
+    }
+}
+
+pub fn error_body(code: &str, message: &str) -> String {
+    let mut error = Map::new();
+    error.insert(String::from("code"), Value::String(String::from(code)));
+    error.insert(String::from("message"), Value::String(String::from(message)));
+    let mut body = Map::new();
+    body.insert(String::from("error"), Value::Object(error));
+    serde_json::to_string(&body).unwrap()
+}
+
+impl From<ServiceError> for IronError {
+    fn from(err: ServiceError) -> IronError {
+        let body = error_body(err.code(), err.message());
+        let status = err.status();
+        IronError::new(err, (status, Header(ContentType::json()), body))
+    }
+}
+
+// StringError is what the storage code gives back, so it's taken as our own failure. Problems
+// with the request need to be turned into a BadRequest where they're found.
+impl From<StringError> for ServiceError {
+    fn from(err: StringError) -> ServiceError {
+        ServiceError::Storage(err.0)
+    }
+}
+
+impl From<serde_json::Error> for ServiceError {
+    fn from(err: serde_json::Error) -> ServiceError {
+        ServiceError::BadRequest(format!("Bad JSON: {}", err))
+    }
+}
+
+impl From<uuid::ParseError> for ServiceError {
+    fn from(err: uuid::ParseError) -> ServiceError {
+        ServiceError::BadRequest(format!("Bad UUID: {}", err))
+    }
+}
+
+impl From<io::Error> for ServiceError {
+    fn from(err: io::Error) -> ServiceError {
+        ServiceError::BadRequest(format!("Couldn't read request: {}", err))
+    }
+}
+
+impl From<postgres::error::Error> for ServiceError {
+    fn from(err: postgres::error::Error) -> ServiceError {
+        ServiceError::Storage(format!("Database error: {}", err))
+    }
+}
+
+impl From<r2d2::GetTimeout> for ServiceError {
+    fn from(err: r2d2::GetTimeout) -> ServiceError {
+        ServiceError::Storage(format!("Couldn't get a database connection: {}", err))
+    }
+}
+
+fn is_json(res: &Response) -> bool {
+    match res.headers.get::<ContentType>() {
+        Some(content_type) => *content_type == ContentType::json(),
+        None => false,
+    }
+}
+
+fn body_text(res: &mut Response) -> String {
+    let mut bytes: Vec<u8> = Vec::new();
+    if let Some(mut body) = res.body.take() {
+        if let Err(err) = body.write_body(&mut ResponseBody::new(&mut bytes)) {
+            warn!("Couldn't read error body: {:?}", err);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Turns the plain text error responses handlers give back into the JSON error bodies, so
+// clients see the same format whatever went wrong. Link it after everything else that might
+// produce an error (but before the logger, so that logs the final response).
+pub struct JsonErrors;
+
+impl JsonErrors {
+    fn rewrite(&self, mut res: Response, fallback: Option<String>) -> Response {
+        let status = match res.status {
+            Some(status) if status.is_client_error() || status.is_server_error() => status,
+            _ => return res,
+        };
+        if is_json(&res) {
+            return res;
+        }
+        let mut message = body_text(&mut res);
+        if message.is_empty() {
+            message = fallback.unwrap_or(String::from(status.canonical_reason().unwrap_or("Error")));
+        }
+        res.set_mut((status, Header(ContentType::json()), error_body(code_for_status(status), &message)));
+        res
+    }
+}
+
+impl AfterMiddleware for JsonErrors {
+    fn after(&self, _: &mut Request, res: Response) -> IronResult<Response> {
+        Ok(self.rewrite(res, None))
+    }
+
+    fn catch(&self, _: &mut Request, err: IronError) -> IronResult<Response> {
+        let description = String::from(err.error.description());
+        let mut res = err.response;
+        if res.status.is_none() {
+            res.status = Some(status::InternalServerError);
+        }
+        Ok(self.rewrite(res, Some(description)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::{IronError, Response};
+    use iron::headers::ContentType;
+    use iron::status;
+    use serde_json::{self, Value};
+    use string_error::StringError;
+    use super::{JsonErrors, ServiceError, body_text};
+
+    // The code and message from a rewritten response
+    fn error_of(res: Response) -> (String, String) {
+        let mut res = res;
+        assert_eq!(res.headers.get::<ContentType>(), Some(&ContentType::json()));
+        let body: Value = serde_json::from_str(&body_text(&mut res)).unwrap();
+        let error = body.find("error").unwrap();
+        (String::from(error.find("code").unwrap().as_str().unwrap()),
+         String::from(error.find("message").unwrap().as_str().unwrap()))
+    }
+
+    #[test]
+    fn each_error_has_its_own_code_and_status() {
+        let errors = [(ServiceError::BadRequest(String::new()), "bad_request", status::BadRequest),
+                      (ServiceError::NotFound(String::new()), "not_found", status::NotFound),
+                      (ServiceError::Conflict(String::new()), "conflict", status::Conflict),
+                      (ServiceError::Upstream(String::new()), "upstream_failure", status::BadGateway),
+                      (ServiceError::Storage(String::new()), "storage_failure", status::InternalServerError)];
+        for &(ref err, code, status) in &errors {
+            assert_eq!(err.code(), code);
+            assert_eq!(err.status(), status);
+        }
+    }
+
+    #[test]
+    fn string_errors_are_storage_failures() {
+        let err = ServiceError::from(StringError::from("connection refused"));
+        assert_eq!(err.code(), "storage_failure");
+        assert_eq!(err.message(), "connection refused");
+    }
+
+    #[test]
+    fn plain_text_errors_become_json() {
+        let res = JsonErrors.rewrite(Response::with((status::NotFound, "No table users")), None);
+        assert_eq!(res.status, Some(status::NotFound));
+        assert_eq!(error_of(res),
+                   (String::from("not_found"), String::from("No table users")));
+    }
+
+    #[test]
+    fn empty_errors_get_a_message() {
+        let res = JsonErrors.rewrite(Response::with(status::ServiceUnavailable), None);
+        assert_eq!(error_of(res),
+                   (String::from("unavailable"), String::from("Service Unavailable")));
+        let res = JsonErrors.rewrite(Response::with(status::InternalServerError),
+                                     Some(String::from("Pool timed out")));
+        assert_eq!(error_of(res),
+                   (String::from("storage_failure"), String::from("Pool timed out")));
+    }
+
+    #[test]
+    fn leaves_successes_and_json_alone() {
+        let res = JsonErrors.rewrite(Response::with((status::Ok, "fine")), None);
+        assert_eq!(res.headers.get::<ContentType>(), None);
+        let err = IronError::from(ServiceError::Conflict(String::from("Raced")));
+        let res = JsonErrors.rewrite(err.response, None);
+        assert_eq!(error_of(res), (String::from("conflict"), String::from("Raced")));
+    }
+}
@@ -0,0 +1,94 @@
+use iron::{IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+use iron::status;
+use serde_json::{self, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// What GET /health and GET /ready report. /health only says the process is up and serving. /ready
+// runs each of the checks, and is a 503 until they've all passed. A check is either a probe run
+// on every request (e.g. can we reach Postgres), or a step the service marks as done once it's
+// done it (e.g. registering with core), which starts off pending.
+
+type Probe = Arc<Fn() -> Result<(), String> + Send + Sync>;
+
+enum Check {
+    Probe(Probe),
+    Step(Result<(), String>),
+}
+
+lazy_static! {
+    static ref CHECKS: Mutex<BTreeMap<&'static str, Check>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn add_probe<F: Fn() -> Result<(), String> + Send + Sync + 'static>(name: &'static str, probe: F) {
+    CHECKS.lock().unwrap().insert(name, Check::Probe(Arc::new(probe)));
+}
+
+// A step that has to happen before the service is ready, with why it hasn't yet
+pub fn pending(name: &'static str, message: &str) {
+    CHECKS.lock().unwrap().insert(name, Check::Step(Err(String::from(message))));
+}
+
+pub fn passed(name: &'static str) {
+    CHECKS.lock().unwrap().insert(name, Check::Step(Ok(())));
+}
+
+fn json_response(status: status::Status, body: Value) -> Response {
+    Response::with((status, Header(ContentType::json()), serde_json::to_string(&body).unwrap()))
+}
+
+// The GET /health handler
+pub fn live(_: &mut Request) -> IronResult<Response> {
+    let mut body = Map::new();
+    body.insert(String::from("status"), Value::String(String::from("ok")));
+    Ok(json_response(status::Ok, Value::Object(body)))
+}
+
+// Runs all the checks, giving whether they all passed and what each one said. Probes can be slow
+// (e.g. waiting for a database connection), so they're run without holding on to the checks.
+pub fn run_checks() -> (bool, Map<String, Value>) {
+    let checks: Vec<(&'static str, Check)> = CHECKS.lock()
+        .unwrap()
+        .iter()
+        .map(|(name, check)| {
+            let copy = match *check {
+                Check::Probe(ref probe) => Check::Probe(probe.clone()),
+                Check::Step(ref result) => Check::Step(result.clone()),
+            };
+            (*name, copy)
+        })
+        .collect();
+    let mut all_ok = true;
+    let mut results = Map::new();
+    for (name, check) in checks {
+        let result = match check {
+            Check::Probe(probe) => probe(),
+            Check::Step(result) => result,
+        };
+        let mut entry = Map::new();
+        entry.insert(String::from("ok"), Value::Bool(result.is_ok()));
+        if let Err(message) = result {
+            all_ok = false;
+            entry.insert(String::from("message"), Value::String(message));
+        }
+        results.insert(String::from(name), Value::Object(entry));
+    }
+    (all_ok, results)
+}
+
+// The GET /ready handler
+pub fn ready(_: &mut Request) -> IronResult<Response> {
+    let (all_ok, checks) = run_checks();
+    let mut body = Map::new();
+    body.insert(String::from("status"),
+                Value::String(String::from(if all_ok { "ready" } else { "not_ready" })));
+    body.insert(String::from("checks"), Value::Object(checks));
+    let status = if all_ok {
+        status::Ok
+    } else {
+        status::ServiceUnavailable
+    };
+    Ok(json_response(status, Value::Object(body)))
+}
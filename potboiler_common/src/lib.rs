@@ -1,3 +1,5 @@
+#[macro_use]
+extern crate hyper;
 extern crate iron;
 extern crate r2d2;
 extern crate r2d2_postgres;
@@ -8,29 +10,43 @@ extern crate postgres;
 extern crate serde_json;
 extern crate hybrid_clocks;
 extern crate router;
+extern crate openssl;
+extern crate rustc_serialize;
+extern crate toml;
+extern crate chan;
+extern crate chan_signal;
+#[macro_use]
+extern crate lazy_static;
 
+pub mod auth;
+pub mod config;
 pub mod db;
+pub mod error;
+pub mod health;
+pub mod metrics;
 pub mod server_id;
+pub mod shutdown;
 pub mod string_error;
+pub mod trace;
 pub mod types;
 pub mod clock;
 
 use hybrid_clocks::{Timestamp, WallT};
+use error::ServiceError;
+use iron::headers::ContentType;
+use iron::modifiers::Header;
 use iron::prelude::{IronError, Request};
 use iron::status;
 use std::io::Read;
 
-pub fn url_from_body(req: &mut Request) -> Result<Option<String>, IronError> {
-    let body_string = {
-        let mut body = String::new();
-        req.body.read_to_string(&mut body).expect("could read from body");
-        body
-    };
-    let json: serde_json::Value = match serde_json::de::from_str(&body_string) {
-        Ok(val) => val,
-        Err(err) => return Err(IronError::new(err, (status::BadRequest, "Bad JSON"))),
-    };
-    Ok(Some(String::from(json.find("url").unwrap().as_str().unwrap())))
+pub fn url_from_body(req: &mut Request) -> Result<String, ServiceError> {
+    let mut body = String::new();
+    try!(req.body.read_to_string(&mut body));
+    let json: serde_json::Value = try!(serde_json::de::from_str(&body));
+    json.find("url")
+        .and_then(|url| url.as_str())
+        .map(String::from)
+        .ok_or(ServiceError::BadRequest(String::from("Need a \"url\" string")))
 }
 
 pub fn get_req_key<T: Into<String>>(req: &Request, key: T) -> Option<String> {
@@ -47,7 +63,21 @@ pub fn get_raw_timestamp(timestamp: &Timestamp<WallT>) -> Vec<u8> {
     return raw_timestamp;
 }
 
+// A 400, unless it's our own database that failed, which is a 500
 pub fn iron_str_error<T: std::error::Error + std::marker::Send + 'static>(se: T) -> iron::IronError {
-    let desc = format!("{:?}", se);
-    return IronError::new(se, (status::BadRequest, desc));
+    let storage = {
+        let err: &std::error::Error = &se;
+        err.is::<postgres::error::Error>() || err.is::<r2d2::GetTimeout>()
+    };
+    if storage {
+        return iron_storage_error(se);
+    }
+    let body = error::error_body("bad_request", &format!("{}", se));
+    return IronError::new(se, (status::BadRequest, Header(ContentType::json()), body));
+}
+
+// For errors from code that talks to the database, which don't say whether that's what failed
+pub fn iron_storage_error<T: std::error::Error + std::marker::Send + 'static>(se: T) -> iron::IronError {
+    let body = error::error_body("storage_failure", &format!("{}", se));
+    return IronError::new(se, (status::InternalServerError, Header(ContentType::json()), body));
 }
\ No newline at end of file
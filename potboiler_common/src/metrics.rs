@@ -0,0 +1,310 @@
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+use iron::status;
+use iron::typemap::Key;
+use router::Router;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Prometheus metrics, kept in-process and served as text from GET /metrics. Each service
+// declares its metrics as consts (e.g. REQUESTS below) and bumps them with label values; a
+// collector can also fill in gauges (e.g. pool or queue sizes) just before they're served.
+
+pub type Labels<'a> = &'a [(&'a str, &'a str)];
+
+pub struct Counter {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+pub struct Histogram {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+// In seconds, and the same for every histogram
+const BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+enum Series {
+    Value(f64),
+    Histogram {
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    // Rendered labels -> series
+    series: BTreeMap<String, Series>,
+}
+
+type Collector = Box<Fn() + Send>;
+
+lazy_static! {
+    static ref FAMILIES: Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+    static ref COLLECTORS: Mutex<Vec<Collector>> = Mutex::new(Vec::new());
+}
+
+pub const REQUESTS: Counter = Counter {
+    name: "potboiler_http_requests_total",
+    help: "HTTP requests handled, by route, method and status",
+};
+
+pub const REQUEST_SECONDS: Histogram = Histogram {
+    name: "potboiler_http_request_duration_seconds",
+    help: "How long HTTP requests took to handle, by route and method",
+};
+
+// For labels whose values come from users (like stream names), so they can't make the metrics grow
+// without limit. The first max values seen get series of their own, and everything after that is
+// counted together as "other".
+pub struct BoundedLabel {
+    max: usize,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl BoundedLabel {
+    pub fn new(max: usize) -> BoundedLabel {
+        BoundedLabel {
+            max: max,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn value<'a>(&self, value: &'a str) -> &'a str {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.contains(value) {
+            value
+        } else if seen.len() < self.max {
+            seen.insert(String::from(value));
+            value
+        } else {
+            "other"
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_labels(labels: Labels) -> String {
+    let parts: Vec<String> = labels.iter()
+        .map(|&(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    parts.join(",")
+}
+
+fn update<F>(name: &'static str, help: &'static str, kind: &'static str, labels: Labels, f: F)
+    where F: FnOnce(&mut Series)
+{
+    let mut families = FAMILIES.lock().unwrap();
+    let family = families.entry(name).or_insert_with(|| {
+        Family {
+            help: help,
+            kind: kind,
+            series: BTreeMap::new(),
+        }
+    });
+    let series = family.series.entry(render_labels(labels)).or_insert_with(|| {
+        if kind == "histogram" {
+            Series::Histogram {
+                counts: vec![0; BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            }
+        } else {
+            Series::Value(0.0)
+        }
+    });
+    f(series);
+}
+
+impl Counter {
+    pub fn inc(&self, labels: Labels) {
+        self.add(labels, 1.0);
+    }
+
+    pub fn add(&self, labels: Labels, amount: f64) {
+        update(self.name, self.help, "counter", labels, |series| {
+            if let Series::Value(ref mut value) = *series {
+                *value += amount;
+            }
+        });
+    }
+}
+
+impl Gauge {
+    pub fn set(&self, labels: Labels, amount: f64) {
+        update(self.name, self.help, "gauge", labels, |series| {
+            if let Series::Value(ref mut value) = *series {
+                *value = amount;
+            }
+        });
+    }
+
+    // Drops all the series, for gauges whose label values come and go (e.g. per-queue ones), so
+    // a collector can set the current ones from scratch
+    pub fn reset(&self) {
+        if let Some(family) = FAMILIES.lock().unwrap().get_mut(self.name) {
+            family.series.clear();
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, labels: Labels, amount: f64) {
+        update(self.name, self.help, "histogram", labels, |series| {
+            if let Series::Histogram { ref mut counts, ref mut sum, ref mut count } = *series {
+                for (bucket, bucket_count) in BUCKETS.iter().zip(counts.iter_mut()) {
+                    if amount <= *bucket {
+                        *bucket_count += 1;
+                    }
+                }
+                *sum += amount;
+                *count += 1;
+            }
+        });
+    }
+}
+
+pub fn seconds_since(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+// Runs just before the metrics are served
+pub fn add_collector<F: Fn() + Send + 'static>(collector: F) {
+    COLLECTORS.lock().unwrap().push(Box::new(collector));
+}
+
+fn with_label(labels: &str, name: &str, value: &str) -> String {
+    if labels.is_empty() {
+        format!("{{{}=\"{}\"}}", name, value)
+    } else {
+        format!("{{{},{}=\"{}\"}}", labels, name, value)
+    }
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+// Everything in the Prometheus text format
+pub fn render() -> String {
+    for collector in COLLECTORS.lock().unwrap().iter() {
+        collector();
+    }
+    let families = FAMILIES.lock().unwrap();
+    let mut out = String::new();
+    for (name, family) in families.iter() {
+        writeln!(out, "# HELP {} {}", name, family.help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, family.kind).unwrap();
+        for (labels, series) in &family.series {
+            match *series {
+                Series::Value(value) => writeln!(out, "{}{} {}", name, braced(labels), value).unwrap(),
+                Series::Histogram { ref counts, sum, count } => {
+                    for (bucket, bucket_count) in BUCKETS.iter().zip(counts.iter()) {
+                        writeln!(out,
+                                 "{}_bucket{} {}",
+                                 name,
+                                 with_label(labels, "le", &bucket.to_string()),
+                                 bucket_count)
+                            .unwrap();
+                    }
+                    writeln!(out, "{}_bucket{} {}", name, with_label(labels, "le", "+Inf"), count).unwrap();
+                    writeln!(out, "{}_sum{} {}", name, braced(labels), sum).unwrap();
+                    writeln!(out, "{}_count{} {}", name, braced(labels), count).unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+// The GET /metrics handler
+pub fn serve(_: &mut Request) -> IronResult<Response> {
+    let content_type = ContentType("text/plain; version=0.0.4".parse().unwrap());
+    Ok(Response::with((status::Ok, Header(content_type), render())))
+}
+
+// The route pattern the router matched (e.g. /kv/:table/:key), so each route is one series
+// whatever the values in it. Requests that didn't match a route are lumped together.
+pub fn route(req: &Request) -> String {
+    let params = match req.extensions.get::<Router>() {
+        Some(params) => params,
+        None => return String::from("unmatched"),
+    };
+    let mut segments = Vec::new();
+    for segment in req.url.path() {
+        let mut named = None;
+        for (name, value) in params.iter() {
+            if segment == value {
+                named = Some(format!(":{}", name));
+                break;
+            }
+        }
+        segments.push(named.unwrap_or(String::from(segment)));
+    }
+    format!("/{}", segments.join("/"))
+}
+
+struct StartTime;
+
+impl Key for StartTime {
+    type Value = Instant;
+}
+
+// Counts and times every request. Link the before half first and the after half last, so the
+// time covers the other middleware too.
+pub struct RequestTimer;
+pub struct RequestMetrics;
+
+pub fn middleware() -> (RequestTimer, RequestMetrics) {
+    (RequestTimer, RequestMetrics)
+}
+
+impl BeforeMiddleware for RequestTimer {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<StartTime>(Instant::now());
+        Ok(())
+    }
+}
+
+fn record(req: &Request, res: &Response) {
+    let route = route(req);
+    let method = req.method.to_string();
+    let code = res.status.map(|status| status.to_u16()).unwrap_or(404).to_string();
+    REQUESTS.inc(&[("route", route.as_str()), ("method", method.as_str()), ("status", code.as_str())]);
+    if let Some(start) = req.extensions.get::<StartTime>() {
+        REQUEST_SECONDS.observe(&[("route", route.as_str()), ("method", method.as_str())],
+                                seconds_since(*start));
+    }
+}
+
+impl AfterMiddleware for RequestMetrics {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        record(req, &res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        record(req, &err.response);
+        Err(err)
+    }
+}
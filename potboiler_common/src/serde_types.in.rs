@@ -1,5 +1,6 @@
 use serde_json;
 use hybrid_clocks::{Timestamp, WallT};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 // from https://serde.rs/enum-str.html
@@ -63,12 +64,29 @@ pub struct LWW {
     pub data: serde_json::Value,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Log {
     pub id: Uuid,
     pub owner: Uuid,
     pub prev: Option<Uuid>,
     pub next: Option<Uuid>,
     pub when: Timestamp<WallT>,
+    pub data: serde_json::Value,
+    #[serde(default)]
+    pub stream: Option<String>,
+    // The traceparent of the request that appended it, if that was traced (see trace)
+    #[serde(default)]
+    pub trace: Option<String>
+}
+
+// Application-supplied summary of a stream's state, covering each owner's chain up to
+// and including the entry given in heads (owner id -> entry id)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub id: Uuid,
+    #[serde(default)]
+    pub stream: Option<String>,
+    pub heads: HashMap<String, Uuid>,
+    pub when: Timestamp<WallT>,
     pub data: serde_json::Value
 }
@@ -1,5 +1,4 @@
 use iron::typemap::Key;
-use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
@@ -24,8 +23,8 @@ macro_rules! get_server_id {
     })
 }
 
-pub fn setup() -> Uuid {
-    let id_path = &env::var("ID_PATH").unwrap_or("server-id".to_string());
+// Reads this server's id from id_path, or makes one up and saves it there if there isn't one yet
+pub fn setup(id_path: &str) -> Uuid {
     if !Path::new(id_path).exists() {
         let mut f = File::create(id_path).expect(&format!("Can create {}", id_path));
         let id = Uuid::new_v4();
@@ -0,0 +1,100 @@
+use chan;
+use chan_signal::{self, Signal};
+use health;
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response};
+use iron::status;
+use iron::typemap::Key;
+use std::process;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use string_error::StringError;
+
+// Stopping cleanly on SIGTERM (or SIGINT). Once one comes in, new requests get a 503 (apart
+// from /health and /ready, which says we're shutting down), the ones already being handled get
+// until the deadline to finish, and then the service's own cleanup runs before we exit.
+
+static DRAINING: AtomicBool = ATOMIC_BOOL_INIT;
+static IN_FLIGHT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub struct Signals(chan::Receiver<Signal>);
+
+// Has to be called at the start of main, before any other threads start, so they all leave
+// the signals to us
+pub fn listen() -> Signals {
+    Signals(chan_signal::notify(&[Signal::INT, Signal::TERM]))
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+impl Signals {
+    // Runs cleanup with the deadline for it to be done by, once the requests have finished (or
+    // we've given up waiting)
+    pub fn on_shutdown<F: FnOnce(Instant) + Send + 'static>(self, timeout: Duration, cleanup: F) {
+        thread::spawn(move || {
+            let signal = match self.0.recv() {
+                Some(signal) => signal,
+                None => return,
+            };
+            info!("Got {:?}, shutting down", signal);
+            let deadline = Instant::now() + timeout;
+            DRAINING.store(true, Ordering::SeqCst);
+            health::pending("shutting_down", "Shutting down");
+            while IN_FLIGHT.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(100));
+            }
+            let left = IN_FLIGHT.load(Ordering::SeqCst);
+            if left > 0 {
+                warn!("Gave up waiting for {} requests to finish", left);
+            }
+            cleanup(deadline);
+            info!("Shut down");
+            process::exit(0);
+        });
+    }
+}
+
+struct Counted;
+
+impl Key for Counted {
+    type Value = ();
+}
+
+// Keeps track of the requests being handled, and turns new ones away once we're shutting down.
+// Link it as the first before middleware and the last after one.
+pub struct Drain;
+
+impl BeforeMiddleware for Drain {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if is_draining() {
+            let path = req.url.path();
+            if path != ["health"] && path != ["ready"] {
+                return Err(IronError::new(StringError::from("Shutting down"),
+                                          (status::ServiceUnavailable, "Shutting down")));
+            }
+        }
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        req.extensions.insert::<Counted>(());
+        Ok(())
+    }
+}
+
+fn finished(req: &mut Request) {
+    if req.extensions.remove::<Counted>().is_some() {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl AfterMiddleware for Drain {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        finished(req);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        finished(req);
+        Err(err)
+    }
+}
@@ -1,7 +1,7 @@
 use postgres::error::Error as PError;
 use serde_json;
 use std::error::Error;
-use std::fmt::{self, Debug};
+use std::fmt;
 use std::io;
 use uuid;
 
@@ -10,7 +10,7 @@ pub struct StringError(pub String);
 
 impl fmt::Display for StringError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Debug::fmt(self, f)
+        write!(f, "{}", self.0)
     }
 }
 
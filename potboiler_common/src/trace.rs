@@ -0,0 +1,367 @@
+use hyper::client::Client;
+use hyper::header::ContentType;
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response};
+use iron::typemap::Key;
+use metrics;
+use serde_json::{self, Map, Value};
+use std::cell::RefCell;
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// Following a piece of work across the services. Each request belongs to a trace, with a span
+// for each step (handling a request, sending a notification, replicating an entry) linked to the
+// span that caused it. Ids and the traceparent header follow W3C Trace Context, so a request
+// that comes with a traceparent joins the caller's trace, and one without starts a new one.
+// Log entries keep the traceparent of the request that appended them, which is how the trace
+// carries on through notifications and replication. Finished spans are logged as JSON under the
+// "trace" target and, with trace_collector_url set, sent to an OpenTelemetry collector as
+// OTLP/HTTP JSON.
+
+header! { (TraceParent, "traceparent") => [String] }
+// On every response, so callers can find the trace for a request
+header! { (TraceId, "X-Trace-Id") => [String] }
+
+// Spans waiting to go to the collector. Past this, new ones are dropped rather than queued.
+const MAX_QUEUED: usize = 10000;
+const MAX_EXPORT_BATCH: usize = 512;
+const EXPORT_INTERVAL_MS: u64 = 1000;
+
+lazy_static! {
+    static ref SERVICE: Mutex<String> = Mutex::new(String::from("potboiler"));
+    static ref EXPORTER: Mutex<Option<SyncSender<Value>>> = Mutex::new(None);
+}
+
+thread_local!(static CURRENT: RefCell<Option<Context>> = RefCell::new(None));
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Context {
+    // 32 hex digits
+    pub trace_id: String,
+    // 16 hex digits
+    pub span_id: String,
+}
+
+fn random_hex(digits: usize) -> String {
+    Uuid::new_v4().simple().to_string()[..digits].to_string()
+}
+
+fn is_id(raw: &str, digits: usize) -> bool {
+    raw.len() == digits && raw.chars().all(|c| c.is_digit(16)) && raw.chars().any(|c| c != '0')
+}
+
+impl Context {
+    // From a traceparent, e.g. 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+    pub fn parse(traceparent: &str) -> Option<Context> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || !is_id(parts[1], 32) || !is_id(parts[2], 16) {
+            return None;
+        }
+        Some(Context {
+            trace_id: parts[1].to_lowercase(),
+            span_id: parts[2].to_lowercase(),
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+}
+
+// The span the work on this thread is being done for, if any. New log entries and requests to
+// other services carry on from it.
+pub fn current() -> Option<Context> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+fn set_current(context: Option<Context>) {
+    CURRENT.with(|current| *current.borrow_mut() = context);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    // Handling a request
+    Server,
+    // Making a request
+    Client,
+    // Sending entries on (notifications and replication)
+    Producer,
+    // Taking in entries from elsewhere
+    Consumer,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match *self {
+            Kind::Server => "server",
+            Kind::Client => "client",
+            Kind::Producer => "producer",
+            Kind::Consumer => "consumer",
+        }
+    }
+
+    // SpanKind in the OTLP protobuf
+    fn otlp(&self) -> u64 {
+        match *self {
+            Kind::Server => 2,
+            Kind::Client => 3,
+            Kind::Producer => 4,
+            Kind::Consumer => 5,
+        }
+    }
+}
+
+pub struct Span {
+    context: Context,
+    parent: Option<String>,
+    name: String,
+    kind: Kind,
+    start: SystemTime,
+    started: Instant,
+    attributes: Vec<(&'static str, String)>,
+    failed: bool,
+}
+
+fn string(value: &str) -> Value {
+    Value::String(String::from(value))
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    since.as_secs() * 1_000_000_000 + since.subsec_nanos() as u64
+}
+
+impl Span {
+    // A child of parent, or the first span of a new trace
+    pub fn start<S: Into<String>>(name: S, kind: Kind, parent: Option<&Context>) -> Span {
+        let trace_id = match parent {
+            Some(parent) => parent.trace_id.clone(),
+            None => random_hex(32),
+        };
+        Span {
+            context: Context {
+                trace_id: trace_id,
+                span_id: random_hex(16),
+            },
+            parent: parent.map(|parent| parent.span_id.clone()),
+            name: name.into(),
+            kind: kind,
+            start: SystemTime::now(),
+            started: Instant::now(),
+            attributes: Vec::new(),
+            failed: false,
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn set_name<S: Into<String>>(&mut self, name: S) {
+        self.name = name.into();
+    }
+
+    pub fn attribute<V: ToString>(&mut self, name: &'static str, value: V) {
+        self.attributes.push((name, value.to_string()));
+    }
+
+    pub fn failed(&mut self) {
+        self.failed = true;
+    }
+
+    // Logs the span and queues it for the collector
+    pub fn finish(self) {
+        let duration = metrics::seconds_since(self.started);
+        info!(target: "trace", "{}", serde_json::to_string(&self.log_record(duration)).unwrap());
+        if let Some(ref exporter) = *EXPORTER.lock().unwrap() {
+            let elapsed = self.started.elapsed();
+            let end = unix_nanos(self.start) + elapsed.as_secs() * 1_000_000_000 +
+                      elapsed.subsec_nanos() as u64;
+            if let Err(TrySendError::Full(_)) = exporter.try_send(self.otlp_span(end)) {
+                warn!("Dropped span {}, as the collector's too far behind", self.context.span_id);
+            }
+        }
+    }
+
+    fn log_record(&self, duration: f64) -> Value {
+        let mut record = Map::new();
+        record.insert(String::from("service"), string(&SERVICE.lock().unwrap()));
+        record.insert(String::from("trace_id"), string(&self.context.trace_id));
+        record.insert(String::from("span_id"), string(&self.context.span_id));
+        if let Some(ref parent) = self.parent {
+            record.insert(String::from("parent_span_id"), string(parent));
+        }
+        record.insert(String::from("name"), string(&self.name));
+        record.insert(String::from("kind"), string(self.kind.name()));
+        record.insert(String::from("duration_secs"), Value::F64(duration));
+        record.insert(String::from("status"),
+                      string(if self.failed { "error" } else { "ok" }));
+        let mut attributes = Map::new();
+        for &(name, ref value) in &self.attributes {
+            attributes.insert(String::from(name), string(value));
+        }
+        record.insert(String::from("attributes"), Value::Object(attributes));
+        Value::Object(record)
+    }
+
+    fn otlp_span(&self, end: u64) -> Value {
+        let mut span = Map::new();
+        span.insert(String::from("traceId"), string(&self.context.trace_id));
+        span.insert(String::from("spanId"), string(&self.context.span_id));
+        if let Some(ref parent) = self.parent {
+            span.insert(String::from("parentSpanId"), string(parent));
+        }
+        span.insert(String::from("name"), string(&self.name));
+        span.insert(String::from("kind"), Value::U64(self.kind.otlp()));
+        span.insert(String::from("startTimeUnixNano"),
+                    Value::String(unix_nanos(self.start).to_string()));
+        span.insert(String::from("endTimeUnixNano"), Value::String(end.to_string()));
+        let attributes = self.attributes
+            .iter()
+            .map(|&(name, ref value)| otlp_attribute(name, value))
+            .collect();
+        span.insert(String::from("attributes"), Value::Array(attributes));
+        let mut status = Map::new();
+        // STATUS_CODE_OK and STATUS_CODE_ERROR
+        status.insert(String::from("code"), Value::U64(if self.failed { 2 } else { 1 }));
+        span.insert(String::from("status"), Value::Object(status));
+        Value::Object(span)
+    }
+}
+
+// A span for work done for a log entry (given its trace field), if it was appended as part of a
+// trace. Work for entries without one isn't traced.
+pub fn for_entry<S: Into<String>>(name: S, kind: Kind, trace: &Option<String>) -> Option<Span> {
+    trace.as_ref()
+        .and_then(|traceparent| Context::parse(traceparent))
+        .map(|parent| Span::start(name, kind, Some(&parent)))
+}
+
+fn otlp_attribute(name: &str, value: &str) -> Value {
+    let mut any = Map::new();
+    any.insert(String::from("stringValue"), string(value));
+    let mut attribute = Map::new();
+    attribute.insert(String::from("key"), string(name));
+    attribute.insert(String::from("value"), Value::Object(any));
+    Value::Object(attribute)
+}
+
+fn otlp_body(service: &str, spans: Vec<Value>) -> Value {
+    let mut scope = Map::new();
+    scope.insert(String::from("name"), string("potboiler"));
+    let mut scope_spans = Map::new();
+    scope_spans.insert(String::from("scope"), Value::Object(scope));
+    scope_spans.insert(String::from("spans"), Value::Array(spans));
+    let mut resource = Map::new();
+    resource.insert(String::from("attributes"),
+                    Value::Array(vec![otlp_attribute("service.name", service)]));
+    let mut resource_spans = Map::new();
+    resource_spans.insert(String::from("resource"), Value::Object(resource));
+    resource_spans.insert(String::from("scopeSpans"),
+                          Value::Array(vec![Value::Object(scope_spans)]));
+    let mut body = Map::new();
+    body.insert(String::from("resourceSpans"),
+                Value::Array(vec![Value::Object(resource_spans)]));
+    Value::Object(body)
+}
+
+// Sends spans to the collector in batches, of whatever's turned up within a second of the first
+fn export_loop(url: String, service: String, recv: Receiver<Value>) {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(10)));
+    client.set_write_timeout(Some(Duration::from_secs(10)));
+    loop {
+        let mut spans = match recv.recv() {
+            Ok(span) => vec![span],
+            Err(_) => return,
+        };
+        let deadline = Instant::now() + Duration::from_millis(EXPORT_INTERVAL_MS);
+        while spans.len() < MAX_EXPORT_BATCH {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match recv.recv_timeout(deadline - now) {
+                Ok(span) => spans.push(span),
+                Err(_) => break,
+            }
+        }
+        let count = spans.len();
+        let body = serde_json::to_string(&otlp_body(&service, spans)).unwrap();
+        match client.post(&url).header(ContentType::json()).body(&body).send() {
+            Ok(res) => {
+                if !res.status.is_success() {
+                    warn!("Collector at {} turned down {} spans: {:?}", url, count, res.status);
+                }
+            }
+            Err(err) => warn!("Couldn't send {} spans to {}: {:?}", count, url, err),
+        }
+    }
+}
+
+// Names the service in the spans, and starts sending them to the collector (e.g.
+// http://localhost:4318/v1/traces) if there is one
+pub fn init(service: &str, collector_url: Option<String>) {
+    *SERVICE.lock().unwrap() = String::from(service);
+    if let Some(url) = collector_url {
+        info!("Sending spans to {}", url);
+        let (send, recv) = sync_channel(MAX_QUEUED);
+        *EXPORTER.lock().unwrap() = Some(send);
+        let service = String::from(service);
+        thread::spawn(move || export_loop(url, service, recv));
+    }
+}
+
+struct RequestSpan;
+
+impl Key for RequestSpan {
+    type Value = Span;
+}
+
+// A server span for each request, carrying on the caller's trace if it sent a traceparent, which
+// is the current one while the request's handled. Link it straight after the metrics middleware
+// at both ends, so the route's known by the time it's finished.
+pub struct Tracing;
+
+impl BeforeMiddleware for Tracing {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let parent = req.headers.get::<TraceParent>().and_then(|header| Context::parse(&header.0));
+        let span = Span::start(format!("{} /{}", req.method, req.url.path().join("/")),
+                               Kind::Server,
+                               parent.as_ref());
+        set_current(Some(span.context().clone()));
+        req.extensions.insert::<RequestSpan>(span);
+        Ok(())
+    }
+}
+
+fn finish_request(req: &mut Request, res: &mut Response) {
+    set_current(None);
+    if let Some(mut span) = req.extensions.remove::<RequestSpan>() {
+        let route = metrics::route(req);
+        let code = res.status.map(|status| status.to_u16()).unwrap_or(404);
+        res.headers.set(TraceId(span.context().trace_id.clone()));
+        span.set_name(format!("{} {}", req.method, route));
+        span.attribute("http.method", &req.method);
+        span.attribute("http.route", route);
+        span.attribute("http.status_code", code);
+        if code >= 500 {
+            span.failed();
+        }
+        span.finish();
+    }
+}
+
+impl AfterMiddleware for Tracing {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        finish_request(req, &mut res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        finish_request(req, &mut err.response);
+        Err(err)
+    }
+}
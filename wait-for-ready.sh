@@ -0,0 +1,16 @@
+#!/bin/bash
+
+host="${1:-core}"
+port="${2:-8000}"
+
+while true; do
+  # bash specific! Asks the service's /ready, and waits for a 200
+  status_line=$( (exec 3<>"/dev/tcp/$host/$port" && printf "GET /ready HTTP/1.0\r\nHost: %s\r\n\r\n" "$host" >&3 && head -n 1 <&3) 2>/dev/null )
+  case "$status_line" in
+    *" 200 "*) break ;;
+  esac
+  >&2 echo "$host:$port isn't ready - sleeping"
+  sleep 5
+done
+
+>&2 echo "$host:$port is ready"